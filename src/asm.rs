@@ -0,0 +1,614 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fmt::Write as FmtWrite;
+use std::fs;
+use std::mem;
+use std::path::{Path, PathBuf};
+use cpu::PROGRAM_START;
+
+/*
+ * Builds a ROM from assembly in the syntax the disassembler and the
+ * tracer print (Cowgod's mnemonics), one statement per line:
+ *
+ *   ; comments run to the end of the line
+ *   SPEED   EQU 3               ; a constant
+ *   start:  LD   V0, SPEED      ; a label, decimal number
+ *           LD   I, sprite
+ *           DRW  V0, V1, 5
+ *           JP   start
+ *   sprite: DB   #F0, #90, #F0, %10010000, 0xF0
+ *           DW   #1234
+ *           INCLUDE "more.asm"  ; relative to the including file
+ *
+ * Numbers are decimal, or hex with '#' or 0x, or binary with '%' or 0b.
+ * Operands may add and subtract numbers, labels and constants, e.g.
+ * `sprite + 5`. Mnemonics and register names are not case sensitive,
+ * labels and constants are.
+ *
+ * The first pass reads all lines (and includes) and places labels, the
+ * second one encodes the statements; every error found on the way is
+ * reported with the file and line it belongs to.
+ */
+
+const MAX_INCLUDE_DEPTH : usize = 16;
+const MAX_CONSTANT_DEPTH : usize = 16;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AsmError {
+    pub file : String,
+    pub line : usize,
+    pub message : String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.file, self.line, self.message)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Program {
+    pub rom : Vec<u8>,                  // Loaded at 0x200
+    pub labels : BTreeMap<String, u16>,
+    pub listing : String,               // Source with addresses and bytes
+    pub lines : Vec<SourceLine>,        // Where the code of each line went
+}
+
+// The code of a source line, for debuggers to map addresses to lines.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SourceLine {
+    pub file : String,
+    pub line : usize,
+    pub addr : u16,
+    pub len : usize,
+}
+
+// One line of source, after the first pass.
+struct Statement {
+    file : String,
+    line : usize,
+    text : String,                      // As written, for the listing
+    addr : usize,
+    mnemonic : Option<String>,          // Upper case
+    operands : Vec<String>,
+}
+
+#[derive(Default)]
+struct Assembler {
+    statements : Vec<Statement>,
+    labels : BTreeMap<String, u16>,
+    constants : BTreeMap<String, (String, String, usize)>,  // expression, file, line
+    errors : Vec<AsmError>,
+    addr : usize,
+}
+
+pub fn assemble(source : &str, name : &str) -> Result<Program, Vec<AsmError>> {
+    // INCLUDE paths are relative to the directory of name.
+    let mut asm = Assembler { addr : PROGRAM_START as usize, ..Assembler::default() };
+    asm.read(source, name, 0);
+    asm.finish()
+}
+
+pub fn assemble_file<P : AsRef<Path>>(path : P) -> Result<Program, Vec<AsmError>> {
+    let name = path.as_ref().display().to_string();
+    match fs::read_to_string(&path) {
+        Ok(source) => assemble(&source, &name),
+        Err(e) => Err(vec![AsmError { file : name, line : 0, message : e.to_string() }]),
+    }
+}
+
+impl Assembler {
+    fn error(&mut self, file : &str, line : usize, message : String) {
+        self.errors.push(AsmError { file : file.to_string(), line, message });
+    }
+
+    fn read(&mut self, source : &str, file : &str, depth : usize) {
+        // First pass: split lines into label, mnemonic and operands,
+        // place the labels and follow the includes.
+
+        for (number, text) in source.lines().enumerate() {
+            let line = number + 1;
+            let mut code = text.split(';').next().unwrap_or("").trim();
+
+            // name EQU expression
+            let words : Vec<&str> = code.split_whitespace().collect();
+            if words.len() >= 3 && words[1].eq_ignore_ascii_case("EQU") {
+                self.define(words[0], &words[2..].join(" "), file, line);
+                self.push(file, line, text, None, Vec::new());
+                continue;
+            }
+
+            if let Some(colon) = code.find(':') {
+                let label = code[..colon].trim();
+                self.define_label(label, file, line);
+                code = code[colon + 1..].trim();
+            }
+
+            if code.is_empty() {
+                self.push(file, line, text, None, Vec::new());
+                continue;
+            }
+
+            let (mnemonic, rest) = match code.find(char::is_whitespace) {
+                Some(i) => (&code[..i], code[i..].trim()),
+                None => (code, ""),
+            };
+            let mnemonic = mnemonic.to_uppercase();
+
+            if mnemonic == "INCLUDE" {
+                self.push(file, line, text, None, Vec::new());
+                self.include(rest, file, line, depth);
+                continue;
+            }
+
+            let operands : Vec<String> = if rest.is_empty() {
+                Vec::new()
+            } else {
+                rest.split(',').map(|o| o.trim().to_string()).collect()
+            };
+            let size = size(&mnemonic, &operands);
+            self.push(file, line, text, Some(mnemonic), operands);
+            self.addr += size;
+        }
+    } // fn read
+
+    fn push(&mut self, file : &str, line : usize, text : &str,
+            mnemonic : Option<String>, operands : Vec<String>) {
+        self.statements.push(Statement {
+            file : file.to_string(),
+            line,
+            text : text.to_string(),
+            addr : self.addr,
+            mnemonic,
+            operands,
+        });
+    }
+
+    fn define_label(&mut self, label : &str, file : &str, line : usize) {
+        if !is_symbol(label) {
+            self.error(file, line, format!("bad label `{}`", label));
+        } else if self.labels.contains_key(label) || self.constants.contains_key(label) {
+            self.error(file, line, format!("`{}` is defined twice", label));
+        } else {
+            self.labels.insert(label.to_string(), self.addr as u16);
+        }
+    }
+
+    fn define(&mut self, name : &str, expr : &str, file : &str, line : usize) {
+        if !is_symbol(name) {
+            self.error(file, line, format!("bad constant name `{}`", name));
+        } else if self.labels.contains_key(name) || self.constants.contains_key(name) {
+            self.error(file, line, format!("`{}` is defined twice", name));
+        } else {
+            self.constants.insert(name.to_string(),
+                                  (expr.to_string(), file.to_string(), line));
+        }
+    }
+
+    fn include(&mut self, operand : &str, file : &str, line : usize, depth : usize) {
+        let name = operand.trim_matches('"');
+        if name.is_empty() || name.len() + 2 != operand.len() {
+            return self.error(file, line, String::from("INCLUDE expects a \"file\""));
+        }
+        if depth >= MAX_INCLUDE_DEPTH {
+            return self.error(file, line, format!("includes nested deeper than {}",
+                                                  MAX_INCLUDE_DEPTH));
+        }
+
+        let path = match Path::new(file).parent() {
+            Some(dir) => dir.join(name),
+            None => PathBuf::from(name),
+        };
+        match fs::read_to_string(&path) {
+            Ok(source) => self.read(&source, &path.display().to_string(), depth + 1),
+            Err(e) => self.error(file, line, format!("{}: {}", path.display(), e)),
+        }
+    } // fn include
+
+    fn finish(mut self) -> Result<Program, Vec<AsmError>> {
+        // Second pass: encode every statement and write the listing.
+
+        let mut program = Program::default();
+        let statements = mem::take(&mut self.statements);
+
+        for statement in &statements {
+            let bytes = match statement.mnemonic {
+                Some(ref mnemonic) => {
+                    let operands : Vec<&str> = statement.operands.iter()
+                        .map(|o| o.as_str())
+                        .collect();
+                    match self.encode(mnemonic, &operands) {
+                        Ok(bytes) => bytes,
+                        Err(message) => {
+                            self.error(&statement.file, statement.line, message);
+                            Vec::new()
+                        },
+                    }
+                },
+                None => Vec::new(),
+            };
+
+            listing(&mut program.listing, statement.line, statement.addr,
+                    &statement.text, &bytes);
+            if !bytes.is_empty() {
+                program.lines.push(SourceLine { file : statement.file.clone(),
+                                                line : statement.line,
+                                                addr : statement.addr as u16,
+                                                len : bytes.len() });
+            }
+            program.rom.extend(bytes);
+        }
+
+        for (name, &(ref expr, ref file, line)) in &self.constants {
+            if let Err(message) = self.value(expr, 0) {
+                self.errors.push(AsmError { file : file.clone(), line,
+                                            message : format!("{}: {}", name, message) });
+            }
+        }
+
+        if !self.errors.is_empty() {
+            return Err(self.errors);
+        }
+        if program.rom.is_empty() {
+            return Err(vec![AsmError { file : String::new(), line : 0,
+                                       message : String::from("no code or data") }]);
+        }
+        program.labels = self.labels;
+        Ok(program)
+    } // fn finish
+
+    fn encode(&self, mnemonic : &str, ops : &[&str]) -> Result<Vec<u8>, String> {
+        let word = |w : u16| Ok(vec![(w >> 8) as u8, w as u8]);
+        let xy = |x : u8, y : u8| (x as u16) << 8 | (y as u16) << 4;
+
+        match (mnemonic, ops.len()) {
+            ("DB", n) if n > 0 => {
+                ops.iter().map(|o| self.byte(o)).collect()
+            },
+            ("DW", n) if n > 0 => {
+                let mut bytes = Vec::new();
+                for o in ops {
+                    let w = self.bounded(o, 0, 0xFFFF, "word")? as u16;
+                    bytes.extend(&[(w >> 8) as u8, w as u8]);
+                }
+                Ok(bytes)
+            },
+            ("CLS", 0) => word(0x00E0),
+            ("RET", 0) => word(0x00EE),
+            ("SCR", 0) => word(0x00FB),
+            ("SCL", 0) => word(0x00FC),
+            ("EXIT", 0) => word(0x00FD),
+            ("LOW", 0) => word(0x00FE),
+            ("HIGH", 0) => word(0x00FF),
+            ("AUDIO", 0) => word(0xF002),
+            ("SCD", 1) => word(0x00C0 | self.nibble(ops[0])? as u16),
+            ("SCU", 1) => word(0x00D0 | self.nibble(ops[0])? as u16),
+            ("SYS", 1) => word(self.addr(ops[0])?),
+            ("JP", 1) => word(0x1000 | self.addr(ops[0])?),
+            ("JP", 2) if reg(ops[0]) == Some(0) => word(0xB000 | self.addr(ops[1])?),
+            ("CALL", 1) => word(0x2000 | self.addr(ops[0])?),
+            ("SE", 2) | ("SNE", 2) => {
+                let x = vx(ops[0])?;
+                let (byte_op, reg_op) = if mnemonic == "SE" { (0x3000, 0x5000) }
+                                        else { (0x4000, 0x9000) };
+                match reg(ops[1]) {
+                    Some(y) => word(reg_op | xy(x, y)),
+                    None => word(byte_op | xy(x, 0) | self.byte(ops[1])? as u16),
+                }
+            },
+            ("SAVE", 1) | ("LOAD", 1) => {
+                let range : Vec<&str> = ops[0].split('-').map(|r| r.trim()).collect();
+                if range.len() != 2 {
+                    return Err(format!("{} expects Vx - Vy", mnemonic));
+                }
+                let n = if mnemonic == "SAVE" { 2 } else { 3 };
+                word(0x5000 | xy(vx(range[0])?, vx(range[1])?) | n)
+            },
+            ("ADD", 2) if is(ops[0], "I") => word(0xF01E | xy(vx(ops[1])?, 0)),
+            ("ADD", 2) => {
+                let x = vx(ops[0])?;
+                match reg(ops[1]) {
+                    Some(y) => word(0x8004 | xy(x, y)),
+                    None => word(0x7000 | xy(x, 0) | self.byte(ops[1])? as u16),
+                }
+            },
+            ("OR", 2) => word(0x8001 | xy(vx(ops[0])?, vx(ops[1])?)),
+            ("AND", 2) => word(0x8002 | xy(vx(ops[0])?, vx(ops[1])?)),
+            ("XOR", 2) => word(0x8003 | xy(vx(ops[0])?, vx(ops[1])?)),
+            ("SUB", 2) => word(0x8005 | xy(vx(ops[0])?, vx(ops[1])?)),
+            ("SUBN", 2) => word(0x8007 | xy(vx(ops[0])?, vx(ops[1])?)),
+            // Without Vy the register shifts itself, with or without quirks.
+            ("SHR", 1) | ("SHL", 1) | ("SHR", 2) | ("SHL", 2) => {
+                let x = vx(ops[0])?;
+                let y = if ops.len() == 2 { vx(ops[1])? } else { x };
+                word(if mnemonic == "SHR" { 0x8006 } else { 0x800E } | xy(x, y))
+            },
+            ("RND", 2) => word(0xC000 | xy(vx(ops[0])?, 0) | self.byte(ops[1])? as u16),
+            ("DRW", 3) => word(0xD000 | xy(vx(ops[0])?, vx(ops[1])?) |
+                               self.nibble(ops[2])? as u16),
+            ("SKP", 1) => word(0xE09E | xy(vx(ops[0])?, 0)),
+            ("SKNP", 1) => word(0xE0A1 | xy(vx(ops[0])?, 0)),
+            ("PLANE", 1) => word(0xF001 | xy(self.nibble(ops[0])?, 0)),
+            ("LD", 2) => self.load(ops[0], ops[1]),
+            _ => Err(format!("unknown instruction `{}` with {} operand(s)",
+                             mnemonic, ops.len())),
+        }
+    } // fn encode
+
+    fn load(&self, dst : &str, src : &str) -> Result<Vec<u8>, String> {
+        // The many forms of LD, told apart by their operands.
+
+        let word = |w : u16| Ok(vec![(w >> 8) as u8, w as u8]);
+
+        if let Some(x) = reg(dst) {
+            let x = (x as u16) << 8;
+            return match reg(src) {
+                Some(y) => word(0x8000 | x | (y as u16) << 4),
+                None if is(src, "DT") => word(0xF007 | x),
+                None if is(src, "K") => word(0xF00A | x),
+                None if is(src, "[I]") => word(0xF065 | x),
+                None if is(src, "R") => word(0xF085 | x),
+                None => word(0x6000 | x | self.byte(src)? as u16),
+            };
+        }
+
+        if is(dst, "I") {
+            if let Some(target) = long(src) {
+                let nnnn = self.bounded(target, 0, 0xFFFF, "address")? as u16;
+                return Ok(vec![0xF0, 0x00, (nnnn >> 8) as u8, nnnn as u8]);
+            }
+            return word(0xA000 | self.addr(src)?);
+        }
+
+        let low = match dst.to_uppercase().as_str() {
+            "DT" => 0x15,
+            "ST" => 0x18,
+            "F" => 0x29,
+            "HF" => 0x30,
+            "B" => 0x33,
+            "PITCH" => 0x3A,
+            "[I]" => 0x55,
+            "R" => 0x75,
+            _ => return Err(format!("cannot load into `{}`", dst)),
+        };
+        word(0xF000 | (vx(src)? as u16) << 8 | low)
+    } // fn load
+
+    fn addr(&self, expr : &str) -> Result<u16, String> {
+        self.bounded(expr, 0, 0xFFF, "address").map(|v| v as u16)
+    }
+
+    fn byte(&self, expr : &str) -> Result<u8, String> {
+        // Negative bytes are stored as two's complement.
+        self.bounded(expr, -128, 0xFF, "byte").map(|v| v as u8)
+    }
+
+    fn nibble(&self, expr : &str) -> Result<u8, String> {
+        self.bounded(expr, 0, 0xF, "nibble").map(|v| v as u8)
+    }
+
+    fn bounded(&self, expr : &str, min : i64, max : i64, what : &str) -> Result<i64, String> {
+        let value = self.value(expr, 0)?;
+        if value < min || value > max {
+            return Err(format!("{} `{}` ({}) is out of range {}-{}",
+                               what, expr.trim(), value, min, max));
+        }
+        Ok(value)
+    }
+
+    fn value(&self, expr : &str, depth : usize) -> Result<i64, String> {
+        // Sum up the terms of expr. Constants are evaluated when used,
+        // so they may refer to labels defined later.
+
+        if depth > MAX_CONSTANT_DEPTH {
+            return Err(format!("constants nested too deep in `{}`", expr.trim()));
+        }
+
+        let mut total = 0;
+        let mut sign = 1;
+        let mut first = true;
+        let mut term = String::new();
+        for c in expr.chars().chain(Some('+')) {
+            if c == '+' || c == '-' {
+                let term = mem::take(&mut term);
+                let term = term.trim();
+                if term.is_empty() {
+                    // Only the first term may have a sign of its own.
+                    if c == '-' && first {
+                        sign = -1;
+                        first = false;
+                        continue;
+                    }
+                    return Err(format!("bad expression `{}`", expr.trim()));
+                }
+                first = false;
+                total = self.term(term, depth)?
+                    .checked_mul(sign)
+                    .and_then(|value| value.checked_add(total))
+                    .ok_or_else(|| format!("`{}` overflows", expr.trim()))?;
+                sign = if c == '-' { -1 } else { 1 };
+            } else {
+                term.push(c);
+            }
+        }
+        Ok(total)
+    } // fn value
+
+    fn term(&self, term : &str, depth : usize) -> Result<i64, String> {
+        if let Some(value) = number(term) {
+            return Ok(value);
+        }
+        if let Some(&addr) = self.labels.get(term) {
+            return Ok(addr as i64);
+        }
+        if let Some((expr, _, _)) = self.constants.get(term) {
+            return self.value(expr, depth + 1);
+        }
+        if is_symbol(term) {
+            Err(format!("`{}` is not defined", term))
+        } else {
+            Err(format!("bad operand `{}`", term))
+        }
+    }
+}
+
+fn size(mnemonic : &str, operands : &[String]) -> usize {
+    // Bytes a statement takes up, known before its operands are.
+    match mnemonic {
+        "DB" => operands.len(),
+        "DW" => operands.len() * 2,
+        "LD" if operands.len() == 2 && long(&operands[1]).is_some() => 4,
+        _ => 2,
+    }
+}
+
+// The address of `LONG nnnn`, a label like `longsprite` is no LONG.
+fn long(src : &str) -> Option<&str> {
+    let rest = src.get(4..)?;
+    if is(&src[..4], "LONG") && rest.starts_with(char::is_whitespace) {
+        Some(rest)
+    } else {
+        None
+    }
+}
+
+pub(crate) fn listing(out : &mut String, line : usize, addr : usize, text : &str,
+                      bytes : &[u8]) {
+    // Every line shows its address and at most 4 bytes, longer data
+    // continues on the lines after it.
+
+    let mut chunks = bytes.chunks(4);
+    let first : Vec<String> = chunks.next().unwrap_or(&[]).iter()
+        .map(|b| format!("{:02X}", b))
+        .collect();
+    let _ = writeln!(out, "{:>5}  {:03X}  {:<11}  {}",
+                     line, addr, first.join(" "), text.trim_end());
+
+    for (i, chunk) in chunks.enumerate() {
+        let hex : Vec<String> = chunk.iter().map(|b| format!("{:02X}", b)).collect();
+        let _ = writeln!(out, "{:>5}  {:03X}  {}", "", addr + (i + 1) * 4,
+                         hex.join(" "));
+    }
+}
+
+fn number(text : &str) -> Option<i64> {
+    let lower = text.to_lowercase();
+    if let Some(hex) = lower.strip_prefix('#').or_else(|| lower.strip_prefix("0x")) {
+        i64::from_str_radix(hex, 16).ok()
+    } else if let Some(bin) = lower.strip_prefix('%').or_else(|| lower.strip_prefix("0b")) {
+        i64::from_str_radix(bin, 2).ok()
+    } else if lower.starts_with(|c : char| c.is_ascii_digit()) {
+        lower.parse().ok()
+    } else {
+        None
+    }
+}
+
+fn is_symbol(text : &str) -> bool {
+    let mut chars = text.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => (),
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn is(operand : &str, keyword : &str) -> bool {
+    operand.eq_ignore_ascii_case(keyword)
+}
+
+fn reg(operand : &str) -> Option<u8> {
+    let bytes = operand.as_bytes();
+    if bytes.len() == 2 && (bytes[0] == b'V' || bytes[0] == b'v') {
+        (bytes[1] as char).to_digit(16).map(|d| d as u8)
+    } else {
+        None
+    }
+}
+
+fn vx(operand : &str) -> Result<u8, String> {
+    reg(operand).ok_or_else(|| format!("expected a register V0-VF, got `{}`", operand))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpu::{decode, Instruction};
+
+    #[test]
+    fn assembles_labels_constants_and_data() {
+        let source = "\
+            ; draw a box
+            X       EQU 10
+            start:  LD V0, X
+                    LD V1, X + 2
+                    LD I, box
+                    DRW V0, V1, 2
+            loop:   JP loop
+            box:    DB #F0, %10010000
+                    DW 0x1234";
+
+        let program = assemble(source, "box.asm").unwrap();
+        assert_eq!(program.rom, [
+            0x60, 0x0A,
+            0x61, 0x0C,
+            0xA2, 0x0A,
+            0xD0, 0x12,
+            0x12, 0x08,
+            0xF0, 0x90,
+            0x12, 0x34,
+        ]);
+        assert_eq!(program.labels["box"], 0x20A);
+        assert!(program.listing.contains("    8  20A  F0 90 "));
+    }
+
+    #[test]
+    fn reads_what_the_disassembler_writes() {
+        for opcode in 0..=0xFFFF {
+            let instruction = decode(opcode);
+            match instruction {
+                Instruction::Unknown(_) | Instruction::LdILong => continue,
+                _ => (),
+            }
+            let program = assemble(&instruction.to_string(), "all.asm").unwrap();
+            assert_eq!(program.rom, [(opcode >> 8) as u8, opcode as u8],
+                       "{}", instruction);
+        }
+
+        let program = assemble("LD I, LONG #1234", "long.asm").unwrap();
+        assert_eq!(program.rom, [0xF0, 0x00, 0x12, 0x34]);
+    }
+
+    #[test]
+    fn names_starting_with_long_are_not_long() {
+        let source = "LONGER EQU 5\nstart: LD V0, LONGER\nLD I, longsprite\n\
+                      end: JP end\nlongsprite: DB #FF";
+        let program = assemble(source, "names.asm").unwrap();
+        assert_eq!(program.rom, [0x60, 0x05, 0xA2, 0x06, 0x12, 0x04, 0xFF]);
+        assert_eq!(program.labels["end"], 0x204);
+    }
+
+    #[test]
+    fn reports_errors_with_their_line() {
+        let errors = assemble("CLS\nLD V0, missing\nDRW V0, V1, 16\nFOO", "bad.asm")
+            .unwrap_err();
+        let errors : Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(errors, [
+            "bad.asm:2: `missing` is not defined",
+            "bad.asm:3: nibble `16` (16) is out of range 0-15",
+            "bad.asm:4: unknown instruction `FOO` with 0 operand(s)",
+        ]);
+    }
+
+    #[test]
+    fn reports_overflowing_expressions() {
+        let errors = assemble("BIG EQU 0x7FFFFFFFFFFFFFFF\nLD V0, BIG + 1\nLD V1, -BIG - 2",
+                              "big.asm")
+            .unwrap_err();
+        let errors : Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(errors, [
+            "big.asm:2: `BIG + 1` overflows",
+            "big.asm:3: `-BIG - 2` overflows",
+        ]);
+    }
+}
@@ -0,0 +1,287 @@
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::{BufWriter, SeekFrom};
+use std::process::{Child, Command, Stdio};
+use byteorder::{LittleEndian, WriteBytesExt};
+
+/*
+ * The CHIP-8 has a single buzzer: as long as the sound timer (ST) is
+ * non-zero a tone is played. The Beeper turns the state of ST into
+ * a square wave, one 60 Hz timer tick at a time, and hands the samples
+ * to an AudioSink which decides where they end up (a sound device,
+ * a WAV file or nowhere at all).
+ *
+ * XO-CHIP replaces the square wave with a 128 bit pattern (F002) played
+ * one bit per sample at 4000 * 2^((pitch - 64) / 48) Hz (Fx3A).
+ */
+
+pub const SAMPLE_RATE : u32 = 44100;
+pub const SAMPLES_PER_TICK : usize = (SAMPLE_RATE / 60) as usize;
+
+pub trait AudioSink {
+    // Receives the signed 16 bit mono samples of one timer tick.
+    fn write(&mut self, samples: &[i16]);
+
+    // Called once the emulation is over, reports what went wrong on the
+    // way. Sinks with nothing to complete keep the default.
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+pub struct Beeper {
+    pub frequency : f32,        // Pitch of the square wave in Hz
+    pub volume : i16,           // Amplitude of the square wave
+    pub pattern : Option<[u8; 16]>, // XO-CHIP audio pattern, None for the square wave
+    pub pitch : u8,             // XO-CHIP playback rate of the pattern
+    phase : f32,                // Position inside the current period (0..1)
+    buffer : Vec<i16>,
+}
+
+impl Beeper {
+    pub fn new() -> Beeper {
+        Beeper {
+            frequency : 440.0,
+            volume : 8000,
+            pattern : None,
+            pitch : 64,
+            phase : 0.0,
+            buffer : vec![0; SAMPLES_PER_TICK],
+        }
+    } // fn new
+
+    // Go back to the plain square wave.
+    pub fn clear_pattern(&mut self) {
+        self.pattern = None;
+        self.pitch = 64;
+    }
+
+    // Rate in Hz at which the bits of the pattern are played.
+    pub fn pattern_rate(&self) -> f32 {
+        4000.0 * 2f32.powf((self.pitch as f32 - 64.0) / 48.0)
+    }
+
+    // Produce the samples of one timer tick and pass them to the sink.
+    // Silence is written too, so a streaming sink never runs dry.
+    pub fn tick(&mut self, on : bool, sink : &mut dyn AudioSink) {
+        // phase runs through one period of the square wave, or through
+        // all 128 bits of the pattern.
+        let step = match self.pattern {
+            Some(_) => self.pattern_rate() / 128.0 / SAMPLE_RATE as f32,
+            None => self.frequency / SAMPLE_RATE as f32,
+        };
+
+        for sample in self.buffer.iter_mut() {
+            if on {
+                let high = match self.pattern {
+                    Some(ref pattern) => {
+                        let bit = (self.phase * 128.0) as usize % 128;
+                        pattern[bit / 8] & (0x80 >> (bit % 8)) != 0
+                    },
+                    None => self.phase < 0.5,
+                };
+                *sample = if high { self.volume } else { -self.volume };
+                self.phase = (self.phase + step) % 1.0;
+            } else {
+                *sample = 0;
+                self.phase = 0.0;
+            }
+        }
+
+        sink.write(&self.buffer);
+    } // fn tick
+}
+
+impl Default for Beeper {
+    fn default() -> Beeper {
+        Beeper::new()
+    }
+}
+
+// Throws every sample away.
+pub struct NullSink;
+
+impl AudioSink for NullSink {
+    fn write(&mut self, _samples: &[i16]) {}
+}
+
+// Records the samples into a 16 bit mono PCM WAV file. The RIFF header
+// is written with empty sizes first and patched by finish (or on drop,
+// which has to drop any error as well).
+pub struct WavSink {
+    writer : BufWriter<File>,
+    data_len : u32,
+    error : Option<io::Error>,
+    finished : bool,
+}
+
+impl WavSink {
+    pub fn create(path : &str) -> io::Result<WavSink> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        write_wav_header(&mut writer, 0)?;
+
+        Ok(WavSink {
+            writer,
+            data_len : 0,
+            error : None,
+            finished : false,
+        })
+    } // fn create
+
+    // Number of samples recorded so far.
+    pub fn samples(&self) -> u32 {
+        self.data_len / 2
+    }
+
+    fn finalize(&mut self) -> io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        self.writer.seek(SeekFrom::Start(0))?;
+        write_wav_header(&mut self.writer, self.data_len)?;
+        self.writer.flush()
+    } // fn finalize
+}
+
+impl AudioSink for WavSink {
+    fn write(&mut self, samples: &[i16]) {
+        if self.error.is_some() {
+            return;
+        }
+        for s in samples {
+            if let Err(e) = self.writer.write_i16::<LittleEndian>(*s) {
+                self.error = Some(e);
+                return;
+            }
+        }
+        self.data_len += (samples.len() * 2) as u32;
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.finalize()
+    }
+}
+
+impl Drop for WavSink {
+    fn drop(&mut self) {
+        let _ = self.finalize();
+    }
+}
+
+fn write_wav_header<W : Write>(w : &mut W, data_len : u32) -> io::Result<()> {
+    w.write_all(b"RIFF")?;
+    w.write_u32::<LittleEndian>(36 + data_len)?;
+    w.write_all(b"WAVE")?;
+    w.write_all(b"fmt ")?;
+    w.write_u32::<LittleEndian>(16)?;             // fmt chunk size
+    w.write_u16::<LittleEndian>(1)?;              // PCM
+    w.write_u16::<LittleEndian>(1)?;              // mono
+    w.write_u32::<LittleEndian>(SAMPLE_RATE)?;
+    w.write_u32::<LittleEndian>(SAMPLE_RATE * 2)?; // byte rate
+    w.write_u16::<LittleEndian>(2)?;              // block align
+    w.write_u16::<LittleEndian>(16)?;             // bits per sample
+    w.write_all(b"data")?;
+    w.write_u32::<LittleEndian>(data_len)
+}
+
+// Streams raw signed 16 bit little endian mono PCM into the standard
+// input of an external player process, e.g. `aplay` on Linux. If the
+// player goes away the sink silently turns into a NullSink. The writes
+// happen on the emulation thread and block whenever the pipe is full,
+// so a player that falls behind slows the emulation down with it.
+pub struct PipeSink {
+    child : Child,
+    bytes : Vec<u8>,
+}
+
+impl PipeSink {
+    pub fn spawn(program : &str, args : &[&str]) -> io::Result<PipeSink> {
+        let child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()?;
+
+        Ok(PipeSink {
+            child,
+            bytes : Vec::with_capacity(SAMPLES_PER_TICK * 2),
+        })
+    } // fn spawn
+
+    pub fn aplay() -> io::Result<PipeSink> {
+        let rate = SAMPLE_RATE.to_string();
+        PipeSink::spawn("aplay",
+                        &["-q", "-t", "raw", "-f", "S16_LE", "-c", "1", "-r", &rate])
+    }
+}
+
+impl AudioSink for PipeSink {
+    fn write(&mut self, samples: &[i16]) {
+        self.bytes.clear();
+        for s in samples {
+            let _ = self.bytes.write_i16::<LittleEndian>(*s);
+        }
+
+        let failed = match self.child.stdin {
+            Some(ref mut stdin) => stdin.write_all(&self.bytes).is_err(),
+            None => false,
+        };
+        if failed {
+            self.child.stdin = None;
+        }
+    }
+}
+
+impl Drop for PipeSink {
+    fn drop(&mut self) {
+        self.child.stdin = None;
+        let _ = self.child.wait();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::{ByteOrder, LittleEndian};
+    use std::fs;
+    use cpu::Cpu;
+
+    #[test]
+    fn records_the_sound_timer_to_wav() {
+        // Three ticks of tone, then two of silence.
+        let path = ::std::env::temp_dir()
+            .join(format!("chip8-{}-records_the_sound_timer_to_wav.wav", ::std::process::id()))
+            .display().to_string();
+        let mut cpu = Cpu::new();
+        cpu.set_audio_sink(Box::new(WavSink::create(&path).unwrap()));
+        cpu.st = 3;
+        for _ in 0..5 {
+            cpu.tick_timers();
+        }
+        cpu.finish_audio().unwrap();
+        let wav = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let data_len = 5 * SAMPLES_PER_TICK * 2;
+        assert_eq!(wav.len(), 44 + data_len);
+        assert_eq!(&wav[0..4], b"RIFF");
+        assert_eq!(LittleEndian::read_u32(&wav[4..8]) as usize, 36 + data_len);
+        assert_eq!(&wav[8..16], b"WAVEfmt ");
+        assert_eq!(LittleEndian::read_u32(&wav[24..28]), SAMPLE_RATE);
+        assert_eq!(&wav[36..40], b"data");
+        assert_eq!(LittleEndian::read_u32(&wav[40..44]) as usize, data_len);
+
+        let samples : Vec<i16> = wav[44..].chunks(2).map(LittleEndian::read_i16).collect();
+        let tone = 3 * SAMPLES_PER_TICK;
+        assert_eq!(samples[0], 8000);
+        assert!(samples[..tone].iter().all(|s| s.abs() == 8000));
+        assert!(samples[tone..].iter().all(|&s| s == 0));
+    }
+}
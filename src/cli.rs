@@ -0,0 +1,445 @@
+use chip8::{Font, FontVariant, KeyMap, MemoryPolicy, Mode, OpcodePolicy, Palette};
+use chip8::{QuirkPreset, Quirks, Speed, TraceFormat};
+use chip8::headless::{KeyScript, Limit};
+use getopts::{Matches, Options};
+use std::path::Path;
+use std::str::FromStr;
+
+/*
+ * Command line handling of the emulator binary. Every option is checked
+ * here, so the frontends only ever see valid settings.
+ */
+
+pub struct Config {
+    pub rom : String,
+    pub mode : Mode,
+    pub quirks : Quirks,
+    pub scale : usize,
+    pub speed : Speed,
+    pub font : Font,
+    pub palette : Palette,
+    pub keymap : KeyMap,
+    pub opcodes : OpcodePolicy,
+    pub addresses : MemoryPolicy,
+    pub paused : bool,
+    pub debug : bool,
+    pub tui : bool,
+    pub gdb : Option<u16>,
+    pub seed : Option<u64>,
+    pub wav : Option<String>,
+    pub headless : Option<Headless>,
+    pub trace : Option<Trace>,
+}
+
+// Settings that only matter without a window.
+pub struct Headless {
+    pub limit : Limit,
+    pub keys : KeyScript,
+    pub png : Option<String>,
+    pub memory : Option<String>,
+}
+
+// Where and what to trace, the file is only created by main.
+pub struct Trace {
+    pub path : String,              // "-" for stderr
+    pub format : TraceFormat,
+    pub range : Option<(u16, u16)>,
+    pub families : u16,
+}
+
+pub enum Command {
+    Run(Box<Config>),
+    Disasm { rom : String, mode : Mode },
+    Asm { source : String, output : String, listing : Option<String> },
+    Dap { port : Option<u16> },
+    Help(String),
+}
+
+fn options() -> Options {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help and exit");
+    opts.optopt("", "mode", "instruction set: chip8, schip or xochip (default chip8)", "MODE");
+    opts.optopt("", "quirks", "vip, chip48, schip, xochip or modern (default: schip and \
+                               xochip for those modes, modern otherwise)", "PRESET");
+    opts.optopt("s", "scale", "window scale: 1, 2, 4, 8, 16 or 32 (default 4)", "N");
+    opts.optopt("", "ipf", "instructions per frame (default 10)", "N");
+    opts.optopt("", "hz", "instructions per second, instead of --ipf", "N");
+    opts.optopt("", "font", "cowgod, vip, eti660, dream6800, schip or an 80 or 240 byte \
+                                font file",
+                "NAME|FILE");
+    opts.optopt("", "palette", "comma separated hex colors, background first \
+                                (default 000000,FFFFFF, with xochip \
+                                000000,FFFFFF,AAAAAA,555555)", "COLORS");
+    opts.optopt("", "keymap", "file binding host keys to CHIP-8 keys", "FILE");
+    opts.optopt("", "opcodes", "on unknown opcodes: ignore, log or halt (default log)",
+                "POLICY");
+    opts.optopt("", "addresses", "on memory addresses past the end: wrap, fault or amiga \
+                                  (wrap, and Fx1E sets VF on overflow; default wrap)",
+                "POLICY");
+    opts.optflag("", "paused", "start paused, P pauses and resumes");
+    opts.optopt("", "seed", "seed for the random number generator", "N");
+    opts.optopt("", "wav", "record the buzzer to a WAV file instead of playing it", "FILE");
+    opts.optopt("", "trace", "trace every instruction to a file, - for stderr", "FILE");
+    opts.optopt("", "trace-format", "trace as text or jsonl (default text)", "FORMAT");
+    opts.optopt("", "trace-range", "only trace instructions at these addresses, e.g. 200-2FF",
+                "START-END");
+    opts.optopt("", "trace-ops", "only trace these opcode families, e.g. 8,D", "LIST");
+    opts.optflag("", "debug", "run under a debugger prompt instead of a window");
+    opts.optflag("", "tui", "run in the terminal instead of a window, with debugger panels");
+    opts.optopt("", "gdb", "serve GDB's remote protocol on a local port instead of a window",
+                "PORT");
+    opts.optflag("", "headless", "run without a window and dump the result");
+    opts.optopt("", "frames", "headless: frames to run (default 60)", "N");
+    opts.optopt("", "instructions", "headless: instructions to run, instead of --frames",
+                "N");
+    opts.optopt("", "keys", "headless: key script", "FILE");
+    opts.optopt("", "png", "headless: write the display to a PNG file", "FILE");
+    opts.optopt("", "memory", "headless: write a hex dump of memory to a file", "FILE");
+    opts
+} // fn options
+
+pub fn parse(program : &str, args : &[String]) -> Result<Command, String> {
+    if args.first().map(|a| a.as_str()) == Some("disasm") {
+        return parse_disasm(program, &args[1..]);
+    }
+    if args.first().map(|a| a.as_str()) == Some("asm") {
+        return parse_asm(program, &args[1..]);
+    }
+    if args.first().map(|a| a.as_str()) == Some("dap") {
+        return parse_dap(program, &args[1..]);
+    }
+
+    let opts = options();
+    let brief = format!("Usage: {0} [options] ROM\n       {0} disasm [options] ROM\n       \
+                         {0} asm [options] SOURCE\n       {0} dap [options]\n\n\
+                         A ROM ending in .8o is compiled as Octo first.", program);
+
+    let m = opts.parse(args).map_err(|e| format!("{}\n{}", e, opts.short_usage(program)))?;
+    if m.opt_present("help") {
+        return Ok(Command::Help(opts.usage(&brief)));
+    }
+
+    let rom = match m.free.as_slice() {
+        [rom] => rom.clone(),
+        [] => return Err(format!("no ROM given\n{}", opts.short_usage(program))),
+        _ => return Err(format!("only one ROM can be run at a time\n{}",
+                                opts.short_usage(program))),
+    };
+
+    let mode = mode(&m)?;
+
+    let quirks = match m.opt_str("quirks") {
+        None => Quirks::preset(match mode {
+            Mode::Chip8 => QuirkPreset::Modern,
+            Mode::SuperChip => QuirkPreset::Schip,
+            Mode::XoChip => QuirkPreset::XoChip,
+        }),
+        Some(name) => QuirkPreset::from_name(&name).map(Quirks::preset)
+            .ok_or_else(|| format!("--quirks must be vip, chip48, schip, xochip or modern, \
+                                    not {}", name))?,
+    };
+
+    let scale = match number::<u64>(&m, "scale")? {
+        None => 4,
+        Some(n) if [1, 2, 4, 8, 16, 32].contains(&n) => n as usize,
+        Some(n) => return Err(format!("--scale must be 1, 2, 4, 8, 16 or 32, not {}", n)),
+    };
+
+    let speed = match (number::<u32>(&m, "ipf")?, number::<u32>(&m, "hz")?) {
+        (Some(_), Some(_)) => return Err(String::from("--ipf and --hz cannot be combined")),
+        (Some(0), _) | (_, Some(0)) => return Err(String::from("the speed must be above 0")),
+        (Some(n), None) => Speed::InstructionsPerFrame(n),
+        (None, Some(n)) => Speed::Hz(n),
+        (None, None) => Speed::default(),
+    };
+
+    let font = match m.opt_str("font") {
+        None => Font::default(),
+        Some(name) => match FontVariant::from_name(&name) {
+            Some(variant) => Font::builtin(variant),
+            None => Font::from_file(&name).map_err(|e| format!("--font {}: {}", name, e))?,
+        },
+    };
+
+    let palette = match m.opt_str("palette") {
+        None if mode == Mode::XoChip => Palette::xo_chip(),
+        None => Palette::default(),
+        Some(text) => Palette::parse(&text).map_err(|e| format!("--palette: {}", e))?,
+    };
+
+    let keymap = match m.opt_str("keymap") {
+        None => KeyMap::default(),
+        Some(path) => KeyMap::from_file(&path).map_err(|e| format!("--keymap {}: {}", path, e))?,
+    };
+
+    let opcodes = match m.opt_str("opcodes").as_deref() {
+        None | Some("log") => OpcodePolicy::Hook(Box::new(|_, fault| {
+            eprintln!("{}, skipped", fault);
+            Ok(())
+        })),
+        Some("ignore") => OpcodePolicy::Ignore,
+        Some("halt") => OpcodePolicy::Halt,
+        Some(other) => return Err(format!("--opcodes must be ignore, log or halt, not {}",
+                                          other)),
+    };
+
+    let addresses = match m.opt_str("addresses").as_deref() {
+        None | Some("wrap") => MemoryPolicy::Wrap,
+        Some("fault") => MemoryPolicy::Fault,
+        Some("amiga") => MemoryPolicy::FlagOverflow,
+        Some(other) => return Err(format!("--addresses must be wrap, fault or amiga, not {}",
+                                          other)),
+    };
+
+    if m.opt_present("debug") && m.opt_present("headless") {
+        return Err(String::from("--debug and --headless cannot be combined"));
+    }
+    let gdb = match m.opt_str("gdb") {
+        None => None,
+        Some(port) => Some(port.parse::<u16>()
+            .map_err(|_| format!("--gdb expects a port number, got `{}`", port))?),
+    };
+    if gdb.is_some() && (m.opt_present("debug") || m.opt_present("headless")) {
+        return Err(String::from("--gdb cannot be combined with --debug or --headless"));
+    }
+    if m.opt_present("tui") &&
+       (gdb.is_some() || m.opt_present("debug") || m.opt_present("headless")) {
+        return Err(String::from("--tui cannot be combined with --gdb, --debug or --headless"));
+    }
+
+    let headless = if m.opt_present("headless") {
+        Some(headless(&m)?)
+    } else {
+        for name in &["frames", "instructions", "keys", "png", "memory"] {
+            if m.opt_present(name) {
+                return Err(format!("--{} only works together with --headless", name));
+            }
+        }
+        None
+    };
+
+    let trace = if m.opt_present("trace") {
+        Some(trace(&m)?)
+    } else {
+        for name in &["trace-format", "trace-range", "trace-ops"] {
+            if m.opt_present(name) {
+                return Err(format!("--{} only works together with --trace", name));
+            }
+        }
+        None
+    };
+
+    Ok(Command::Run(Box::new(Config {
+        rom,
+        mode,
+        quirks,
+        scale,
+        speed,
+        font,
+        palette,
+        keymap,
+        opcodes,
+        addresses,
+        paused : m.opt_present("paused"),
+        debug : m.opt_present("debug"),
+        tui : m.opt_present("tui"),
+        gdb,
+        seed : number(&m, "seed")?,
+        wav : m.opt_str("wav"),
+        headless,
+        trace,
+    })))
+} // fn parse
+
+fn parse_disasm(program : &str, args : &[String]) -> Result<Command, String> {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help and exit");
+    opts.optopt("", "mode", "instruction set: chip8, schip or xochip (default chip8)", "MODE");
+    let brief = format!("Usage: {} disasm [options] ROM", program);
+
+    let m = opts.parse(args).map_err(|e| format!("{}\n{}", e, opts.short_usage(program)))?;
+    if m.opt_present("help") {
+        return Ok(Command::Help(opts.usage(&brief)));
+    }
+
+    match m.free.as_slice() {
+        [rom] => Ok(Command::Disasm { rom : rom.clone(), mode : mode(&m)? }),
+        _ => Err(format!("disasm expects one ROM\n{}", opts.usage(&brief))),
+    }
+} // fn parse_disasm
+
+fn parse_asm(program : &str, args : &[String]) -> Result<Command, String> {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help and exit");
+    opts.optopt("o", "output", "ROM to write (default: SOURCE with a .ch8 extension)", "FILE");
+    opts.optopt("", "listing", "write a listing with addresses and bytes", "FILE");
+    let brief = format!("Usage: {} asm [options] SOURCE\n\n\
+                         SOURCE ending in .8o is compiled as Octo.", program);
+
+    let m = opts.parse(args).map_err(|e| format!("{}\n{}", e, opts.short_usage(program)))?;
+    if m.opt_present("help") {
+        return Ok(Command::Help(opts.usage(&brief)));
+    }
+
+    let source = match m.free.as_slice() {
+        [source] => source.clone(),
+        _ => return Err(format!("asm expects one source file\n{}", opts.usage(&brief))),
+    };
+    let output = m.opt_str("output").unwrap_or_else(|| {
+        Path::new(&source).with_extension("ch8").display().to_string()
+    });
+    if output == source {
+        return Err(format!("the ROM would overwrite {}, use --output", source));
+    }
+
+    Ok(Command::Asm { source, output, listing : m.opt_str("listing") })
+} // fn parse_asm
+
+fn parse_dap(program : &str, args : &[String]) -> Result<Command, String> {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help and exit");
+    opts.optopt("", "port", "listen on a local port instead of stdin and stdout", "PORT");
+    let brief = format!("Usage: {} dap [options]\n\n\
+                         Serves the debug adapter protocol, the ROM comes with `launch`.",
+                        program);
+
+    let m = opts.parse(args).map_err(|e| format!("{}\n{}", e, opts.short_usage(program)))?;
+    if m.opt_present("help") {
+        return Ok(Command::Help(opts.usage(&brief)));
+    }
+    if !m.free.is_empty() {
+        return Err(format!("dap takes no arguments\n{}", opts.usage(&brief)));
+    }
+
+    let port = match m.opt_str("port") {
+        None => None,
+        Some(port) => Some(port.parse::<u16>()
+            .map_err(|_| format!("--port expects a port number, got `{}`", port))?),
+    };
+    Ok(Command::Dap { port })
+}
+
+fn mode(m : &Matches) -> Result<Mode, String> {
+    match m.opt_str("mode") {
+        None => Ok(Mode::Chip8),
+        Some(name) => Mode::from_name(&name)
+            .ok_or_else(|| format!("--mode must be chip8, schip or xochip, not {}", name)),
+    }
+}
+
+fn headless(m : &Matches) -> Result<Headless, String> {
+    let limit = match (number(m, "frames")?, number(m, "instructions")?) {
+        (Some(_), Some(_)) =>
+            return Err(String::from("--frames and --instructions cannot be combined")),
+        (_, Some(n)) => Limit::Instructions(n),
+        (Some(n), None) => Limit::Frames(n),
+        (None, None) => Limit::Frames(60),
+    };
+
+    let keys = match m.opt_str("keys") {
+        None => KeyScript::new(),
+        Some(path) => KeyScript::from_file(&path).map_err(|e| format!("--keys {}: {}", path, e))?,
+    };
+
+    Ok(Headless {
+        limit,
+        keys,
+        png : m.opt_str("png"),
+        memory : m.opt_str("memory"),
+    })
+} // fn headless
+
+fn trace(m : &Matches) -> Result<Trace, String> {
+    let format = match m.opt_str("trace-format") {
+        None => TraceFormat::Text,
+        Some(name) => TraceFormat::from_name(&name)
+            .ok_or_else(|| format!("--trace-format must be text or jsonl, not {}", name))?,
+    };
+
+    let range = match m.opt_str("trace-range") {
+        None => None,
+        Some(text) => {
+            let bounds : Vec<Option<u16>> = text.split('-')
+                .map(|b| u16::from_str_radix(b.trim(), 16).ok())
+                .collect();
+            match bounds.as_slice() {
+                [Some(start), Some(end)] if start <= end => Some((*start, *end)),
+                _ => return Err(format!("--trace-range expects START-END in hex, got `{}`",
+                                        text)),
+            }
+        },
+    };
+
+    let families = match m.opt_str("trace-ops") {
+        None => 0xFFFF,
+        Some(text) => {
+            let mut families = 0;
+            for part in text.split(',') {
+                match u8::from_str_radix(part.trim(), 16) {
+                    Ok(n) if n < 16 => families |= 1 << n,
+                    _ => return Err(format!("--trace-ops expects hex digits 0-F, got `{}`",
+                                            part)),
+                }
+            }
+            families
+        },
+    };
+
+    Ok(Trace {
+        path : m.opt_str("trace").unwrap_or_default(),
+        format,
+        range,
+        families,
+    })
+} // fn trace
+
+// Values too large for T are refused like any other bad number.
+fn number<T : FromStr>(m : &Matches, name : &str) -> Result<Option<T>, String> {
+    match m.opt_str(name) {
+        None => Ok(None),
+        Some(value) => value.parse().map(Some)
+            .map_err(|_| format!("--{} expects a number, got `{}`", name, value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(args : &[&str]) -> String {
+        let args : Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        match parse("chip8", &args) {
+            Ok(_) => panic!("{:?} parsed", args),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn refuses_bad_speeds() {
+        assert_eq!(error(&["--ipf", "fast", "rom.ch8"]), "--ipf expects a number, got `fast`");
+        assert_eq!(error(&["--hz", "-1", "rom.ch8"]), "--hz expects a number, got `-1`");
+        assert_eq!(error(&["--hz", "4294967296", "rom.ch8"]),
+                   "--hz expects a number, got `4294967296`");
+        assert_eq!(error(&["--ipf", "0", "rom.ch8"]), "the speed must be above 0");
+        assert_eq!(error(&["--ipf", "10", "--hz", "600", "rom.ch8"]),
+                   "--ipf and --hz cannot be combined");
+    }
+
+    #[test]
+    fn refuses_bad_palettes() {
+        assert_eq!(error(&["--palette", "000000", "rom.ch8"]),
+                   "--palette: a palette needs at least two colors");
+        assert_eq!(error(&["--palette", "000000,+12345", "rom.ch8"]),
+                   "--palette: bad color `+12345`, expected RRGGBB");
+        assert_eq!(error(&["--palette", "#000000,FFFFF", "rom.ch8"]),
+                   "--palette: bad color `FFFFF`, expected RRGGBB");
+    }
+
+    #[test]
+    fn refuses_bad_quirks_and_opcodes() {
+        assert_eq!(error(&["--quirks", "cosmac", "rom.ch8"]),
+                   "--quirks must be vip, chip48, schip, xochip or modern, not cosmac");
+        assert_eq!(error(&["--opcodes", "skip", "rom.ch8"]),
+                   "--opcodes must be ignore, log or halt, not skip");
+    }
+}
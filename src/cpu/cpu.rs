@@ -1,104 +1,526 @@
+use std::io;
 use std::io::prelude::*;
 use std::fs::File;
+use std::mem;
+use rand;
+use rand::{Rng, SeedableRng, XorShiftRng};
+use std::path::Path;
 use cpu::opcode::*;
-use std::time;
-use super::time as ttime;
-use std::thread;
-#[allow(unused)]
+use audio::{AudioSink, Beeper, NullSink};
+use trace::{Record, Registers, Tracer};
+use cpu::instruction::{decode_in, Instruction};
+use cpu::font::{Font, FONT_ADDR, BIG_FONT_ADDR};
+use cpu::error::{Fault, LoadError};
+use cpu::scheduler::Scheduler;
+use cpu::quirks::Quirks;
+
+pub const DISPLAY_WIDTH : usize = 64;
+pub const DISPLAY_HEIGHT : usize = 32;
+pub const HIRES_WIDTH : usize = 128;        // SUPER-CHIP high resolution
+pub const HIRES_HEIGHT : usize = 64;
+pub const PROGRAM_START : u16 = 0x200;    // Where ROMs are loaded and run from
+pub const MEMORY_SIZE : usize = 0x1000;
+pub const STACK_SIZE : usize = 16;          // Deepest stack of any interpreter
+pub const XO_MEMORY_SIZE : usize = 0x10000;     // XO-CHIP 64 KiB address space
+
+// The instruction set the interpreter understands. Each one extends
+// the one before it, instructions of a later set are treated as
+// unknown opcodes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Mode {
+    Chip8,
+    SuperChip,              // SUPER-CHIP 1.1
+    XoChip,                 // XO-CHIP
+}
+
+impl Mode {
+    pub const ALL : [Mode; 3] = [Mode::Chip8, Mode::SuperChip, Mode::XoChip];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Mode::Chip8 => "chip8",
+            Mode::SuperChip => "schip",
+            Mode::XoChip => "xochip",
+        }
+    }
+
+    pub fn memory_size(self) -> usize {
+        match self {
+            Mode::XoChip => XO_MEMORY_SIZE,
+            _ => MEMORY_SIZE,
+        }
+    }
+
+    pub fn from_name(name : &str) -> Option<Mode> {
+        let name = name.to_lowercase();
+        Mode::ALL.iter()
+            .find(|m| m.name() == name)
+            .copied()
+    }
+}
 
+pub type OpcodeHook = Box<dyn FnMut(&mut Cpu, Fault) -> Result<(), Fault>>;
+
+// What to do when the interpreter meets an opcode it does not know
+// (or a 0nnn machine code call, which it cannot run). The library never
+// prints anything itself, a hook can report the opcode and go on.
+pub enum OpcodePolicy {
+    Ignore,                 // Skip it silently
+    Halt,                   // Stop with a Fault::UnknownOpcode
+    // Let the caller decide. The hook may emulate the opcode by
+    // changing the Cpu, return Ok to go on or Err to halt.
+    Hook(OpcodeHook),
+}
+
+// What to do when an instruction addresses memory past its end.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MemoryPolicy {
+    #[default]
+    Wrap,                   // Wrap around to address 0
+    Fault,                  // Stop with a Fault::BadAddress
+    // Wrap, and let Fx1E set VF when I runs past the end, which the
+    // Amiga interpreter did and a few ROMs rely on.
+    FlagOverflow,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessKind {
+    Read,
+    Write,
+    Execute,                // Fetched as (part of) an instruction
+}
+
+// A memory access of the last instruction, see Cpu::log_accesses.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Access {
+    pub kind : AccessKind,
+    pub addr : usize,
+    pub old : u8,           // The same as new unless written
+    pub new : u8,
+}
+
+#[allow(unused)]
 pub struct Cpu {
     rom_buffer : Vec<u8>,
     pub opcode : u16,
-    pub memory : [u8; 4096],
+    pub memory : Vec<u8>,           // Sized by Mode::memory_size
     pub v_regs : [u8; 16],
     // V0 - VE: general purpose registers,
     // VF: carry flag register
     pub i_reg : u16,                // Index register
     pub pc : u16,               // Program Counter
-    pub v_memory : [u32; 64 * 32],   // Video memory
-    pub stack : Vec<u16>,          // Stack memory
-    pub sp : u16,                   // Stack Pointer
-    pub dt : u8,
+    pub v_memory : Vec<u8>,         // Video memory, see display_size
+    pub hires : bool,               // SUPER-CHIP 128x64 mode
+    pub planes : u8,                // XO-CHIP bitplanes drawn to (Fn01)
+    pub stack : [u16; STACK_SIZE],  // Return addresses, stack[..sp] are in use
+    pub sp : u16,                   // Stack Pointer, number of return addresses
+    pub dt : u8,                    // Delay timer
+    pub st : u8,                    // Sound timer
     pub keypad : [u8; 16],
+    pub rpl : [u8; 16],             // SUPER-CHIP RPL user flags
+    pub mode : Mode,
+    pub exited : bool,              // Set by 00FD, stops execution
+    pub quirks : Quirks,
+    pub opcode_policy : OpcodePolicy,
+    pub memory_policy : MemoryPolicy,
+    pub scheduler : Scheduler,
+    pub rng : XorShiftRng,          // Source of CXKK random numbers
+    pub frames : u64,               // Frames emulated since reset
+    pub instructions : u64,         // Instructions executed since reset
+    font : Font,
+    beeper : Beeper,
+    audio : Box<dyn AudioSink>,
+    tracer : Option<Tracer>,
+    log_accesses : bool,
+    accesses : Vec<Access>,
 }
 
 impl Cpu {
     pub fn new() -> Cpu {
 
-        Cpu {
+        let mut cpu = Cpu {
             rom_buffer : Vec::new(),
             opcode : 0,
-            memory : [0; 4096],
+            memory : vec![0; MEMORY_SIZE],
             v_regs : [0; 16],
             i_reg : 0,
-            pc : 0x200,
+            pc : PROGRAM_START,
             sp : 0,
-            stack : Vec::new(),
-            v_memory : [0; 64 * 32],
+            stack : [0; STACK_SIZE],
+            v_memory : vec![0; DISPLAY_WIDTH * DISPLAY_HEIGHT],
+            hires : false,
+            planes : 1,
             dt : 0,
+            st : 0,
             keypad : [0; 16],
-        }
+            rpl : [0; 16],
+            mode : Mode::Chip8,
+            exited : false,
+            quirks : Quirks::default(),
+            opcode_policy : OpcodePolicy::Ignore,
+            memory_policy : MemoryPolicy::default(),
+            scheduler : Scheduler::default(),
+            rng : rand::thread_rng().gen(),
+            frames : 0,
+            instructions : 0,
+            font : Font::default(),
+            beeper : Beeper::new(),
+            audio : Box::new(NullSink),
+            tracer : None,
+            log_accesses : false,
+            accesses : Vec::new(),
+        };
+        cpu.install_font();
+        cpu
     } // fn new
 
-    pub fn load_rom(&mut self, path: String) {
+    pub fn reset(&mut self) {
+        // Bring the machine back to its power-on state, keeping the
+        // selected font and the loaded ROM (which is copied back).
+        // Like on the HP48 the RPL flags survive a reset.
+        // Memory is resized to what the current mode can address.
+
+        self.opcode = 0;
+        self.memory.clear();
+        self.memory.resize(self.mode.memory_size(), 0);
+        self.v_regs = [0; 16];
+        self.i_reg = 0;
+        self.pc = PROGRAM_START;
+        self.sp = 0;
+        self.stack = [0; STACK_SIZE];
+        self.set_hires(false);
+        self.planes = 1;
+        self.exited = false;
+        self.dt = 0;
+        self.st = 0;
+        self.keypad = [0; 16];
+        self.beeper.clear_pattern();
+        self.frames = 0;
+        self.instructions = 0;
+
+        // load the rom to the memory
+        // the starting address is 0x200 (512)
+        self.install_font();
+        // A ROM loaded for XO-CHIP may not fit once the mode is changed
+        // to a smaller memory, then only what fits is copied.
+        let rom_start = PROGRAM_START as usize;
+        let len = self.rom_buffer.len().min(self.memory.len() - rom_start);
+        self.memory[rom_start..rom_start + len].copy_from_slice(&self.rom_buffer[..len]);
+    } // fn reset
+
+    pub fn font(&self) -> &Font {
+        &self.font
+    }
+
+    pub fn set_font(&mut self, font : Font) {
+        self.font = font;
+        self.install_font();
+    }
+
+    fn install_font(&mut self) {
+        let start = FONT_ADDR as usize;
+        self.memory[start..start + self.font.small.len()]
+            .copy_from_slice(&self.font.small);
+
+        let start = BIG_FONT_ADDR as usize;
+        self.memory[start..start + self.font.big.len()]
+            .copy_from_slice(&self.font.big);
+    }
+
+    pub fn seed_rng(&mut self, seed : u64) {
+        // Make CXKK repeatable. XorShift must not be seeded with zeros
+        // only, so the upper half of the state is never zero.
+        let lo = seed as u32;
+        let hi = (seed >> 32) as u32;
+        self.rng = XorShiftRng::from_seed([lo, hi, lo ^ 0x9E37_79B9, hi ^ 0x7F4A_7C15]);
+    }
+
+    pub fn set_audio_sink(&mut self, sink : Box<dyn AudioSink>) {
+        self.audio = sink;
+    }
+
+    // Complete the audio output, e.g. patch a WAV header, once the
+    // program is over.
+    pub fn finish_audio(&mut self) -> io::Result<()> {
+        self.audio.finish()
+    }
+
+    pub fn set_tracer(&mut self, tracer : Option<Tracer>) {
+        self.tracer = tracer;
+    }
+
+    pub fn tracer(&mut self) -> Option<&mut Tracer> {
+        self.tracer.as_mut()
+    }
+
+    // Keep a log of the memory accesses of every instruction, which a
+    // debugger reads after each step. Off by default, it costs time.
+    pub fn log_accesses(&mut self, on : bool) {
+        self.log_accesses = on;
+        self.accesses.clear();
+    }
+
+    // The memory accesses of the last instruction, in order.
+    pub fn accesses(&self) -> &[Access] {
+        &self.accesses
+    }
+
+    pub fn beeper(&mut self) -> &mut Beeper {
+        &mut self.beeper
+    }
+
+    pub fn load_rom<P : AsRef<Path>>(&mut self, path : P) -> Result<(), LoadError> {
+        let path = path.as_ref();
+
+        let mut f = File::open(path).map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound =>
+                LoadError::NotFound(path.display().to_string()),
+            _ => LoadError::Io(e),
+        })?;
+
+        let mut rom = Vec::new();
+        f.read_to_end(&mut rom)?;
+        self.load_rom_bytes(&rom)
+    } // fn load_rom
 
-        let mut f = File::open(path)
-        .expect("Error with file loading!");
+    pub fn load_rom_bytes(&mut self, rom : &[u8]) -> Result<(), LoadError> {
+        // The ROM is kept in rom_buffer and copied to memory by reset,
+        // so the machine always starts from its power-on state.
 
-        f.read_to_end(&mut self.rom_buffer)
-        .expect("Error with file reading!");
+        let max = self.mode.memory_size() - PROGRAM_START as usize;
+        if rom.is_empty() {
+            return Err(LoadError::Empty);
+        }
+        if rom.len() > max {
+            return Err(LoadError::TooLarge { size : rom.len(), max });
+        }
+
+        self.rom_buffer = rom.to_vec();
+        self.reset();
+        Ok(())
+    } // fn load_rom_bytes
 
-        for i in &self.rom_buffer {
-            print!("{:x} ", i);
+    pub fn step(&mut self) -> Result<(), Fault> {
+        // Run a single instruction without touching the timers.
+        // Once the ROM has exited (00FD) nothing happens any more.
+
+        if self.exited {
+            return Ok(());
         }
-        println!("\nRom length (in bytes): {}", self.rom_buffer.len());
 
-        // load the rom to the memory
-        // the starting address is 0x200 (512)
-        for i in 0..self.rom_buffer.len() {
-            self.memory[i + 512] = self.rom_buffer[i];
+        self.accesses.clear();
+        fetch(self)?;       // fetch opcode from memory
+
+        let pc = self.pc;
+        let traced = match self.tracer {
+            Some(ref tracer) => tracer.wants(pc, self.opcode),
+            None => false,
+        };
+        let before = self.registers();
+
+        execute(self)?;     // execute opcode
+
+        if traced {
+            let record = Record {
+                cycle : self.instructions,
+                pc,
+                opcode : self.opcode,
+                instruction : decode_in(self.opcode, self.mode),
+                before,
+                after : self.registers(),
+            };
+            if let Some(ref mut tracer) = self.tracer {
+                tracer.record(&record);
+            }
         }
+        self.instructions += 1;
+        Ok(())
+    } // fn step
 
-        let font_set : [u8; 80] = [
-              0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
-              0x20, 0x60, 0x20, 0x20, 0x70, // 1
-              0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
-              0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
-              0x90, 0x90, 0xF0, 0x10, 0x10, // 4
-              0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
-              0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
-              0xF0, 0x10, 0x20, 0x40, 0x40, // 7
-              0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
-              0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
-              0xF0, 0x90, 0xF0, 0x90, 0x90, // A
-              0xE0, 0x90, 0xE0, 0x90, 0xE0, // B
-              0xF0, 0x80, 0x80, 0x80, 0xF0, // C
-              0xE0, 0x90, 0x90, 0x90, 0xE0, // D
-              0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
-              0xF0, 0x80, 0xF0, 0x80, 0x80]; // F
-
-        for i in 0..80 {
-            self.memory[i] = font_set[i];
+    fn registers(&self) -> Registers {
+        Registers {
+            v : self.v_regs,
+            i : self.i_reg,
+            pc : self.pc,
+            sp : self.sp,
+            dt : self.dt,
+            st : self.st,
         }
+    }
 
-    } // load_rom
+    pub fn run_frame(&mut self) -> Result<(), Fault> {
+        // Emulate one 60 Hz frame: execute as many instructions as the
+        // scheduler asks for, then tick the timers once. A fault stops
+        // the frame right away and leaves the timers alone.
+
+        let count = self.scheduler.next_frame();
+        for _ in 0..count {
+            self.step()?;
+        }
 
-    pub fn cycle(&mut self) {
-        let start = ttime::get_time();
+        self.end_frame();
+        Ok(())
+    } // fn run_frame
+
+    pub fn end_frame(&mut self) {
+        // Close a frame driven by hand with step, e.g. by a debugger.
+
+        self.tick_timers();
+        self.frames += 1;
+    }
+
+    pub(crate) fn unknown_opcode(&mut self) -> Result<(), Fault> {
+        // Called with pc already past the offending opcode. On a halt
+        // pc is moved back, so the fault is reported at its address.
+
+        let fault = Fault::UnknownOpcode {
+            opcode : self.opcode,
+            addr : self.pc.wrapping_sub(2),
+        };
+
+        let result = match self.opcode_policy {
+            OpcodePolicy::Ignore => Ok(()),
+            OpcodePolicy::Halt => Err(fault),
+            OpcodePolicy::Hook(_) => {
+                let mut policy = mem::replace(&mut self.opcode_policy,
+                                              OpcodePolicy::Ignore);
+                let result = match policy {
+                    OpcodePolicy::Hook(ref mut hook) => hook(self, fault),
+                    _ => unreachable!(),
+                };
+                self.opcode_policy = policy;
+                result
+            },
+        };
+
+        if result.is_err() {
+            self.pc = self.pc.wrapping_sub(2);
+        }
+        result
+    } // fn unknown_opcode
+
+    pub(crate) fn check_address(&self, target : usize, addr : u16) -> Result<usize, Fault> {
+        // Turn target into an index into memory, or a fault reported at
+        // the instruction at addr, depending on the memory policy.
+
+        match self.memory_policy {
+            MemoryPolicy::Fault if target >= self.memory.len() =>
+                Err(Fault::BadAddress { target, addr }),
+            _ => Ok(target % self.memory.len()),
+        }
+    }
+
+    // Memory access for the instructions. Like unknown_opcode these
+    // expect pc to be past the running instruction already, which is
+    // where a fault is reported.
+
+    pub fn read_byte(&mut self, target : usize) -> Result<u8, Fault> {
+        let index = self.check_address(target, self.pc.wrapping_sub(2))?;
+        let value = self.memory[index];
+        self.log_access(AccessKind::Read, index, value, value);
+        Ok(value)
+    }
+
+    pub fn read_word(&mut self, target : usize) -> Result<u16, Fault> {
+        let hi = self.read_byte(target)? as u16;
+        let lo = self.read_byte(target + 1)? as u16;
+        Ok(hi << 8 | lo)
+    }
+
+    pub fn write_byte(&mut self, target : usize, value : u8) -> Result<(), Fault> {
+        let index = self.check_address(target, self.pc.wrapping_sub(2))?;
+        let old = self.memory[index];
+        self.memory[index] = value;
+        self.log_access(AccessKind::Write, index, old, value);
+        Ok(())
+    }
+
+    // A byte of the instruction at addr: its opcode, or the nnnn of
+    // F000 nnnn.
+    pub(crate) fn fetch_byte(&mut self, target : usize, addr : u16) -> Result<u8, Fault> {
+        let index = self.check_address(target, addr)?;
+        let value = self.memory[index];
+        self.log_access(AccessKind::Execute, index, value, value);
+        Ok(value)
+    }
+
+    // A look at memory by the interpreter itself, which is not logged.
+    // The instruction at addr as this Cpu runs it, the place to take
+    // the size of an instruction from. Bytes past the end read as 0.
+    pub fn instruction_at(&self, addr : u16) -> Instruction {
+        let byte = |a : usize| self.memory.get(a).copied().unwrap_or(0) as u16;
+        decode_in(byte(addr as usize) << 8 | byte(addr as usize + 1), self.mode)
+    }
+
+    pub(crate) fn peek_word(&self, target : usize) -> Option<u16> {
+        let hi = self.check_address(target, 0).ok()?;
+        let lo = self.check_address(target + 1, 0).ok()?;
+        Some((self.memory[hi] as u16) << 8 | self.memory[lo] as u16)
+    }
+
+    fn log_access(&mut self, kind : AccessKind, addr : usize, old : u8, new : u8) {
+        // DRW reads every sprite byte once per pixel, repeats are kept
+        // only once.
+        if !self.log_accesses {
+            return;
+        }
+        let access = Access { kind, addr, old, new };
+        if self.accesses.last() != Some(&access) {
+            self.accesses.push(access);
+        }
+    }
+
+    pub fn display_size(&self) -> (usize, usize) {
+        if self.hires {
+            (HIRES_WIDTH, HIRES_HEIGHT)
+        } else {
+            (DISPLAY_WIDTH, DISPLAY_HEIGHT)
+        }
+    }
+
+    pub fn set_hires(&mut self, hires : bool) {
+        // Switching the resolution resizes the display and clears it.
+
+        self.hires = hires;
+        let (width, height) = self.display_size();
+        self.v_memory.clear();
+        self.v_memory.resize(width * height, 0);
+    }
+
+    pub fn framebuffer(&self) -> &[u8] {
+        // One byte per pixel, row by row, display_size().0 pixels wide.
+        // Every bit is one bitplane: 0 is dark, 1 is lit and with XO-CHIP
+        // planes 2, 3, ... are possible too. A Palette turns them into
+        // colors.
+        &self.v_memory
+    }
+
+    pub fn set_key(&mut self, key : usize, pressed : bool) {
+        self.keypad[key & 0xF] = pressed as u8;
+    }
+
+    pub fn release_keys(&mut self) {
+        self.keypad = [0; 16];
+    }
+
+    pub fn tick_timers(&mut self) {
+        // Both timers count down towards zero at 60 Hz. The buzzer
+        // sounds for as long as the sound timer is non-zero.
 
-        fetch(self);        // fetch opcode from rom_buffer
-        execute(self);      // execute opcode
         if self.dt > 0 {
             self.dt -= 1;
         }
 
-        let stop = ttime::get_time();
-        let diff = 16 - ((stop.nsec - start.nsec)/1000) as i64;
-        if 0 < diff && diff < 20 {
-            thread::sleep(time::Duration::from_millis(diff as u64));
+        self.beeper.tick(self.st > 0, &mut *self.audio);
+        if self.st > 0 {
+            self.st -= 1;
         }
+    } // fn tick_timers
 
-    } // fn cycle
-
+}
 
+impl Default for Cpu {
+    fn default() -> Cpu {
+        Cpu::new()
+    }
 }
@@ -0,0 +1,70 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum LoadError {
+    NotFound(String),                       // The path that could not be found
+    Io(io::Error),                          // Any other error while reading
+    TooLarge { size : usize, max : usize }, // ROM does not fit in memory
+    Empty,
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LoadError::NotFound(ref path) =>
+                write!(f, "ROM file not found: {}", path),
+            LoadError::Io(ref e) =>
+                write!(f, "error while reading the ROM: {}", e),
+            LoadError::TooLarge { size, max } =>
+                write!(f, "ROM is {} bytes long, but only {} bytes fit in memory",
+                       size, max),
+            LoadError::Empty =>
+                write!(f, "ROM is empty"),
+        }
+    }
+}
+
+impl Error for LoadError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            LoadError::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for LoadError {
+    fn from(e : io::Error) -> LoadError {
+        LoadError::Io(e)
+    }
+}
+
+// Raised while executing a ROM. Every fault carries the address of
+// the instruction that caused it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fault {
+    UnknownOpcode { opcode : u16, addr : u16 },
+    // Memory past the end was addressed with MemoryPolicy::Fault.
+    BadAddress { target : usize, addr : u16 },
+    StackOverflow { addr : u16 },       // CALL with a full stack
+    StackUnderflow { addr : u16 },      // RET with an empty stack
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Fault::UnknownOpcode { opcode, addr } =>
+                write!(f, "unknown opcode {:04X} at {:03X}", opcode, addr),
+            Fault::BadAddress { target, addr } =>
+                write!(f, "memory address {:X} out of range at {:03X}", target, addr),
+            Fault::StackOverflow { addr } =>
+                write!(f, "stack overflow at {:03X}", addr),
+            Fault::StackUnderflow { addr } =>
+                write!(f, "stack underflow at {:03X}", addr),
+        }
+    }
+}
+
+impl Error for Fault {}
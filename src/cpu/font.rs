@@ -0,0 +1,215 @@
+use std::io;
+use std::io::prelude::*;
+use std::fs::File;
+
+/*
+ * The interpreter keeps a sprite for each hexadecimal digit (0-F) in
+ * the reserved area below 0x200. Every glyph is 5 bytes long, which is
+ * what Fx29 relies on when it points I at a digit. Different machines
+ * shipped slightly different shapes, so a few of them are built in and
+ * any other 80 byte font can be loaded from a file.
+ *
+ * SUPER-CHIP adds a big font with 10 byte glyphs for Fx30, which is
+ * installed right after the small one. All built-in variants share the
+ * same big font; a font file may bring its own by appending 160 bytes.
+ */
+
+pub const FONT_ADDR : u16 = 0x000;      // Where the small font is installed
+pub const GLYPH_SIZE : u16 = 5;         // Bytes per small glyph
+pub const FONT_SIZE : usize = 16 * 5;
+pub const BIG_FONT_ADDR : u16 = 0x050;  // Where the SUPER-CHIP font is installed
+pub const BIG_GLYPH_SIZE : u16 = 10;    // Bytes per big glyph (8x10 pixels)
+pub const BIG_FONT_SIZE : usize = 16 * 10;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FontVariant {
+    Cowgod,         // The font from Cowgod's technical reference
+    CosmacVip,
+    Eti660,
+    Dream6800,
+    Schip,
+}
+
+impl FontVariant {
+    pub const ALL : [FontVariant; 5] = [
+        FontVariant::Cowgod,
+        FontVariant::CosmacVip,
+        FontVariant::Eti660,
+        FontVariant::Dream6800,
+        FontVariant::Schip];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            FontVariant::Cowgod => "cowgod",
+            FontVariant::CosmacVip => "vip",
+            FontVariant::Eti660 => "eti660",
+            FontVariant::Dream6800 => "dream6800",
+            FontVariant::Schip => "schip",
+        }
+    }
+
+    pub fn from_name(name : &str) -> Option<FontVariant> {
+        let name = name.to_lowercase();
+        FontVariant::ALL.iter()
+            .find(|v| v.name() == name)
+            .copied()
+    }
+}
+
+#[derive(Clone)]
+pub struct Font {
+    pub small : [u8; FONT_SIZE],
+    pub big : [u8; BIG_FONT_SIZE],
+}
+
+impl Font {
+    pub fn builtin(variant : FontVariant) -> Font {
+        let small = match variant {
+            FontVariant::Cowgod => COWGOD,
+            FontVariant::CosmacVip => COSMAC_VIP,
+            FontVariant::Eti660 => ETI_660,
+            FontVariant::Dream6800 => DREAM_6800,
+            FontVariant::Schip => SCHIP,
+        };
+        Font { small, big : SCHIP_BIG }
+    } // fn builtin
+
+    pub fn from_bytes(bytes : &[u8]) -> io::Result<Font> {
+        // Either just the small font, or the small font followed by
+        // the big one.
+
+        let mut font = Font::default();
+        if bytes.len() == FONT_SIZE {
+            font.small.copy_from_slice(bytes);
+        } else if bytes.len() == FONT_SIZE + BIG_FONT_SIZE {
+            font.small.copy_from_slice(&bytes[..FONT_SIZE]);
+            font.big.copy_from_slice(&bytes[FONT_SIZE..]);
+        } else {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                format!("a font must be {} or {} bytes long, got {}",
+                        FONT_SIZE, FONT_SIZE + BIG_FONT_SIZE, bytes.len())));
+        }
+        Ok(font)
+    } // fn from_bytes
+
+    pub fn from_file(path : &str) -> io::Result<Font> {
+        let mut bytes = Vec::new();
+        File::open(path)?.read_to_end(&mut bytes)?;
+        Font::from_bytes(&bytes)
+    }
+}
+
+impl Default for Font {
+    fn default() -> Font {
+        Font::builtin(FontVariant::Cowgod)
+    }
+}
+
+const COWGOD : [u8; FONT_SIZE] = [
+      0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
+      0x20, 0x60, 0x20, 0x20, 0x70, // 1
+      0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
+      0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
+      0x90, 0x90, 0xF0, 0x10, 0x10, // 4
+      0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
+      0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
+      0xF0, 0x10, 0x20, 0x40, 0x40, // 7
+      0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
+      0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
+      0xF0, 0x90, 0xF0, 0x90, 0x90, // A
+      0xE0, 0x90, 0xE0, 0x90, 0xE0, // B
+      0xF0, 0x80, 0x80, 0x80, 0xF0, // C
+      0xE0, 0x90, 0x90, 0x90, 0xE0, // D
+      0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
+      0xF0, 0x80, 0xF0, 0x80, 0x80]; // F
+
+const COSMAC_VIP : [u8; FONT_SIZE] = [
+      0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
+      0x60, 0x20, 0x20, 0x20, 0x70, // 1
+      0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
+      0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
+      0xA0, 0xA0, 0xF0, 0x20, 0x20, // 4
+      0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
+      0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
+      0xF0, 0x10, 0x10, 0x10, 0x10, // 7
+      0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
+      0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
+      0xF0, 0x90, 0xF0, 0x90, 0x90, // A
+      0xF0, 0x50, 0x70, 0x50, 0xF0, // B
+      0xF0, 0x80, 0x80, 0x80, 0xF0, // C
+      0xF0, 0x50, 0x50, 0x50, 0xF0, // D
+      0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
+      0xF0, 0x80, 0xF0, 0x80, 0x80]; // F
+
+const ETI_660 : [u8; FONT_SIZE] = [
+      0xE0, 0xA0, 0xA0, 0xA0, 0xE0, // 0
+      0x20, 0x20, 0x20, 0x20, 0x20, // 1
+      0xE0, 0x20, 0xE0, 0x80, 0xE0, // 2
+      0xE0, 0x20, 0xE0, 0x20, 0xE0, // 3
+      0xA0, 0xA0, 0xE0, 0x20, 0x20, // 4
+      0xE0, 0x80, 0xE0, 0x20, 0xE0, // 5
+      0xE0, 0x80, 0xE0, 0xA0, 0xE0, // 6
+      0xE0, 0x20, 0x20, 0x20, 0x20, // 7
+      0xE0, 0xA0, 0xE0, 0xA0, 0xE0, // 8
+      0xE0, 0xA0, 0xE0, 0x20, 0xE0, // 9
+      0xE0, 0xA0, 0xE0, 0xA0, 0xA0, // A
+      0xC0, 0xA0, 0xE0, 0xA0, 0xC0, // B
+      0xE0, 0x80, 0x80, 0x80, 0xE0, // C
+      0xC0, 0xA0, 0xA0, 0xA0, 0xC0, // D
+      0xE0, 0x80, 0xE0, 0x80, 0xE0, // E
+      0xE0, 0x80, 0xC0, 0x80, 0x80]; // F
+
+const DREAM_6800 : [u8; FONT_SIZE] = [
+      0xE0, 0xA0, 0xA0, 0xA0, 0xE0, // 0
+      0x40, 0x40, 0x40, 0x40, 0x40, // 1
+      0xE0, 0x20, 0xE0, 0x80, 0xE0, // 2
+      0xE0, 0x20, 0xE0, 0x20, 0xE0, // 3
+      0x80, 0xA0, 0xA0, 0xE0, 0x20, // 4
+      0xE0, 0x80, 0xE0, 0x20, 0xE0, // 5
+      0xE0, 0x80, 0xE0, 0xA0, 0xE0, // 6
+      0xE0, 0x20, 0x20, 0x20, 0x20, // 7
+      0xE0, 0xA0, 0xE0, 0xA0, 0xE0, // 8
+      0xE0, 0xA0, 0xE0, 0x20, 0xE0, // 9
+      0xE0, 0xA0, 0xE0, 0xA0, 0xA0, // A
+      0xC0, 0xA0, 0xE0, 0xA0, 0xC0, // B
+      0xE0, 0x80, 0x80, 0x80, 0xE0, // C
+      0xC0, 0xA0, 0xA0, 0xA0, 0xC0, // D
+      0xE0, 0x80, 0xE0, 0x80, 0xE0, // E
+      0xE0, 0x80, 0xC0, 0x80, 0x80]; // F
+
+const SCHIP : [u8; FONT_SIZE] = [
+      0x60, 0xA0, 0xA0, 0xA0, 0xC0, // 0
+      0x40, 0xC0, 0x40, 0x40, 0xE0, // 1
+      0xC0, 0x20, 0x40, 0x80, 0xE0, // 2
+      0xC0, 0x20, 0x40, 0x20, 0xC0, // 3
+      0x20, 0xA0, 0xE0, 0x20, 0x20, // 4
+      0xE0, 0x80, 0xC0, 0x20, 0xC0, // 5
+      0x40, 0x80, 0xC0, 0xA0, 0x40, // 6
+      0xE0, 0x20, 0x60, 0x40, 0x40, // 7
+      0x40, 0xA0, 0x40, 0xA0, 0x40, // 8
+      0x40, 0xA0, 0x60, 0x20, 0x40, // 9
+      0x40, 0xA0, 0xE0, 0xA0, 0xA0, // A
+      0xC0, 0xA0, 0xC0, 0xA0, 0xC0, // B
+      0x60, 0x80, 0x80, 0x80, 0x60, // C
+      0xC0, 0xA0, 0xA0, 0xA0, 0xC0, // D
+      0xE0, 0x80, 0xC0, 0x80, 0xE0, // E
+      0xE0, 0x80, 0xC0, 0x80, 0x80]; // F
+
+// SUPER-CHIP 1.1 only has big digits, A-F are the ones XO-CHIP uses.
+const SCHIP_BIG : [u8; BIG_FONT_SIZE] = [
+      0x3C, 0x7E, 0xE7, 0xC3, 0xC3, 0xC3, 0xC3, 0xE7, 0x7E, 0x3C, // 0
+      0x18, 0x38, 0x58, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x3C, // 1
+      0x3E, 0x7F, 0xC3, 0x06, 0x0C, 0x18, 0x30, 0x60, 0xFF, 0xFF, // 2
+      0x3C, 0x7E, 0xC3, 0x03, 0x0E, 0x0E, 0x03, 0xC3, 0x7E, 0x3C, // 3
+      0x06, 0x0E, 0x1E, 0x36, 0x66, 0xC6, 0xFF, 0xFF, 0x06, 0x06, // 4
+      0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFE, 0x03, 0xC3, 0x7E, 0x3C, // 5
+      0x3E, 0x7C, 0xC0, 0xC0, 0xFC, 0xFE, 0xC3, 0xC3, 0x7E, 0x3C, // 6
+      0xFF, 0xFF, 0x03, 0x06, 0x0C, 0x18, 0x30, 0x60, 0x60, 0x60, // 7
+      0x3C, 0x7E, 0xC3, 0xC3, 0x7E, 0x7E, 0xC3, 0xC3, 0x7E, 0x3C, // 8
+      0x3C, 0x7E, 0xC3, 0xC3, 0x7F, 0x3F, 0x03, 0x03, 0x3E, 0x7C, // 9
+      0x7E, 0xFF, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, // A
+      0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, // B
+      0x3C, 0xFF, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0xFF, 0x3C, // C
+      0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC, // D
+      0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // E
+      0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0]; // F
@@ -0,0 +1,277 @@
+/*
+ * Every CHIP-8 instruction is a 16 bit big endian word. The most
+ * significant nibble selects the instruction family, the remaining
+ * bits carry the operands in one of these layouts:
+ *
+ *   nnn - a 12 bit address          (0x0FFF)
+ *   x   - a register index          (0x0F00)
+ *   y   - a register index          (0x00F0)
+ *   kk  - an 8 bit immediate byte   (0x00FF)
+ *   n   - a 4 bit immediate nibble  (0x000F)
+ *
+ * The one exception is the XO-CHIP F000 nnnn, whose 16 bit address is
+ * the word after the opcode and is read when the instruction runs.
+ *
+ * decode pulls the operands out once, so the handlers, a disassembler
+ * or a debugger never have to mask the raw opcode again.
+ *
+ * Instructions of every supported extension are decoded, whether they
+ * may run is up to the Mode of the Cpu (see Instruction::mode). decode_in
+ * gives the instruction a Mode actually runs, and so its real size.
+ */
+
+use std::fmt;
+use cpu::cpu::Mode;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Instruction {
+    Sys(u16),               // 0nnn - SYS addr (machine code, not supported)
+    ScrollDown(u8),         // 00Cn - SCD nibble       (SUPER-CHIP)
+    ScrollUp(u8),           // 00Dn - SCU nibble       (XO-CHIP)
+    Cls,                    // 00E0 - CLS
+    Ret,                    // 00EE - RET
+    ScrollRight,            // 00FB - SCR              (SUPER-CHIP)
+    ScrollLeft,             // 00FC - SCL              (SUPER-CHIP)
+    Exit,                   // 00FD - EXIT             (SUPER-CHIP)
+    Low,                    // 00FE - LOW              (SUPER-CHIP)
+    High,                   // 00FF - HIGH             (SUPER-CHIP)
+    Jp(u16),                // 1nnn - JP addr
+    Call(u16),              // 2nnn - CALL addr
+    SeByte(u8, u8),         // 3xkk - SE Vx, byte
+    SneByte(u8, u8),        // 4xkk - SNE Vx, byte
+    SeReg(u8, u8),          // 5xy0 - SE Vx, Vy
+    SaveRange(u8, u8),      // 5xy2 - SAVE Vx - Vy     (XO-CHIP)
+    LoadRange(u8, u8),      // 5xy3 - LOAD Vx - Vy     (XO-CHIP)
+    LdByte(u8, u8),         // 6xkk - LD Vx, byte
+    AddByte(u8, u8),        // 7xkk - ADD Vx, byte
+    LdReg(u8, u8),          // 8xy0 - LD Vx, Vy
+    Or(u8, u8),             // 8xy1 - OR Vx, Vy
+    And(u8, u8),            // 8xy2 - AND Vx, Vy
+    Xor(u8, u8),            // 8xy3 - XOR Vx, Vy
+    AddReg(u8, u8),         // 8xy4 - ADD Vx, Vy
+    Sub(u8, u8),            // 8xy5 - SUB Vx, Vy
+    Shr(u8, u8),            // 8xy6 - SHR Vx {, Vy}
+    Subn(u8, u8),           // 8xy7 - SUBN Vx, Vy
+    Shl(u8, u8),            // 8xyE - SHL Vx {, Vy}
+    SneReg(u8, u8),         // 9xy0 - SNE Vx, Vy
+    LdI(u16),               // Annn - LD I, addr
+    JpV0(u16),              // Bnnn - JP V0, addr
+    Rnd(u8, u8),            // Cxkk - RND Vx, byte
+    Drw(u8, u8, u8),        // Dxyn - DRW Vx, Vy, nibble
+    Skp(u8),                // Ex9E - SKP Vx
+    Sknp(u8),               // ExA1 - SKNP Vx
+    LdILong,                // F000 nnnn - LD I, LONG  (XO-CHIP)
+    Plane(u8),              // Fn01 - PLANE n          (XO-CHIP)
+    Audio,                  // F002 - AUDIO            (XO-CHIP)
+    LdVxDt(u8),             // Fx07 - LD Vx, DT
+    LdVxK(u8),              // Fx0A - LD Vx, K
+    LdDtVx(u8),             // Fx15 - LD DT, Vx
+    LdStVx(u8),             // Fx18 - LD ST, Vx
+    AddI(u8),               // Fx1E - ADD I, Vx
+    LdF(u8),                // Fx29 - LD F, Vx
+    LdHf(u8),               // Fx30 - LD HF, Vx        (SUPER-CHIP)
+    LdB(u8),                // Fx33 - LD B, Vx
+    LdPitch(u8),            // Fx3A - LD PITCH, Vx     (XO-CHIP)
+    LdIVx(u8),              // Fx55 - LD [I], Vx
+    LdVxI(u8),              // Fx65 - LD Vx, [I]
+    LdRVx(u8),              // Fx75 - LD R, Vx         (SUPER-CHIP)
+    LdVxR(u8),              // Fx85 - LD Vx, R         (SUPER-CHIP)
+    Unknown(u16),           // Anything else, carries the raw opcode
+}
+
+pub fn decode(opcode : u16) -> Instruction {
+    use self::Instruction::*;
+
+    let nnn : u16 = opcode & 0x0FFF;
+    let x : u8 = ((opcode & 0x0F00) >> 8) as u8;
+    let y : u8 = ((opcode & 0x00F0) >> 4) as u8;
+    let kk : u8 = (opcode & 0x00FF) as u8;
+    let n : u8 = (opcode & 0x000F) as u8;
+
+    match (opcode & 0xF000) >> 12 {
+        0x0 => match opcode {
+            0x00E0 => Cls,
+            0x00EE => Ret,
+            0x00FB => ScrollRight,
+            0x00FC => ScrollLeft,
+            0x00FD => Exit,
+            0x00FE => Low,
+            0x00FF => High,
+            _ if opcode & 0xFFF0 == 0x00C0 => ScrollDown(n),
+            _ if opcode & 0xFFF0 == 0x00D0 => ScrollUp(n),
+            _ => Sys(nnn),
+        },
+        0x1 => Jp(nnn),
+        0x2 => Call(nnn),
+        0x3 => SeByte(x, kk),
+        0x4 => SneByte(x, kk),
+        0x5 => match n {
+            0x0 => SeReg(x, y),
+            0x2 => SaveRange(x, y),
+            0x3 => LoadRange(x, y),
+            _ => Unknown(opcode),
+        },
+        0x6 => LdByte(x, kk),
+        0x7 => AddByte(x, kk),
+        0x8 => match n {
+            0x0 => LdReg(x, y),
+            0x1 => Or(x, y),
+            0x2 => And(x, y),
+            0x3 => Xor(x, y),
+            0x4 => AddReg(x, y),
+            0x5 => Sub(x, y),
+            0x6 => Shr(x, y),
+            0x7 => Subn(x, y),
+            0xE => Shl(x, y),
+            _ => Unknown(opcode),
+        },
+        0x9 if n == 0 => SneReg(x, y),
+        0xA => LdI(nnn),
+        0xB => JpV0(nnn),
+        0xC => Rnd(x, kk),
+        0xD => Drw(x, y, n),
+        0xE => match kk {
+            0x9E => Skp(x),
+            0xA1 => Sknp(x),
+            _ => Unknown(opcode),
+        },
+        0xF => match kk {
+            0x00 if x == 0 => LdILong,
+            0x01 => Plane(x),
+            0x02 if x == 0 => Audio,
+            0x07 => LdVxDt(x),
+            0x0A => LdVxK(x),
+            0x15 => LdDtVx(x),
+            0x18 => LdStVx(x),
+            0x1E => AddI(x),
+            0x29 => LdF(x),
+            0x30 => LdHf(x),
+            0x33 => LdB(x),
+            0x3A => LdPitch(x),
+            0x55 => LdIVx(x),
+            0x65 => LdVxI(x),
+            0x75 => LdRVx(x),
+            0x85 => LdVxR(x),
+            _ => Unknown(opcode),
+        },
+        _ => Unknown(opcode),
+    }
+} // fn decode
+
+// The instruction as an interpreter of the given Mode runs it. What only
+// a later instruction set knows is Unknown, e.g. F000 is two bytes of
+// nothing before XO-CHIP.
+pub fn decode_in(opcode : u16, mode : Mode) -> Instruction {
+    let instruction = decode(opcode).in_mode(mode);
+    if instruction.mode() > mode {
+        Instruction::Unknown(opcode)
+    } else {
+        instruction
+    }
+}
+
+impl Instruction {
+    // The first instruction set that contains the instruction.
+    pub fn mode(self) -> Mode {
+        use self::Instruction::*;
+
+        match self {
+            ScrollDown(_) | ScrollRight | ScrollLeft | Exit | Low | High |
+            LdHf(_) | LdRVx(_) | LdVxR(_) => Mode::SuperChip,
+            ScrollUp(_) | SaveRange(..) | LoadRange(..) | LdILong | Plane(_) |
+            Audio | LdPitch(_) => Mode::XoChip,
+            _ => Mode::Chip8,
+        }
+    }
+
+    // The instruction as the given instruction set sees it. Before
+    // XO-CHIP the last nibble of 5xyN and 9xyN is not looked at, so
+    // 5xy2 is still SE Vx, Vy and 9xy1 SNE Vx, Vy.
+    pub fn in_mode(self, mode : Mode) -> Instruction {
+        use self::Instruction::*;
+
+        if mode >= Mode::XoChip {
+            return self;
+        }
+        match self {
+            SaveRange(x, y) | LoadRange(x, y) => SeReg(x, y),
+            Unknown(opcode) if opcode & 0xF000 == 0x5000 || opcode & 0xF000 == 0x9000 => {
+                let (x, y) = ((opcode >> 8) as u8 & 0xF, (opcode >> 4) as u8 & 0xF);
+                if opcode & 0xF000 == 0x5000 { SeReg(x, y) } else { SneReg(x, y) }
+            },
+            other => other,
+        }
+    }
+
+    // Length in bytes, only F000 nnnn is longer than one word.
+    pub fn size(self) -> u16 {
+        match self {
+            Instruction::LdILong => 4,
+            _ => 2,
+        }
+    }
+}
+
+// Assembly in the style of Cowgod's reference. Addresses and bytes are
+// hex with a leading '#', nibbles are decimal. Opcodes that are not
+// instructions become a DW.
+impl fmt::Display for Instruction {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        use self::Instruction::*;
+
+        match *self {
+            Sys(nnn) => write!(f, "SYS #{:03X}", nnn),
+            ScrollDown(n) => write!(f, "SCD {}", n),
+            ScrollUp(n) => write!(f, "SCU {}", n),
+            Cls => write!(f, "CLS"),
+            Ret => write!(f, "RET"),
+            ScrollRight => write!(f, "SCR"),
+            ScrollLeft => write!(f, "SCL"),
+            Exit => write!(f, "EXIT"),
+            Low => write!(f, "LOW"),
+            High => write!(f, "HIGH"),
+            Jp(nnn) => write!(f, "JP #{:03X}", nnn),
+            Call(nnn) => write!(f, "CALL #{:03X}", nnn),
+            SeByte(x, kk) => write!(f, "SE V{:X}, #{:02X}", x, kk),
+            SneByte(x, kk) => write!(f, "SNE V{:X}, #{:02X}", x, kk),
+            SeReg(x, y) => write!(f, "SE V{:X}, V{:X}", x, y),
+            SaveRange(x, y) => write!(f, "SAVE V{:X} - V{:X}", x, y),
+            LoadRange(x, y) => write!(f, "LOAD V{:X} - V{:X}", x, y),
+            LdByte(x, kk) => write!(f, "LD V{:X}, #{:02X}", x, kk),
+            AddByte(x, kk) => write!(f, "ADD V{:X}, #{:02X}", x, kk),
+            LdReg(x, y) => write!(f, "LD V{:X}, V{:X}", x, y),
+            Or(x, y) => write!(f, "OR V{:X}, V{:X}", x, y),
+            And(x, y) => write!(f, "AND V{:X}, V{:X}", x, y),
+            Xor(x, y) => write!(f, "XOR V{:X}, V{:X}", x, y),
+            AddReg(x, y) => write!(f, "ADD V{:X}, V{:X}", x, y),
+            Sub(x, y) => write!(f, "SUB V{:X}, V{:X}", x, y),
+            Shr(x, y) => write!(f, "SHR V{:X}, V{:X}", x, y),
+            Subn(x, y) => write!(f, "SUBN V{:X}, V{:X}", x, y),
+            Shl(x, y) => write!(f, "SHL V{:X}, V{:X}", x, y),
+            SneReg(x, y) => write!(f, "SNE V{:X}, V{:X}", x, y),
+            LdI(nnn) => write!(f, "LD I, #{:03X}", nnn),
+            JpV0(nnn) => write!(f, "JP V0, #{:03X}", nnn),
+            Rnd(x, kk) => write!(f, "RND V{:X}, #{:02X}", x, kk),
+            Drw(x, y, n) => write!(f, "DRW V{:X}, V{:X}, {}", x, y, n),
+            Skp(x) => write!(f, "SKP V{:X}", x),
+            Sknp(x) => write!(f, "SKNP V{:X}", x),
+            LdILong => write!(f, "LD I, LONG"),
+            Plane(n) => write!(f, "PLANE {}", n),
+            Audio => write!(f, "AUDIO"),
+            LdVxDt(x) => write!(f, "LD V{:X}, DT", x),
+            LdVxK(x) => write!(f, "LD V{:X}, K", x),
+            LdDtVx(x) => write!(f, "LD DT, V{:X}", x),
+            LdStVx(x) => write!(f, "LD ST, V{:X}", x),
+            AddI(x) => write!(f, "ADD I, V{:X}", x),
+            LdF(x) => write!(f, "LD F, V{:X}", x),
+            LdHf(x) => write!(f, "LD HF, V{:X}", x),
+            LdB(x) => write!(f, "LD B, V{:X}", x),
+            LdPitch(x) => write!(f, "LD PITCH, V{:X}", x),
+            LdIVx(x) => write!(f, "LD [I], V{:X}", x),
+            LdVxI(x) => write!(f, "LD V{:X}, [I]", x),
+            LdRVx(x) => write!(f, "LD R, V{:X}", x),
+            LdVxR(x) => write!(f, "LD V{:X}, R", x),
+            Unknown(opcode) => write!(f, "DW #{:04X}", opcode),
+        }
+    } // fn fmt
+}
@@ -1,7 +1,16 @@
 mod opcode;
+pub mod font;
+pub mod error;
+pub mod instruction;
+pub mod scheduler;
+pub mod quirks;
+#[allow(clippy::module_inception)]
 pub mod cpu;
-pub use self::cpu::Cpu;
-extern crate byteorder;
-extern crate colored;
-extern crate rand;
-extern crate time;
+pub use self::cpu::{Access, AccessKind, Cpu, MemoryPolicy, Mode, OpcodeHook, OpcodePolicy};
+pub use self::cpu::{DISPLAY_WIDTH, DISPLAY_HEIGHT, HIRES_WIDTH, HIRES_HEIGHT, PROGRAM_START};
+pub use self::cpu::{MEMORY_SIZE, STACK_SIZE, XO_MEMORY_SIZE};
+pub use self::error::{Fault, LoadError};
+pub use self::instruction::{decode, decode_in, Instruction};
+pub use self::font::{Font, FontVariant};
+pub use self::quirks::{IndexIncrement, QuirkPreset, Quirks};
+pub use self::scheduler::{Scheduler, Speed, TIMER_HZ};
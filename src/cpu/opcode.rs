@@ -1,10 +1,14 @@
 pub use cpu::Cpu;
-use super::byteorder::{ByteOrder, BigEndian};
-use super::colored::*;
-use super::rand::Rng;
+use cpu::instruction::{decode, Instruction};
+use cpu::error::Fault;
+use byteorder::{ByteOrder, BigEndian};
+use rand::Rng;
+use cpu::font::{FONT_ADDR, GLYPH_SIZE, BIG_FONT_ADDR, BIG_GLYPH_SIZE};
+use cpu::cpu::{MemoryPolicy, Mode, STACK_SIZE};
+use cpu::quirks::IndexIncrement;
 
 #[allow(unused)]
-pub fn fetch(cpu : &mut Cpu) {
+pub fn fetch(cpu : &mut Cpu) -> Result<(), Fault> {
 
 /*
  * During this step, the system will fetch one opcode from the
@@ -14,15 +18,15 @@ pub fn fetch(cpu : &mut Cpu) {
  * we will need to fetch two successive bytes and merge them to
  * get the actual opcode.
  */
-    let buffer = [cpu.memory[cpu.pc as usize],
-                  cpu.memory[(cpu.pc+1) as usize]];
+    let pc = cpu.pc;
+    let buffer = [cpu.fetch_byte(pc as usize, pc)?,
+                  cpu.fetch_byte(pc as usize + 1, pc)?];
 
     cpu.opcode = BigEndian::read_u16(&buffer);
-
+    Ok(())
 }
 
-pub fn execute(cpu : &mut Cpu) {
-// TODO: implement
+pub fn execute(cpu : &mut Cpu) -> Result<(), Fault> {
 
 /*
  * Now that we know what to do with the opcode, we can execute
@@ -34,402 +38,385 @@ pub fn execute(cpu : &mut Cpu) {
  * should be skipped, increase the program counter by four.
  */
 
-     cpu.pc += 2;
-     if cpu.opcode & 0xFFFF == 0x00e0 { _00e0(cpu); return; }
-     if cpu.opcode & 0xFFFF == 0x00ee { _00ee(cpu); return; }
-     if cpu.opcode & 0xF000 == 0x1000 { _1nnn(cpu); return; }
-     if cpu.opcode & 0xF000 == 0x2000 { _2nnn(cpu); return; }
-     if cpu.opcode & 0xF000 == 0x3000 { _3xkk(cpu); return; }
-     if cpu.opcode & 0xF000 == 0x4000 { _4xkk(cpu); return; }
-     if cpu.opcode & 0xF000 == 0x5000 { _5xy0(cpu); return; }
-     if cpu.opcode & 0xF000 == 0x6000 { _6xkk(cpu); return; }
-     if cpu.opcode & 0xF000 == 0x7000 { _7xkk(cpu); return; }
-     if cpu.opcode & 0xF00F == 0x8000 { _8xy0(cpu); return; }
-     if cpu.opcode & 0xF00F == 0x8001 { _8xy1(cpu); return; }
-     if cpu.opcode & 0xF00F == 0x8002 { _8xy2(cpu); return; }
-     if cpu.opcode & 0xF00F == 0x8003 { _8xy3(cpu); return; }
-     if cpu.opcode & 0xF00F == 0x8004 { _8xy4(cpu); return; }
-     if cpu.opcode & 0xF00F == 0x8005 { _8xy5(cpu); return; }
-     if cpu.opcode & 0xF00F == 0x8006 { _8xy6(cpu); return; }
-     if cpu.opcode & 0xF00F == 0x8007 { _8xy7(cpu); return; }
-     if cpu.opcode & 0xF00F == 0x800e { _8xye(cpu); return; }
-     if cpu.opcode & 0xF000 == 0x9000 { _9xy0(cpu); return; }
-     if cpu.opcode & 0xF000 == 0xa000 { _annn(cpu); return; }
-     if cpu.opcode & 0xF000 == 0xb000 { _bnnn(cpu); return; }
-     if cpu.opcode & 0xF000 == 0xc000 { _cxkk(cpu); return; }
-     if cpu.opcode & 0xF000 == 0xd000 { _dxyn(cpu); return; }
-     if cpu.opcode & 0xF0FF == 0xe09e { _ex9e(cpu); return; }
-     if cpu.opcode & 0xF0FF == 0xe0a1 { _exa1(cpu); return; }
-     if cpu.opcode & 0xF0FF == 0xf007 { _fx07(cpu); return; }
-     if cpu.opcode & 0xF0FF == 0xf00a { _fx0a(cpu); return; }
-     if cpu.opcode & 0xF0FF == 0xf015 { _fx15(cpu); return; }
-     if cpu.opcode & 0xF0FF == 0xf01e { _fx1e(cpu); return; }
-     if cpu.opcode & 0xF0FF == 0xf033 { _fx33(cpu); return; }
-     if cpu.opcode & 0xF0FF == 0xf055 { _fx55(cpu); return; }
-     if cpu.opcode & 0xF0FF == 0xf065 { _fx65(cpu); return; }
-
-     // TODO:
-     // fx18, fx29
+     let start = cpu.pc;
+     cpu.pc = cpu.pc.wrapping_add(2);
+
+     // A faulting instruction leaves pc at its own address.
+     let result = decode(cpu.opcode).execute(cpu);
+     if result.is_err() {
+         cpu.pc = start;
+     }
+     result
+}
+
+impl Instruction {
+    pub fn execute(self, cpu : &mut Cpu) -> Result<(), Fault> {
+        // Run the handler of the instruction. The program counter must
+        // already point at the next instruction.
+
+        use self::Instruction::*;
+
+        let instruction = self.in_mode(cpu.mode);
+        if instruction.mode() > cpu.mode {
+            return cpu.unknown_opcode();
+        }
+
+        match instruction {
+            Sys(_) | Unknown(_) => return cpu.unknown_opcode(),
+            ScrollDown(n) => _00cn(cpu, n),
+            ScrollUp(n) => _00dn(cpu, n),
+            Cls => _00e0(cpu),
+            Ret => _00ee(cpu)?,
+            ScrollRight => _00fb(cpu),
+            ScrollLeft => _00fc(cpu),
+            Exit => _00fd(cpu),
+            Low => _00fe(cpu),
+            High => _00ff(cpu),
+            Jp(nnn) => _1nnn(cpu, nnn),
+            Call(nnn) => _2nnn(cpu, nnn)?,
+            SeByte(x, kk) => _3xkk(cpu, x, kk),
+            SneByte(x, kk) => _4xkk(cpu, x, kk),
+            SeReg(x, y) => _5xy0(cpu, x, y),
+            SaveRange(x, y) => _5xy2(cpu, x, y)?,
+            LoadRange(x, y) => _5xy3(cpu, x, y)?,
+            LdByte(x, kk) => _6xkk(cpu, x, kk),
+            AddByte(x, kk) => _7xkk(cpu, x, kk),
+            LdReg(x, y) => _8xy0(cpu, x, y),
+            Or(x, y) => _8xy1(cpu, x, y),
+            And(x, y) => _8xy2(cpu, x, y),
+            Xor(x, y) => _8xy3(cpu, x, y),
+            AddReg(x, y) => _8xy4(cpu, x, y),
+            Sub(x, y) => _8xy5(cpu, x, y),
+            Shr(x, y) => _8xy6(cpu, x, y),
+            Subn(x, y) => _8xy7(cpu, x, y),
+            Shl(x, y) => _8xye(cpu, x, y),
+            SneReg(x, y) => _9xy0(cpu, x, y),
+            LdI(nnn) => _annn(cpu, nnn),
+            JpV0(nnn) => _bnnn(cpu, nnn),
+            Rnd(x, kk) => _cxkk(cpu, x, kk),
+            Drw(x, y, n) => _dxyn(cpu, x, y, n)?,
+            Skp(x) => _ex9e(cpu, x),
+            Sknp(x) => _exa1(cpu, x),
+            LdILong => _f000(cpu)?,
+            Plane(n) => _fn01(cpu, n),
+            Audio => _f002(cpu)?,
+            LdVxDt(x) => _fx07(cpu, x),
+            LdVxK(x) => _fx0a(cpu, x),
+            LdDtVx(x) => _fx15(cpu, x),
+            LdStVx(x) => _fx18(cpu, x),
+            AddI(x) => _fx1e(cpu, x),
+            LdF(x) => _fx29(cpu, x),
+            LdHf(x) => _fx30(cpu, x),
+            LdB(x) => _fx33(cpu, x)?,
+            LdPitch(x) => _fx3a(cpu, x),
+            LdIVx(x) => _fx55(cpu, x)?,
+            LdVxI(x) => _fx65(cpu, x)?,
+            LdRVx(x) => _fx75(cpu, x),
+            LdVxR(x) => _fx85(cpu, x),
+        }
+        Ok(())
+    } // fn execute
+}
+
+fn _00cn(cpu : &mut Cpu, n : u8) {
+    // Scroll the display down by n pixels.            (SUPER-CHIP)
+    // The rows that scroll in at the top are blank.
+
+    scroll(cpu, 0, n as isize);
+}
+
+fn _00dn(cpu : &mut Cpu, n : u8) {
+    // Scroll the display up by n pixels.              (XO-CHIP)
+
+    scroll(cpu, 0, -(n as isize));
 }
 
 fn _00e0(cpu : &mut Cpu) {
     // CLS - Clear the display.
+    // With XO-CHIP only the selected bitplanes are cleared.
 
-    println!("{}",
-    "CLS -------------------------00e0--".black().on_white());
     for i in 0..cpu.v_memory.len() {
-        cpu.v_memory[i] = 0;
+        cpu.v_memory[i] &= !cpu.planes;
     }
 }
 
-fn _00ee(cpu : &mut Cpu) {
+fn _00ee(cpu : &mut Cpu) -> Result<(), Fault> {
     // Return from a subroutine.
     // The interpreter sets the program counter to the address at
     // the top of the stack, then subtracts 1 from the stack pointer.
+    // Returning with an empty stack is a fault.
 
-    println!("{}",
-    "RET -------------------------00ee--".black().on_white().bold());
-
-    if cpu.stack.len() > 0 && cpu.sp > 0 {
-        cpu.pc = cpu.stack.pop().unwrap();
-        cpu.sp -= 1;
+    if cpu.sp == 0 {
+        return Err(Fault::StackUnderflow { addr : cpu.pc.wrapping_sub(2) });
     }
+    cpu.sp -= 1;
+    cpu.pc = cpu.stack[cpu.sp as usize];
+    Ok(())
+}
+
+fn _00fb(cpu : &mut Cpu) {
+    // Scroll the display right by 4 pixels.           (SUPER-CHIP)
+
+    scroll(cpu, 4, 0);
+}
+
+fn _00fc(cpu : &mut Cpu) {
+    // Scroll the display left by 4 pixels.            (SUPER-CHIP)
+
+    scroll(cpu, -4, 0);
+}
+
+fn _00fd(cpu : &mut Cpu) {
+    // Exit the interpreter.                           (SUPER-CHIP)
+    // The program stops, the display keeps its last picture.
+
+    cpu.exited = true;
+    cpu.pc = cpu.pc.wrapping_sub(2);
+}
+
+fn _00fe(cpu : &mut Cpu) {
+    // Switch to the 64x32 low resolution mode.        (SUPER-CHIP)
+
+    cpu.set_hires(false);
+}
+
+fn _00ff(cpu : &mut Cpu) {
+    // Switch to the 128x64 high resolution mode.      (SUPER-CHIP)
+
+    cpu.set_hires(true);
 }
 
-fn _1nnn(cpu : &mut Cpu) {
+fn _1nnn(cpu : &mut Cpu, nnn : u16) {
     // Jump to location nnn.
     // The interpreter sets the program counter to nnn.
 
-    println!("{}",
-    "JP ADDR ---------------------1nnn--".black().on_red().bold());
-
-    let nnn : u16 = cpu.opcode & 0x0FFF;
-    //println!("Opcode: {:x}, NNN: {:x}, PC: {:x}", cpu.opcode, nnn, cpu.pc);
     cpu.pc = nnn;
 }
 
-fn _2nnn(cpu : &mut Cpu) {
+fn _2nnn(cpu : &mut Cpu, nnn : u16) -> Result<(), Fault> {
     // Call subroutine at nnn.
-    // The interpreter increments the stack pointer, then puts the
-    // current PC on the top of the stack. The PC is then set to nnn.
-
-    println!("{}",
-    "CALL ADDR -------------------2nnn--".black().on_red().underline());
+    // The interpreter puts the current PC on the top of the stack, then
+    // increments the stack pointer. The PC is then set to nnn.
+    // Calling with a full stack (see Quirks::stack_depth) is a fault.
 
-    let nnn : u16 = cpu.opcode & 0x0FFF;
+    let depth = cpu.quirks.stack_depth.min(STACK_SIZE);
+    if cpu.sp as usize >= depth {
+        return Err(Fault::StackOverflow { addr : cpu.pc.wrapping_sub(2) });
+    }
+    cpu.stack[cpu.sp as usize] = cpu.pc;
     cpu.sp += 1;
-    cpu.stack.push(cpu.pc);
     cpu.pc = nnn;
-
+    Ok(())
 }
 
-fn _3xkk(cpu : &mut Cpu) {
+fn _3xkk(cpu : &mut Cpu, x : u8, kk : u8) {
     // Skip next instruction if Vx = kk.
     // The interpreter compares register Vx to kk, and if they are
     // equal, increments the program counter by 2.
 
-    println!("{}",
-    "SE Vx, BYTE -----------------3xkk--".red().on_blue().bold());
-    let x : u8 = ((cpu.opcode & 0x0F00) >> 8) as u8;
-    let kk : u8 = (cpu.opcode & 0x00FF) as u8;
-
-    //println!("Opcode: {:x} x: {:x} kk: {:x}", cpu.opcode, x, kk);
-
     if cpu.v_regs[x as usize] == kk {
-        cpu.pc += 2;
+        skip(cpu);
     }
 }
 
-fn _4xkk(cpu : &mut Cpu) {
+fn _4xkk(cpu : &mut Cpu, x : u8, kk : u8) {
     // Skip next instruction if Vx != kk.
     // The interpreter compares register Vx to kk, and if they are
     // not equal, increments the program counter by 2.
 
-    println!("{}",
-    "SNE Vx, BYTE ----------------4xkk--".red().on_blue());
-    let x : u8 = ((cpu.opcode & 0x0F00) >> 8) as u8;
-    let kk : u8 = (cpu.opcode & 0x00FF) as u8;
-
-    //println!("Opcode: {:x} x: {:x} kk: {:x}", cpu.opcode, x, kk);
-
     if cpu.v_regs[x as usize] != kk {
-        cpu.pc += 2;
+        skip(cpu);
     }
 }
 
-fn _5xy0(cpu : &mut Cpu) {
+fn _5xy0(cpu : &mut Cpu, x : u8, y : u8) {
     // Skip next instruction if Vx = Vy.
     // The interpreter compares register Vx to register Vy, and if
     // they are equal, increments the program counter by 2.
 
-    println!("{}",
-    "SE Vx, Vy -------------------5xy0--".red().on_blue().underline());
-    let x : u8 = ((cpu.opcode & 0x0F00) >> 8) as u8;
-    let y : u8 = ((cpu.opcode & 0x00F0) >> 4) as u8;
+    if cpu.v_regs[x as usize] == cpu.v_regs[y as usize] {
+        skip(cpu);
+    }
+}
 
-    //println!("Opcode: {:x} x: {:x} y: {:x}", cpu.opcode, x, y);
+fn _5xy2(cpu : &mut Cpu, x : u8, y : u8) -> Result<(), Fault> {
+    // Store registers Vx through Vy in memory starting at I. (XO-CHIP)
+    // The registers may be given in descending order, I is left alone.
 
-    if cpu.v_regs[x as usize] == cpu.v_regs[y as usize] {
-        cpu.pc += 2;
+    for (offset, reg) in range(x, y).into_iter().enumerate() {
+        let value = cpu.v_regs[reg];
+        cpu.write_byte(cpu.i_reg as usize + offset, value)?;
     }
+    Ok(())
 }
 
-fn _6xkk(cpu : &mut Cpu) {
-    // Set Vx = kk.
-    // The interpreter puts the value kk into register Vx.
+fn _5xy3(cpu : &mut Cpu, x : u8, y : u8) -> Result<(), Fault> {
+    // Read registers Vx through Vy from memory starting at I. (XO-CHIP)
 
-    println!("{}",
-    "LD Vx, BYTE -----------------6xkk--".white().on_cyan());
-    let x : u8 = ((cpu.opcode & 0x0F00) >> 8) as u8;
-    let kk : u8 = (cpu.opcode & 0x00FF) as u8;
+    for (offset, reg) in range(x, y).into_iter().enumerate() {
+        cpu.v_regs[reg] = cpu.read_byte(cpu.i_reg as usize + offset)?;
+    }
+    Ok(())
+}
 
-    //println!("Opcode: {:x} x: {:x} kk: {:x}", cpu.opcode, x, kk);
+fn _6xkk(cpu : &mut Cpu, x : u8, kk : u8) {
+    // Set Vx = kk.
+    // The interpreter puts the value kk into register Vx.
 
     cpu.v_regs[x as usize] = kk;
 }
 
-fn _7xkk(cpu : &mut Cpu) {
+fn _7xkk(cpu : &mut Cpu, x : u8, kk : u8) {
     // Set Vx = Vx + kk.
     // Adds the value kk to the value of register Vx, then
-    // stores the result in Vx.
-
-    println!("{}",
-    "ADD Vx, BYTE ----------------7xkk--".blue().on_green());
-    let x : u8 = ((cpu.opcode & 0x0F00) >> 8) as u8;
-    let kk : u8 = (cpu.opcode & 0x00FF) as u8;
+    // stores the result in Vx. VF is left alone.
 
-    //println!("Opcode: {:x} Vx: {:x} x: {:x} kk: {:x}", cpu.opcode, cpu.v_regs[x as usize], x, kk);
-    let mut value : u16 = cpu.v_regs[x as usize] as u16 + kk as u16;
-    value = value % 256;
-
-    cpu.v_regs[x as usize] = value as u8;
+    cpu.v_regs[x as usize] = cpu.v_regs[x as usize].wrapping_add(kk);
 }
 
-fn _8xy0(cpu : &mut Cpu) {
+fn _8xy0(cpu : &mut Cpu, x : u8, y : u8) {
     // Set Vx = Vy.
     // Stores the value of register Vy in register Vx.
 
-    println!("{}",
-    "LD Vx, Vy -------------------8xy0--".white().on_cyan().underline());
-    let x : u8 = ((cpu.opcode & 0x0F00) >> 8) as u8;
-    let y : u8 = ((cpu.opcode & 0x00F0) >> 4) as u8;
-
-    //println!("Opcode: {:x} x: {:x} y: {:x}", cpu.opcode, x, y);
-
     cpu.v_regs[x as usize] = cpu.v_regs[y as usize];
 }
 
-fn _8xy1(cpu : &mut Cpu) {
+fn _8xy1(cpu : &mut Cpu, x : u8, y : u8) {
     // Set Vx = Vx OR Vy.
     // Performs a bitwise OR on the values of Vx and Vy, then stores
     // the result in Vx. A bitwise OR compares the corrseponding bits
     // from two values, and if either bit is 1, then the same bit in the
     // result is also 1. Otherwise, it is 0.
 
-    println!("{}",
-    "OR Vx, Vy -------------------8xy1--".white().on_green());
-
-    let x : u8 = ((cpu.opcode & 0x0F00) >> 8) as u8;
-    let y : u8 = ((cpu.opcode & 0x00F0) >> 4) as u8;
-
-    //println!("Opcode: {:x} x: {:x} y: {:x}", cpu.opcode, x, y);
-
     cpu.v_regs[x as usize] |= cpu.v_regs[y as usize];
+    if cpu.quirks.vf_reset {
+        cpu.v_regs[15] = 0;
+    }
 }
 
-fn _8xy2(cpu : &mut Cpu) {
+fn _8xy2(cpu : &mut Cpu, x : u8, y : u8) {
     // Set Vx = Vx AND Vy.
     // Performs a bitwise AND on the values of Vx and Vy, then stores
     // the result in Vx.
 
-    println!("{}",
-    "AND Vx, Vy ------------------8xy2--".white().on_green().underline());
-
-    let x : u8 = ((cpu.opcode & 0x0F00) >> 8) as u8;
-    let y : u8 = ((cpu.opcode & 0x00F0) >> 4) as u8;
-
-    //println!("Opcode: {:x} x: {:x} y: {:x}", cpu.opcode, x, y);
-
     cpu.v_regs[x as usize] &= cpu.v_regs[y as usize];
+    if cpu.quirks.vf_reset {
+        cpu.v_regs[15] = 0;
+    }
 }
 
-fn _8xy3(cpu : &mut Cpu) {
+fn _8xy3(cpu : &mut Cpu, x : u8, y : u8) {
     // Set Vx = Vx XOR Vy.
     // Performs a bitwise XOR on the values of Vx and Vy, then stores
     // the result in Vx.
 
-    println!("{}",
-    "XOR Vx, Vy ------------------8xy3--".white().on_green().bold());
-
-    let x : u8 = ((cpu.opcode & 0x0F00) >> 8) as u8;
-    let y : u8 = ((cpu.opcode & 0x00F0) >> 4) as u8;
-
-    //println!("Opcode: {:x} x: {:x} y: {:x}", cpu.opcode, x, y);
-
     cpu.v_regs[x as usize] ^= cpu.v_regs[y as usize];
+    if cpu.quirks.vf_reset {
+        cpu.v_regs[15] = 0;
+    }
 }
 
-fn _8xy4(cpu : &mut Cpu) {
+fn _8xy4(cpu : &mut Cpu, x : u8, y : u8) {
     // Set Vx = Vx + Vy, set VF = carry.
     // The values of Vx and Vy are added together. If the result is
     // greater than 8 bits (i.e., > 255,) VF is set to 1, otherwise 0.
     // Only the lowest 8 bits of the result are kept, and stored in Vx.
+    // VF is written last, so with x = F it ends up holding the carry.
 
-    println!("{}",
-    "ADD Vx, Vy ------------------8xy4--".blue().on_green().bold());
-
-    let x : u8 = ((cpu.opcode & 0x0F00) >> 8) as u8;
-    let y : u8 = ((cpu.opcode & 0x00F0) >> 4) as u8;
-
-    //println!("Opcode: {:x} x: {:x} y: {:x}", cpu.opcode, x, y);
+    let (result, carry) = cpu.v_regs[x as usize].overflowing_add(cpu.v_regs[y as usize]);
 
-    let xy : u16 = cpu.v_regs[x as usize] as u16 +
-                    cpu.v_regs[y as usize] as u16 ;
-
-    if xy > 255 {
-        cpu.v_regs[15] = 1;
-        cpu.v_regs[x as usize] = xy as u8;
-    } else {
-        cpu.v_regs[15] = 0;
-        cpu.v_regs[x as usize] = xy as u8;
-    }
+    cpu.v_regs[x as usize] = result;
+    cpu.v_regs[15] = carry as u8;
 }
 
-fn _8xy5(cpu : &mut Cpu) {
+fn _8xy5(cpu : &mut Cpu, x : u8, y : u8) {
     // Set Vx = Vx - Vy, set VF = NOT borrow.
-    // If Vx > Vy, then VF is set to 1, otherwise 0. Then Vy is subtracted
-    // from Vx, and the results stored in Vx.
+    // If Vx >= Vy, then VF is set to 1, otherwise 0. Then Vy is
+    // subtracted from Vx (wrapping around on a borrow), and the result
+    // is stored in Vx. VF is written last.
 
-    println!("{}",
-    "SUB Vx, Vy ------------------8xy5--".white().on_green().bold());
+    let (result, borrow) = cpu.v_regs[x as usize].overflowing_sub(cpu.v_regs[y as usize]);
 
-    let x : u8 = ((cpu.opcode & 0x0F00) >> 8) as u8;
-    let y : u8 = ((cpu.opcode & 0x00F0) >> 4) as u8;
-
-    if cpu.v_regs[x as usize] > cpu.v_regs[y as usize] {
-        cpu.v_regs[15] = 1;
-        cpu.v_regs[x as usize] -= cpu.v_regs[y as usize];
-    } else {
-        cpu.v_regs[15] = 0;
-    }
+    cpu.v_regs[x as usize] = result;
+    cpu.v_regs[15] = !borrow as u8;
 }
 
-// TODO: ????
-fn _8xy6(cpu : &mut Cpu) {
+fn _8xy6(cpu : &mut Cpu, x : u8, y : u8) {
     // Set Vx = Vx SHR 1.
     // If the least-significant bit of Vx is 1, then VF is set to 1,
-    // otherwise 0. Then Vx is divided by 2.
-
-    println!("{}",
-    "SHR Vx ----------------------8xy6--".blue().on_green());
+    // otherwise 0. Then Vx is divided by 2. VF is written last.
+    // The COSMAC VIP shifts Vy instead and stores the result in Vx.
 
-    let x : u8 = ((cpu.opcode & 0x0F00) >> 8) as u8;
+    let source = if cpu.quirks.shift_vy { y } else { x };
+    let value = cpu.v_regs[source as usize];
 
-    if cpu.v_regs[x as usize] & 0b0000_0001 == 1 {
-        cpu.v_regs[15] = 1;
-        cpu.v_regs[x as usize] = cpu.v_regs[x as usize] >> 1;
-    } else {
-        cpu.v_regs[15] = 0;
-        cpu.v_regs[x as usize] = cpu.v_regs[x as usize] >> 1;
-    }
+    cpu.v_regs[x as usize] = value >> 1;
+    cpu.v_regs[15] = value & 0b0000_0001;
 }
 
-fn _8xy7(cpu : &mut Cpu) {
+fn _8xy7(cpu : &mut Cpu, x : u8, y : u8) {
     // Set Vx = Vy - Vx, set VF = NOT borrow.
-    // If Vy > Vx, then VF is set to 1, otherwise 0. Then Vx is subtracted
-    // from Vy, and the results stored in Vx.
-
-    println!("{}",
-    "SUBN Vx, Vy ------------------8xy7--".white().on_green().bold().underline());
+    // If Vy >= Vx, then VF is set to 1, otherwise 0. Then Vx is
+    // subtracted from Vy (wrapping around on a borrow), and the result
+    // is stored in Vx. VF is written last.
 
-    let x : u8 = ((cpu.opcode & 0x0F00) >> 8) as u8;
-    let y : u8 = ((cpu.opcode & 0x00F0) >> 4) as u8;
+    let (result, borrow) = cpu.v_regs[y as usize].overflowing_sub(cpu.v_regs[x as usize]);
 
-    if cpu.v_regs[y as usize] > cpu.v_regs[x as usize] {
-        cpu.v_regs[15] = 1;
-        cpu.v_regs[x as usize] = cpu.v_regs[y as usize] - cpu.v_regs[x as usize];
-    } else {
-        cpu.v_regs[15] = 0;
-    }
+    cpu.v_regs[x as usize] = result;
+    cpu.v_regs[15] = !borrow as u8;
 }
 
-// TODO:????
-fn _8xye(cpu : &mut Cpu) {
+fn _8xye(cpu : &mut Cpu, x : u8, y : u8) {
     // Set Vx = Vx SHL 1.
-    // If the least-significant bit of Vx is 1, then VF is set to 1,
-    // otherwise 0. Then Vx is divided by 2.
-
-    println!("{}",
-    "SHL Vx ----------------------8xye--".blue().on_green().bold());
+    // If the most-significant bit of Vx is 1, then VF is set to 1,
+    // otherwise 0. Then Vx is multiplied by 2. VF is written last.
+    // The COSMAC VIP shifts Vy instead and stores the result in Vx.
 
-    let x : u8 = ((cpu.opcode & 0x0F00) >> 8) as u8;
+    let source = if cpu.quirks.shift_vy { y } else { x };
+    let value = cpu.v_regs[source as usize];
 
-    if cpu.v_regs[x as usize] & 0b0000_0001 == 1 {
-        cpu.v_regs[15] = 1;
-        cpu.v_regs[x as usize] = cpu.v_regs[x as usize] << 1;
-    } else {
-        cpu.v_regs[15] = 0;
-        cpu.v_regs[x as usize] = cpu.v_regs[x as usize] << 1;
-    }
+    cpu.v_regs[x as usize] = value << 1;
+    cpu.v_regs[15] = value >> 7;
 }
 
-fn _9xy0(cpu : &mut Cpu) {
+fn _9xy0(cpu : &mut Cpu, x : u8, y : u8) {
     // Skip next instruction if Vx != Vy.
     // The values of Vx and Vy are compared, and if they are not equal,
     // the program counter is increased by 2.
 
-    println!("{}",
-    "SNE Vx, Vy ------------------9xy0--".blue().on_cyan());
-
-    let x : u8 = ((cpu.opcode & 0x0F00) >> 8) as u8;
-    let y : u8 = ((cpu.opcode & 0x00F0) >> 4) as u8;
-
     if cpu.v_regs[x as usize] != cpu.v_regs[y as usize] {
-        cpu.pc += 2;
+        skip(cpu);
     }
 }
 
-fn _annn(cpu : &mut Cpu) {
+fn _annn(cpu : &mut Cpu, nnn : u16) {
     // Set I = nnn.
     // The value of register I is set to nnn.
 
-    println!("{}",
-    "LD I, ADDR ------------------annn--".green().on_cyan());
-
-    let nnn : u16 = cpu.opcode & 0x0FFF;
-
     cpu.i_reg = nnn;
 }
 
-fn _bnnn(cpu : &mut Cpu) {
+fn _bnnn(cpu : &mut Cpu, nnn : u16) {
     // Jump to location nnn + V0.
     // The program counter is set to nnn plus the value of V0.
+    // CHIP-48 and SUPER-CHIP read it as Bxnn and add Vx instead.
 
-    println!("{}",
-    "JP V0, ADDR ------------------bnnn--".white().on_cyan());
-
-    let nnn : u16 = cpu.opcode & 0x0FFF;
-
-    cpu.pc = nnn + cpu.v_regs[0] as u16;
+    let reg = if cpu.quirks.jump_vx { (nnn >> 8) as usize } else { 0 };
+    cpu.pc = nnn + cpu.v_regs[reg] as u16;
 }
 
-fn _cxkk(cpu : &mut Cpu) {
+fn _cxkk(cpu : &mut Cpu, x : u8, kk : u8) {
     // Set Vx = random byte AND kk.
     // The interpreter generates a random number from 0 to 255,
     // which is then ANDed with the value kk. The results are
     // stored in Vx.
 
-    println!("{}",
-    "RND Vx, BYTE ----------------cxkk--".yellow().on_cyan().underline());
-
-    let x : u8 = ((cpu.opcode & 0x0F00) >> 8) as u8;
-    let kk : u8 = (cpu.opcode & 0x00FF) as u8;
-    let random : u8 = super::rand::thread_rng().gen_range(0,255);
+    let random : u8 = cpu.rng.gen();
 
     cpu.v_regs[x as usize] = random & kk;
 }
 
-fn _dxyn(cpu : &mut Cpu) {
+fn _dxyn(cpu : &mut Cpu, x : u8, y : u8, n : u8) -> Result<(), Fault> {
     // Display n-byte sprite starting at memory location I at
     // (Vx, Vy), set VF = collision.
     // The interpreter reads n bytes from memory, starting at the
@@ -438,82 +425,110 @@ fn _dxyn(cpu : &mut Cpu) {
     // existing screen. If this causes any pixels to be erased, VF is
     // set to 1, otherwise it is set to 0. If the sprite is positioned
     // so part of it is outside the coordinates of the display, it wraps
-    // around to the opposite side of the screen.
-
-    println!("{}",
-    "DRW Vx, Vy ------------------dxyn--".yellow().on_cyan().bold());
-
-    let x : u8 = ((cpu.opcode & 0x0F00) >> 8) as u8;
-    let y : u8 = ((cpu.opcode & 0x00F0) >> 4) as u8;
-    let n : u8 = (cpu.opcode & 0x000F) as u8;
-    let vx : u8 = cpu.v_regs[x as usize];
-    let vy : u8 = cpu.v_regs[y as usize];
-
-    //println!("x: {:x} y: {:x} n: {:x}", vx, vy, n);
+    // around to the opposite side of the screen, or is clipped with the
+    // clip_sprites quirk. The position itself always wraps.
+
+    let (width, height) = cpu.display_size();
+    let vx = cpu.v_regs[x as usize] as usize % width;
+    let vy = cpu.v_regs[y as usize] as usize % height;
+    let clip = cpu.quirks.clip_sprites;
+
+    // SUPER-CHIP draws a 16x16 sprite (two bytes per row) for n = 0,
+    // in low resolution it draws 8x16 like SCHIP 1.1. XO-CHIP always
+    // draws 16x16.
+    let (cols, rows) = match cpu.mode {
+        Mode::SuperChip if n == 0 && !cpu.hires => (8, 16),
+        Mode::SuperChip | Mode::XoChip if n == 0 => (16, 16),
+        _ => (8, n as usize),
+    };
+    let row_bytes = cols / 8;
+
+    // Every selected bitplane gets its own sprite, stored one after
+    // the other starting at I. Without XO-CHIP only plane 1 is drawn.
+    let mut addr = cpu.i_reg as usize;
+    let planes = cpu.planes;
 
     cpu.v_regs[15] = 0;
-    for i in 0..n {
-        for j in 0..8 {
-            if (cpu.memory[cpu.i_reg as usize + i as usize] & (0x80 >> j)) != 0 {
-                /*
-                println!("I: {} n: {} BYTE: {:b}",
-                    cpu.i_reg + i as u16,
-                    n,
-                    cpu.memory[cpu.i_reg as usize + i as usize]);
-                */
-
-                if cpu.v_memory[coord((vx as usize + j as usize),
-                (vy as usize + i as usize))] != 0 {
-                    cpu.v_regs[15] = 1;
+    for plane in (0..4).map(|p| 1 << p).filter(|p| planes & p != 0) {
+        for i in 0..rows {
+            for j in 0..cols {
+                let byte = cpu.read_byte(addr + i * row_bytes + j / 8)?;
+                let outside = vx + j >= width || vy + i >= height;
+                if (byte & (0x80 >> (j % 8))) != 0 && !(clip && outside) {
+                    let pixel = coord(vx + j, vy + i, width, height);
+                    if cpu.v_memory[pixel] & plane != 0 {
+                        cpu.v_regs[15] = 1;
+                    }
+                    cpu.v_memory[pixel] ^= plane;
                 }
-                cpu.v_memory[coord((vx as usize + j as usize),
-                (vy as usize + i as usize))] ^= 0xFFFFFFFF;
             }
         }
+        addr += rows * row_bytes;
     }
+    Ok(())
 }
 
-fn _ex9e(cpu : &mut Cpu) {
+fn _ex9e(cpu : &mut Cpu, x : u8) {
     // Skip next instruction if key with the value of Vx is pressed.
     // Checks the keyboard, and if the key corresponding to the value
     // of Vx is currently in the down position, PC is increased by 2.
 
-    let x : u8 = ((cpu.opcode & 0x0F00) >> 8) as u8;
-
-    if cpu.keypad[cpu.v_regs[x as usize] as usize] == 1 {
-        cpu.pc += 2;
+    if cpu.keypad[(cpu.v_regs[x as usize] & 0xF) as usize] == 1 {
+        skip(cpu);
     }
 }
 
-fn _exa1(cpu : &mut Cpu) {
+fn _exa1(cpu : &mut Cpu, x : u8) {
     // Skip next instruction if key with the value of Vx is not pressed.
     // Checks the keyboard, and if the key corresponding to the value
     // of Vx is currently in the up position, PC is increased by 2.
 
-    let x : u8 = ((cpu.opcode & 0x0F00) >> 8) as u8;
+    if cpu.keypad[(cpu.v_regs[x as usize] & 0xF) as usize] == 0 {
+        skip(cpu);
+    }
+}
+
+fn _f000(cpu : &mut Cpu) -> Result<(), Fault> {
+    // Set I = nnnn, the 16 bit word after the opcode.    (XO-CHIP)
 
-    if cpu.keypad[cpu.v_regs[x as usize] as usize] == 0 {
-        cpu.pc += 2;
+    let start = cpu.pc.wrapping_sub(2);
+    let hi = cpu.fetch_byte(cpu.pc as usize, start)? as u16;
+    let lo = cpu.fetch_byte(cpu.pc as usize + 1, start)? as u16;
+    cpu.i_reg = hi << 8 | lo;
+    cpu.pc = cpu.pc.wrapping_add(2);
+    Ok(())
+}
+
+fn _fn01(cpu : &mut Cpu, n : u8) {
+    // Select the bitplanes drawn to by CLS, DRW and the scrolls.
+    // (XO-CHIP) Bit 0 is the first plane, bit 1 the second and so on.
+
+    cpu.planes = n;
+}
+
+fn _f002(cpu : &mut Cpu) -> Result<(), Fault> {
+    // Load the 16 byte audio pattern from memory at I.   (XO-CHIP)
+
+    let mut pattern = [0; 16];
+    for (offset, byte) in pattern.iter_mut().enumerate() {
+        *byte = cpu.read_byte(cpu.i_reg as usize + offset)?;
     }
+    cpu.beeper().pattern = Some(pattern);
+    Ok(())
 }
 
-fn _fx07(cpu : &mut Cpu) {
+fn _fx07(cpu : &mut Cpu, x : u8) {
     // Set Vx = delay timer value.
     // The value of DT is placed into Vx.
 
-    println!("{}",
-    "LD Vx, DT -------------------fx07--".green().on_cyan());
-
-    let x : u8 = ((cpu.opcode & 0x0F00) >> 8) as u8;
     cpu.v_regs[x as usize] = cpu.dt;
 }
 
-fn _fx0a(cpu : &mut Cpu) {
+fn _fx0a(cpu : &mut Cpu, x : u8) {
     // Wait for a key press, store the value of the key in Vx.
     // All execution stops until a key is pressed, then the value of
     //  that key is stored in Vx.
 
-    let x : u8 = ((cpu.opcode & 0x0F00) >> 8) as u8;
     let mut count = 0;
 
     for i in 0..16 {
@@ -526,78 +541,565 @@ fn _fx0a(cpu : &mut Cpu) {
     }
 
     if count == 16 {
-        cpu.pc -= 2;
+        cpu.pc = cpu.pc.wrapping_sub(2);
     }
 }
 
-fn _fx15(cpu : &mut Cpu) {
+fn _fx15(cpu : &mut Cpu, x : u8) {
     // Set delay timer = Vx.
     // DT is set equal to the value of Vx.
 
-    println!("{}",
-    "LD DT, Vx -------------------fx15--".green().on_cyan());
-
-    let x : u8 = ((cpu.opcode & 0x0F00) >> 8) as u8;
-    //println!("Vx: {:x} DT: {:x}", cpu.v_regs[x as usize], cpu.dt);
     cpu.dt = cpu.v_regs[x as usize];
 }
 
-fn _fx1e(cpu : &mut Cpu) {
+fn _fx18(cpu : &mut Cpu, x : u8) {
+    // Set sound timer = Vx.
+    // ST is set equal to the value of Vx.
+
+    cpu.st = cpu.v_regs[x as usize];
+}
+
+fn _fx1e(cpu : &mut Cpu, x : u8) {
     // Set I = I + Vx.
     // The values of I and Vx are added, and the results are stored in I.
 
-    println!("{}",
-    "ADD I, Vx -------------------fx1e--".blue().on_green().bold());
+    let sum = cpu.i_reg as usize + cpu.v_regs[x as usize] as usize;
+    cpu.i_reg = sum as u16;
+    if cpu.memory_policy == MemoryPolicy::FlagOverflow {
+        cpu.v_regs[15] = (sum >= cpu.memory.len()) as u8;
+    }
+}
 
-    let x : u8 = ((cpu.opcode & 0x0F00) >> 8) as u8;
-    cpu.i_reg += cpu.v_regs[x as usize] as u16;
+fn _fx29(cpu : &mut Cpu, x : u8) {
+    // Set I = location of sprite for digit Vx.
+    // The value of I is set to the location for the hexadecimal sprite
+    // corresponding to the value of Vx. Only the low nibble is used.
+
+    let digit : u16 = (cpu.v_regs[x as usize] & 0x0F) as u16;
+    cpu.i_reg = FONT_ADDR + digit * GLYPH_SIZE;
+}
+
+fn _fx30(cpu : &mut Cpu, x : u8) {
+    // Set I = location of big sprite for digit Vx.    (SUPER-CHIP)
+    // Like Fx29, but points at the 8x10 glyphs of the big font.
+
+    let digit : u16 = (cpu.v_regs[x as usize] & 0x0F) as u16;
+    cpu.i_reg = BIG_FONT_ADDR + digit * BIG_GLYPH_SIZE;
 }
 
-fn _fx33(cpu : &mut Cpu) {
+fn _fx33(cpu : &mut Cpu, x : u8) -> Result<(), Fault> {
     // Store BCD representation of Vx in memory locations I, I+1, and I+2.
     // The interpreter takes the decimal value of Vx, and places the
     // hundreds digit in memory at location in I, the tens digit at
     // location I+1, and the ones digit at location I+2.
 
-    println!("{}",
-    "ADD B, Vx -------------------fx33--".blue().on_green().bold());
+    let i = cpu.i_reg as usize;
+    let vx = cpu.v_regs[x as usize];
+    cpu.write_byte(i, vx / 100)?;
+    cpu.write_byte(i + 1, (vx / 10) % 10)?;
+    cpu.write_byte(i + 2, (vx % 100) % 10)
+}
+
+fn _fx3a(cpu : &mut Cpu, x : u8) {
+    // Set the pitch of the audio pattern = Vx.           (XO-CHIP)
 
-    let x : u8 = ((cpu.opcode & 0x0F00) >> 8) as u8;
-    cpu.memory[cpu.i_reg as usize] = cpu.v_regs[x as usize] / 100;
-    cpu.memory[(cpu.i_reg + 1) as usize] = (cpu.v_regs[x as usize] / 10) % 10;
-    cpu.memory[(cpu.i_reg + 2) as usize] = (cpu.v_regs[x as usize] % 100) % 10;
+    cpu.beeper().pitch = cpu.v_regs[x as usize];
 }
 
-fn _fx55(cpu : &mut Cpu) {
+fn _fx55(cpu : &mut Cpu, x : u8) -> Result<(), Fault> {
     // Store registers V0 through Vx in memory starting at location I.
     // The interpreter copies the values of registers V0 through Vx into
     // memory, starting at the address in I.
 
-    println!("{}",
-    "LD [I], Vx ------------------fx55--".green().on_cyan());
-
-    let x : u8 = ((cpu.opcode & 0x0F00) >> 8) as u8;
-
     for i in 0..(x+1) {
-        cpu.memory[cpu.i_reg as usize + i as usize] = cpu.v_regs[i as usize];
+        let value = cpu.v_regs[i as usize];
+        cpu.write_byte(cpu.i_reg as usize + i as usize, value)?;
     }
+    increment_index(cpu, x);
+    Ok(())
 }
 
-fn _fx65(cpu : &mut Cpu) {
+fn _fx65(cpu : &mut Cpu, x : u8) -> Result<(), Fault> {
     // Read registers V0 through Vx from memory starting at location I.
     // The interpreter reads values from memory starting at location I
     // into registers V0 through Vx.
 
-    println!("{}",
-    "LD Vx, [I] ------------------fx65--".green().on_cyan());
+    for i in 0..(x+1) {
+        cpu.v_regs[i as usize] = cpu.read_byte(cpu.i_reg as usize + i as usize)?;
+    }
+    increment_index(cpu, x);
+    Ok(())
+}
 
-    let x : u8 = ((cpu.opcode & 0x0F00) >> 8) as u8;
+fn _fx75(cpu : &mut Cpu, x : u8) {
+    // Store V0 through Vx in the RPL user flags.      (SUPER-CHIP)
+    // The HP48 only has 8 flags, so x is limited to 7. XO-CHIP has 16.
 
-    for i in 0..(x+1) {
-        cpu.v_regs[i as usize] = cpu.memory[cpu.i_reg as usize + i as usize];
+    let x = x.min(rpl_last(cpu)) as usize;
+    cpu.rpl[..=x].copy_from_slice(&cpu.v_regs[..=x]);
+}
+
+fn _fx85(cpu : &mut Cpu, x : u8) {
+    // Read V0 through Vx from the RPL user flags.     (SUPER-CHIP)
+
+    let x = x.min(rpl_last(cpu)) as usize;
+    cpu.v_regs[..=x].copy_from_slice(&cpu.rpl[..=x]);
+}
+
+fn increment_index(cpu : &mut Cpu, x : u8) {
+    // What Fx55 and Fx65 leave in I, depending on the quirks.
+
+    match cpu.quirks.index_increment {
+        IndexIncrement::Unchanged => (),
+        IndexIncrement::ByX => cpu.i_reg = cpu.i_reg.wrapping_add(x as u16),
+        IndexIncrement::ByXPlusOne => cpu.i_reg = cpu.i_reg.wrapping_add(x as u16 + 1),
+    }
+}
+
+fn skip(cpu : &mut Cpu) {
+    // Skip the next instruction. With XO-CHIP that may be the four
+    // byte F000 nnnn.
+
+    // An address out of range cannot hold F000, fetch reports it.
+    if cpu.mode >= Mode::XoChip && cpu.peek_word(cpu.pc as usize) == Some(0xF000) {
+        cpu.pc = cpu.pc.wrapping_add(2);
+    }
+    cpu.pc = cpu.pc.wrapping_add(2);
+}
+
+fn scroll(cpu : &mut Cpu, dx : isize, dy : isize) {
+    // Move the selected bitplanes by (dx, dy) pixels. Pixels moved off
+    // the display are lost, the ones moved in are blank.
+
+    let (width, height) = cpu.display_size();
+    let planes = cpu.planes;
+    let old = cpu.v_memory.clone();
+
+    for y in 0..height {
+        for x in 0..width {
+            let (sx, sy) = (x as isize - dx, y as isize - dy);
+            let src = if sx >= 0 && sx < width as isize && sy >= 0 && sy < height as isize {
+                old[sy as usize * width + sx as usize]
+            } else {
+                0
+            };
+            let pixel = &mut cpu.v_memory[y * width + x];
+            *pixel = (*pixel & !planes) | (src & planes);
+        }
+    }
+} // fn scroll
+
+// Register indices from x to y, counting down when y < x.
+fn range(x : u8, y : u8) -> Vec<usize> {
+    if x <= y {
+        (x..=y).map(|r| r as usize).collect()
+    } else {
+        (y..=x).rev().map(|r| r as usize).collect()
     }
 }
 
-fn coord(x : usize, y : usize) -> usize {
-    (((y % 32) * 64) + (x % 64))
+fn rpl_last(cpu : &Cpu) -> u8 {
+    if cpu.mode >= Mode::XoChip { 15 } else { 7 }
+}
+
+fn coord(x : usize, y : usize, width : usize, height : usize) -> usize {
+    ((y % height) * width) + (x % width)
+}
+
+#[cfg(test)]
+mod tests {
+    use cpu::{Cpu, Fault, MemoryPolicy, Mode};
+    use cpu::instruction::decode;
+    use cpu::quirks::{QuirkPreset, Quirks};
+
+    // Run a single opcode on a fresh Cpu with the given registers.
+    fn run(opcode : u16, regs : &[(usize, u8)]) -> Cpu {
+        let mut cpu = Cpu::new();
+        run_on(&mut cpu, opcode, regs);
+        cpu
+    }
+
+    fn run_on(cpu : &mut Cpu, opcode : u16, regs : &[(usize, u8)]) {
+        for &(reg, value) in regs {
+            cpu.v_regs[reg] = value;
+        }
+        cpu.opcode = opcode;
+        cpu.pc += 2;
+        decode(opcode).execute(cpu).unwrap();
+    }
+
+    #[test]
+    fn add_sets_carry_on_overflow() {
+        let cpu = run(0x8014, &[(0, 0xF0), (1, 0x20)]);
+        assert_eq!(cpu.v_regs[0], 0x10);
+        assert_eq!(cpu.v_regs[15], 1);
+
+        let cpu = run(0x8014, &[(0, 0x10), (1, 0x20), (15, 1)]);
+        assert_eq!(cpu.v_regs[0], 0x30);
+        assert_eq!(cpu.v_regs[15], 0);
+    }
+
+    #[test]
+    fn add_into_vf_keeps_the_carry() {
+        let cpu = run(0x8F04, &[(15, 0xFF), (0, 0x02)]);
+        assert_eq!(cpu.v_regs[15], 1);
+    }
+
+    #[test]
+    fn sub_stores_the_wrapped_result() {
+        // Vx >= Vy: no borrow, VF = 1.
+        let cpu = run(0x8015, &[(0, 0x30), (1, 0x10)]);
+        assert_eq!(cpu.v_regs[0], 0x20);
+        assert_eq!(cpu.v_regs[15], 1);
+
+        // Equal operands do not borrow either.
+        let cpu = run(0x8015, &[(0, 0x30), (1, 0x30)]);
+        assert_eq!(cpu.v_regs[0], 0x00);
+        assert_eq!(cpu.v_regs[15], 1);
+
+        // Vx < Vy: borrow, VF = 0, the result wraps around.
+        let cpu = run(0x8015, &[(0, 0x10), (1, 0x30)]);
+        assert_eq!(cpu.v_regs[0], 0xE0);
+        assert_eq!(cpu.v_regs[15], 0);
+    }
+
+    #[test]
+    fn sub_with_vf_operand_writes_the_flag_last() {
+        let cpu = run(0x8F05, &[(15, 0x10), (0, 0x30)]);
+        assert_eq!(cpu.v_regs[15], 0);
+
+        let cpu = run(0x80F5, &[(0, 0x30), (15, 0x10)]);
+        assert_eq!(cpu.v_regs[0], 0x20);
+        assert_eq!(cpu.v_regs[15], 1);
+    }
+
+    #[test]
+    fn subn_stores_the_wrapped_result() {
+        let cpu = run(0x8017, &[(0, 0x10), (1, 0x30)]);
+        assert_eq!(cpu.v_regs[0], 0x20);
+        assert_eq!(cpu.v_regs[15], 1);
+
+        let cpu = run(0x8017, &[(0, 0x30), (1, 0x10)]);
+        assert_eq!(cpu.v_regs[0], 0xE0);
+        assert_eq!(cpu.v_regs[15], 0);
+
+        let cpu = run(0x8F17, &[(15, 0x30), (1, 0x10)]);
+        assert_eq!(cpu.v_regs[15], 0);
+    }
+
+    #[test]
+    fn shr_moves_the_low_bit_into_vf() {
+        let cpu = run(0x8016, &[(0, 0b0000_0101)]);
+        assert_eq!(cpu.v_regs[0], 0b0000_0010);
+        assert_eq!(cpu.v_regs[15], 1);
+
+        let cpu = run(0x8016, &[(0, 0b0000_0100), (15, 1)]);
+        assert_eq!(cpu.v_regs[0], 0b0000_0010);
+        assert_eq!(cpu.v_regs[15], 0);
+
+        let cpu = run(0x8F06, &[(15, 0b0000_0011)]);
+        assert_eq!(cpu.v_regs[15], 1);
+    }
+
+    #[test]
+    fn shl_moves_the_high_bit_into_vf() {
+        let cpu = run(0x801E, &[(0, 0b1000_0001)]);
+        assert_eq!(cpu.v_regs[0], 0b0000_0010);
+        assert_eq!(cpu.v_regs[15], 1);
+
+        let cpu = run(0x801E, &[(0, 0b0100_0001), (15, 1)]);
+        assert_eq!(cpu.v_regs[0], 0b1000_0010);
+        assert_eq!(cpu.v_regs[15], 0);
+
+        let cpu = run(0x8F0E, &[(15, 0b1000_0000)]);
+        assert_eq!(cpu.v_regs[15], 1);
+    }
+
+    #[test]
+    fn shifts_read_vy_with_the_vip_quirk() {
+        let mut cpu = Cpu::new();
+        cpu.quirks = Quirks::preset(QuirkPreset::CosmacVip);
+
+        run_on(&mut cpu, 0x8016, &[(0, 0xFF), (1, 0b0000_0110)]);
+        assert_eq!(cpu.v_regs[0], 0b0000_0011);
+        assert_eq!(cpu.v_regs[15], 0);
+
+        run_on(&mut cpu, 0x801E, &[(0, 0x00), (1, 0b1000_0001)]);
+        assert_eq!(cpu.v_regs[0], 0b0000_0010);
+        assert_eq!(cpu.v_regs[15], 1);
+    }
+
+    #[test]
+    fn memory_past_the_end_wraps_by_default() {
+        let mut cpu = Cpu::new();
+        cpu.i_reg = 0xFFF;
+        run_on(&mut cpu, 0xF033, &[(0, 123)]);
+        assert_eq!(cpu.memory[0xFFF], 1);
+        assert_eq!(cpu.memory[0x000], 2);
+        assert_eq!(cpu.memory[0x001], 3);
+    }
+
+    #[test]
+    fn memory_past_the_end_faults_with_the_fault_policy() {
+        let mut cpu = Cpu::new();
+        cpu.memory_policy = MemoryPolicy::Fault;
+        cpu.i_reg = 0xFFE;
+        cpu.pc = 0x300;
+        cpu.memory[0x300] = 0xF2;
+        cpu.memory[0x301] = 0x55;
+
+        assert_eq!(cpu.step(), Err(Fault::BadAddress { target : 0x1000, addr : 0x300 }));
+        assert_eq!(cpu.pc, 0x300);
+    }
+
+    #[test]
+    fn add_index_flags_overflow_with_the_amiga_policy() {
+        let mut cpu = Cpu::new();
+        cpu.memory_policy = MemoryPolicy::FlagOverflow;
+        cpu.i_reg = 0xFFF;
+        run_on(&mut cpu, 0xF01E, &[(0, 1)]);
+        assert_eq!(cpu.i_reg, 0x1000);
+        assert_eq!(cpu.v_regs[15], 1);
+
+        cpu.i_reg = 0x100;
+        run_on(&mut cpu, 0xF01E, &[(0, 1)]);
+        assert_eq!(cpu.v_regs[15], 0);
+    }
+
+    #[test]
+    fn call_past_the_stack_depth_overflows() {
+        let mut cpu = Cpu::new();
+        cpu.quirks = Quirks::preset(QuirkPreset::CosmacVip);
+        // A subroutine at 0x300 that calls itself.
+        cpu.memory[0x300] = 0x23;
+        cpu.memory[0x301] = 0x00;
+        cpu.pc = 0x300;
+
+        for _ in 0..12 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.sp, 12);
+        assert_eq!(cpu.step(), Err(Fault::StackOverflow { addr : 0x300 }));
+        assert_eq!(cpu.sp, 12);
+    }
+
+    #[test]
+    fn return_with_an_empty_stack_underflows() {
+        let mut cpu = Cpu::new();
+        cpu.memory[0x200] = 0x00;
+        cpu.memory[0x201] = 0xEE;
+
+        assert_eq!(cpu.step(), Err(Fault::StackUnderflow { addr : 0x200 }));
+        assert_eq!(cpu.pc, 0x200);
+    }
+
+    // An XO-CHIP Cpu with the program at 0x200.
+    fn xo_chip(program : &[u8]) -> Cpu {
+        let mut cpu = Cpu::new();
+        cpu.mode = Mode::XoChip;
+        cpu.load_rom_bytes(program).unwrap();
+        cpu
+    }
+
+    #[test]
+    fn long_load_reads_the_word_after_f000() {
+        let mut cpu = xo_chip(&[0xF0, 0x00, 0xAB, 0xCD, 0x60, 0x01]);
+        cpu.step().unwrap();
+        assert_eq!(cpu.i_reg, 0xABCD);
+        assert_eq!(cpu.pc, 0x204);
+    }
+
+    #[test]
+    fn skips_step_over_the_whole_long_load() {
+        let mut cpu = xo_chip(&[0x30, 0x00, 0xF0, 0x00, 0x12, 0x34, 0x60, 0x01]);
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x206);
+
+        // Without XO-CHIP F000 is just another two bytes.
+        let mut cpu = Cpu::new();
+        cpu.load_rom_bytes(&[0x30, 0x00, 0xF0, 0x00]).unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x204);
+    }
+
+    #[test]
+    fn saves_and_loads_register_ranges() {
+        let mut cpu = xo_chip(&[0x00, 0x00]);
+        cpu.i_reg = 0x300;
+        cpu.v_regs[2..5].copy_from_slice(&[1, 2, 3]);
+        run_on(&mut cpu, 0x5242, &[]);
+        assert_eq!(&cpu.memory[0x300..0x304], &[1, 2, 3, 0]);
+        assert_eq!(cpu.i_reg, 0x300);
+
+        // Descending, Vy is loaded first.
+        run_on(&mut cpu, 0x5A83, &[]);
+        assert_eq!(&cpu.v_regs[8..11], &[3, 2, 1]);
+        assert_eq!(cpu.i_reg, 0x300);
+    }
+
+    #[test]
+    fn draws_to_the_selected_bitplanes() {
+        let mut cpu = xo_chip(&[0x00, 0x00]);
+        cpu.i_reg = 0x300;
+        cpu.memory[0x300] = 0x80;   // plane 1
+        cpu.memory[0x301] = 0xC0;   // plane 2
+
+        run_on(&mut cpu, 0xF301, &[]);
+        run_on(&mut cpu, 0xD011, &[(0, 0), (1, 0)]);
+        assert_eq!(&cpu.framebuffer()[..3], &[3, 2, 0]);
+        assert_eq!(cpu.v_regs[15], 0);
+
+        // Clearing plane 2 only leaves plane 1.
+        run_on(&mut cpu, 0xF201, &[]);
+        run_on(&mut cpu, 0x00E0, &[]);
+        assert_eq!(&cpu.framebuffer()[..3], &[1, 0, 0]);
+    }
+
+    #[test]
+    fn loads_the_audio_pattern_and_pitch() {
+        let mut cpu = xo_chip(&[0x00, 0x00]);
+        cpu.i_reg = 0x300;
+        for i in 0..16 {
+            cpu.memory[0x300 + i] = i as u8;
+        }
+        run_on(&mut cpu, 0xF002, &[]);
+        run_on(&mut cpu, 0xF13A, &[(1, 112)]);
+        let mut expected = [0; 16];
+        for (i, byte) in expected.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        assert_eq!(cpu.beeper().pattern, Some(expected));
+        assert_eq!(cpu.beeper().pitch, 112);
+        assert_eq!(cpu.beeper().pattern_rate(), 8000.0);
+
+        cpu.reset();
+        assert_eq!(cpu.beeper().pattern, None);
+    }
+
+    #[test]
+    fn reset_keeps_what_fits_after_a_smaller_mode() {
+        let mut cpu = xo_chip(&vec![0xAA; 0x2000]);
+        cpu.mode = Mode::Chip8;
+        cpu.reset();
+        assert_eq!(cpu.memory.len(), 0x1000);
+        assert_eq!(cpu.memory[0xFFF], 0xAA);
+    }
+
+    // A SUPER-CHIP Cpu with the I pointing at an all lit 32 byte sprite.
+    fn super_chip() -> Cpu {
+        let mut cpu = Cpu::new();
+        cpu.mode = Mode::SuperChip;
+        cpu.i_reg = 0x300;
+        for i in 0..32 {
+            cpu.memory[0x300 + i] = 0xFF;
+        }
+        cpu
+    }
+
+    fn lit(cpu : &Cpu) -> usize {
+        cpu.framebuffer().iter().filter(|&&p| p != 0).count()
+    }
+
+    #[test]
+    fn switches_resolution() {
+        let mut cpu = super_chip();
+        run_on(&mut cpu, 0x00FF, &[]);
+        assert_eq!(cpu.display_size(), (128, 64));
+        assert_eq!(cpu.framebuffer().len(), 128 * 64);
+
+        run_on(&mut cpu, 0xD011, &[(0, 0), (1, 0)]);
+        run_on(&mut cpu, 0x00FE, &[]);
+        assert_eq!(cpu.display_size(), (64, 32));
+        assert_eq!(lit(&cpu), 0);
+    }
+
+    #[test]
+    fn big_sprites_are_8x16_in_low_resolution() {
+        let mut cpu = super_chip();
+        run_on(&mut cpu, 0xD010, &[(0, 0), (1, 0)]);
+        assert_eq!(lit(&cpu), 8 * 16);
+
+        let mut cpu = super_chip();
+        run_on(&mut cpu, 0x00FF, &[]);
+        run_on(&mut cpu, 0xD010, &[(0, 0), (1, 0)]);
+        assert_eq!(lit(&cpu), 16 * 16);
+        assert_eq!(cpu.framebuffer()[15 * 128 + 15], 1);
+
+        let mut cpu = super_chip();
+        cpu.mode = Mode::XoChip;
+        run_on(&mut cpu, 0xD010, &[(0, 0), (1, 0)]);
+        assert_eq!(lit(&cpu), 16 * 16);
+    }
+
+    #[test]
+    fn scrolls_the_display() {
+        // A single pixel at (8, 8) of the high resolution display.
+        let mut cpu = super_chip();
+        run_on(&mut cpu, 0x00FF, &[]);
+        cpu.memory[0x300] = 0x80;
+        run_on(&mut cpu, 0xD011, &[(0, 8), (1, 8)]);
+
+        run_on(&mut cpu, 0x00C3, &[]);
+        assert_eq!(cpu.framebuffer()[11 * 128 + 8], 1);
+        run_on(&mut cpu, 0x00FB, &[]);
+        assert_eq!(cpu.framebuffer()[11 * 128 + 12], 1);
+        run_on(&mut cpu, 0x00FC, &[]);
+        run_on(&mut cpu, 0x00FC, &[]);
+        assert_eq!(cpu.framebuffer()[11 * 128 + 4], 1);
+        assert_eq!(lit(&cpu), 1);
+
+        // Pixels scrolled off the display are gone.
+        run_on(&mut cpu, 0x00CF, &[]);
+        run_on(&mut cpu, 0x00CF, &[]);
+        run_on(&mut cpu, 0x00CF, &[]);
+        run_on(&mut cpu, 0x00CF, &[]);
+        assert_eq!(lit(&cpu), 0);
+    }
+
+    #[test]
+    fn skips_ignore_the_last_nibble_before_xo_chip() {
+        // 5xy2 is SE and 9xy1 is SNE, as they always were.
+        let cpu = run(0x5012, &[(0, 7), (1, 7)]);
+        assert_eq!(cpu.pc, 0x204);
+        let cpu = run(0x9011, &[(0, 7), (1, 8)]);
+        assert_eq!(cpu.pc, 0x204);
+
+        // With XO-CHIP 5xy2 saves and 9xy1 is unknown.
+        let mut cpu = Cpu::new();
+        cpu.mode = Mode::XoChip;
+        cpu.i_reg = 0x300;
+        run_on(&mut cpu, 0x5012, &[(0, 7), (1, 7)]);
+        assert_eq!(cpu.pc, 0x202);
+        assert_eq!(&cpu.memory[0x300..0x302], &[7, 7]);
+        assert_eq!(decode(0x9011).in_mode(Mode::XoChip), decode(0x9011));
+    }
+
+    #[test]
+    fn unknown_opcodes_follow_the_policy() {
+        use cpu::OpcodePolicy;
+        use std::rc::Rc;
+        use std::cell::RefCell;
+
+        // Skipped silently by default.
+        let mut cpu = Cpu::new();
+        cpu.load_rom_bytes(&[0xFF, 0xFF, 0x60, 0x01]).unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x202);
+
+        cpu.reset();
+        cpu.opcode_policy = OpcodePolicy::Halt;
+        assert_eq!(cpu.step(), Err(Fault::UnknownOpcode { opcode : 0xFFFF, addr : 0x200 }));
+        assert_eq!(cpu.pc, 0x200);
+
+        // A hook sees every fault and decides.
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let log = seen.clone();
+        cpu.opcode_policy = OpcodePolicy::Hook(Box::new(move |_, fault| {
+            log.borrow_mut().push(fault);
+            Ok(())
+        }));
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x202);
+        assert_eq!(*seen.borrow(), [Fault::UnknownOpcode { opcode : 0xFFFF, addr : 0x200 }]);
+    }
 }
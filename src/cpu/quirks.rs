@@ -0,0 +1,168 @@
+/*
+ * A handful of instructions behave differently depending on which
+ * interpreter a ROM was written for. Quirks holds one switch for each
+ * of them, and the presets collect the switches of the well known
+ * interpreters. The default is the modern behaviour most ROMs found
+ * today expect.
+ *
+ * The depth of the call stack is part of the profile too: the COSMAC
+ * VIP had room for 12 return addresses, later interpreters for 16.
+ */
+
+// What Fx55 and Fx65 do to I after the registers have been copied.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IndexIncrement {
+    Unchanged,              // SUPER-CHIP and most modern interpreters
+    ByX,                    // CHIP-48: I = I + x
+    ByXPlusOne,             // COSMAC VIP and XO-CHIP: I = I + x + 1
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Quirks {
+    pub shift_vy : bool,                // 8xy6/8xyE shift Vy into Vx instead of Vx itself
+    pub index_increment : IndexIncrement,   // Fx55/Fx65
+    pub jump_vx : bool,                 // Bxnn jumps to xnn + Vx instead of nnn + V0
+    pub clip_sprites : bool,            // Dxyn clips at the edges instead of wrapping
+    pub vf_reset : bool,                // 8xy1/8xy2/8xy3 set VF to 0
+    pub stack_depth : usize,            // Nested CALLs before a stack overflow
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QuirkPreset {
+    CosmacVip,
+    Chip48,
+    Schip,                  // SUPER-CHIP 1.1
+    XoChip,
+    Modern,
+}
+
+impl QuirkPreset {
+    pub const ALL : [QuirkPreset; 5] = [
+        QuirkPreset::CosmacVip,
+        QuirkPreset::Chip48,
+        QuirkPreset::Schip,
+        QuirkPreset::XoChip,
+        QuirkPreset::Modern];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            QuirkPreset::CosmacVip => "vip",
+            QuirkPreset::Chip48 => "chip48",
+            QuirkPreset::Schip => "schip",
+            QuirkPreset::XoChip => "xochip",
+            QuirkPreset::Modern => "modern",
+        }
+    }
+
+    pub fn from_name(name : &str) -> Option<QuirkPreset> {
+        let name = name.to_lowercase();
+        QuirkPreset::ALL.iter()
+            .find(|p| p.name() == name)
+            .copied()
+    }
+}
+
+impl Quirks {
+    pub fn preset(preset : QuirkPreset) -> Quirks {
+        match preset {
+            QuirkPreset::CosmacVip => Quirks {
+                shift_vy : true,
+                index_increment : IndexIncrement::ByXPlusOne,
+                jump_vx : false,
+                clip_sprites : true,
+                vf_reset : true,
+                stack_depth : 12,
+            },
+            QuirkPreset::Chip48 => Quirks {
+                shift_vy : false,
+                index_increment : IndexIncrement::ByX,
+                jump_vx : true,
+                clip_sprites : true,
+                vf_reset : false,
+                stack_depth : 16,
+            },
+            QuirkPreset::Schip => Quirks {
+                shift_vy : false,
+                index_increment : IndexIncrement::Unchanged,
+                jump_vx : true,
+                clip_sprites : true,
+                vf_reset : false,
+                stack_depth : 16,
+            },
+            QuirkPreset::XoChip => Quirks {
+                shift_vy : true,
+                index_increment : IndexIncrement::ByXPlusOne,
+                jump_vx : false,
+                clip_sprites : false,
+                vf_reset : false,
+                stack_depth : 16,
+            },
+            QuirkPreset::Modern => Quirks {
+                shift_vy : false,
+                index_increment : IndexIncrement::Unchanged,
+                jump_vx : false,
+                clip_sprites : false,
+                vf_reset : false,
+                stack_depth : 16,
+            },
+        }
+    } // fn preset
+}
+
+impl Default for Quirks {
+    fn default() -> Quirks {
+        Quirks::preset(QuirkPreset::Modern)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpu::Cpu;
+
+    #[test]
+    fn presets_are_found_by_name() {
+        for &preset in QuirkPreset::ALL.iter() {
+            assert_eq!(QuirkPreset::from_name(preset.name()), Some(preset));
+        }
+        assert_eq!(QuirkPreset::from_name("VIP"), Some(QuirkPreset::CosmacVip));
+        assert_eq!(QuirkPreset::from_name("chip8"), None);
+        assert_eq!(Quirks::default(), Quirks::preset(QuirkPreset::Modern));
+    }
+
+    // Run a program under a preset until it has run `steps` instructions.
+    fn run(preset : QuirkPreset, program : &[u8], steps : usize) -> Cpu {
+        let mut cpu = Cpu::new();
+        cpu.quirks = Quirks::preset(preset);
+        cpu.load_rom_bytes(program).unwrap();
+        for _ in 0..steps {
+            cpu.step().unwrap();
+        }
+        cpu
+    }
+
+    #[test]
+    fn presets_change_what_instructions_do() {
+        // V1 = 3, VF = 1, V0 |= V1: only the VIP resets VF.
+        let program = [0x61, 0x03, 0x6F, 0x01, 0x80, 0x11];
+        assert_eq!(run(QuirkPreset::CosmacVip, &program, 3).v_regs[15], 0);
+        assert_eq!(run(QuirkPreset::Modern, &program, 3).v_regs[15], 1);
+
+        // V3 = 4, B300: CHIP-48 and SUPER-CHIP jump to 300 + V3.
+        let program = [0x63, 0x04, 0xB3, 0x00];
+        assert_eq!(run(QuirkPreset::Chip48, &program, 2).pc, 0x304);
+        assert_eq!(run(QuirkPreset::Schip, &program, 2).pc, 0x304);
+        assert_eq!(run(QuirkPreset::XoChip, &program, 2).pc, 0x300);
+
+        // I = 300, Fx55 with x = 2 leaves I depending on the preset.
+        let program = [0xA3, 0x00, 0xF2, 0x55];
+        assert_eq!(run(QuirkPreset::CosmacVip, &program, 2).i_reg, 0x303);
+        assert_eq!(run(QuirkPreset::Chip48, &program, 2).i_reg, 0x302);
+        assert_eq!(run(QuirkPreset::Schip, &program, 2).i_reg, 0x300);
+
+        // A sprite at x = 62 is clipped by the VIP and wraps on modern.
+        let program = [0x60, 0x3E, 0xA2, 0x06, 0xD0, 0x11, 0xFF];
+        assert_eq!(run(QuirkPreset::CosmacVip, &program, 3).framebuffer()[..2], [0, 0]);
+        assert_eq!(run(QuirkPreset::Modern, &program, 3).framebuffer()[..2], [1, 1]);
+    }
+}
@@ -0,0 +1,94 @@
+/*
+ * The delay and sound timers always count down at 60 Hz, no matter
+ * how fast the interpreter runs. Work is therefore split into 60 Hz
+ * frames: the Scheduler tells the Cpu how many instructions to run
+ * in the next frame, after which the timers tick once and the frame
+ * can be presented.
+ */
+
+pub const TIMER_HZ : u32 = 60;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Speed {
+    InstructionsPerFrame(u32),
+    Hz(u32),                    // Instructions per second
+}
+
+impl Default for Speed {
+    fn default() -> Speed {
+        Speed::InstructionsPerFrame(10)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Scheduler {
+    pub speed : Speed,
+    carry : u32,        // Instructions owed by previous frames (Hz mode)
+}
+
+impl Scheduler {
+    pub fn new(speed : Speed) -> Scheduler {
+        Scheduler { speed, carry : 0 }
+    }
+
+    pub fn set_speed(&mut self, speed : Speed) {
+        self.speed = speed;
+        self.carry = 0;
+    }
+
+    // Number of instructions to run in the next frame. A rate that is
+    // not a multiple of 60 Hz is spread over the frames, so that every
+    // second exactly `hz` instructions are executed.
+    pub fn next_frame(&mut self) -> u32 {
+        match self.speed {
+            Speed::InstructionsPerFrame(n) => n,
+            Speed::Hz(hz) => {
+                // u64 so a rate near u32::MAX cannot overflow.
+                let total = self.carry as u64 + hz as u64;
+                self.carry = (total % TIMER_HZ as u64) as u32;
+                (total / TIMER_HZ as u64) as u32
+            },
+        }
+    } // fn next_frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runs_a_fixed_number_per_frame() {
+        let mut scheduler = Scheduler::new(Speed::InstructionsPerFrame(15));
+        assert_eq!(scheduler.next_frame(), 15);
+        assert_eq!(scheduler.next_frame(), 15);
+        assert_eq!(Scheduler::default().next_frame(), 10);
+    }
+
+    #[test]
+    fn spreads_hz_over_the_frames() {
+        // 90 Hz is 1.5 a frame: the half is carried to the next frame.
+        let mut scheduler = Scheduler::new(Speed::Hz(90));
+        let frames : Vec<u32> = (0..4).map(|_| scheduler.next_frame()).collect();
+        assert_eq!(frames, [1, 2, 1, 2]);
+
+        let mut scheduler = Scheduler::new(Speed::Hz(1000));
+        let second : u32 = (0..TIMER_HZ).map(|_| scheduler.next_frame()).sum();
+        assert_eq!(second, 1000);
+
+        // Changing the speed drops what was carried.
+        scheduler.next_frame();
+        scheduler.set_speed(Speed::Hz(30));
+        assert_eq!(scheduler.next_frame(), 0);
+        assert_eq!(scheduler.next_frame(), 1);
+    }
+
+    #[test]
+    fn does_not_overflow_near_u32_max() {
+        let mut scheduler = Scheduler::new(Speed::Hz(u32::MAX));
+        let first = scheduler.next_frame();
+        assert_eq!(first, u32::MAX / TIMER_HZ);
+        for _ in 0..TIMER_HZ * 2 {
+            assert!(scheduler.next_frame() >= first);
+        }
+    }
+}
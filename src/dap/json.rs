@@ -0,0 +1,338 @@
+use std::fmt;
+use std::fmt::Write as FmtWrite;
+
+/*
+ * Just enough JSON for the debug adapter protocol: a value type that
+ * parses and prints itself. Objects keep their keys in order, numbers
+ * are f64 and print without a fraction when they have none.
+ */
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn parse(text : &str) -> Result<Json, String> {
+        let mut parser = Parser { bytes : text.as_bytes(), pos : 0 };
+        let value = parser.value()?;
+        parser.space();
+        if parser.pos < parser.bytes.len() {
+            return Err(format!("unexpected `{}` after the value at {}",
+                               parser.bytes[parser.pos] as char, parser.pos));
+        }
+        Ok(value)
+    }
+
+    // An object from key and value pairs.
+    pub fn object(fields : Vec<(&str, Json)>) -> Json {
+        Json::Object(fields.into_iter().map(|(k, v)| (k.to_string(), v)).collect())
+    }
+
+    // The value of a key, Null when missing or not an object.
+    pub fn get(&self, key : &str) -> &Json {
+        match *self {
+            Json::Object(ref fields) => fields.iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| v)
+                .unwrap_or(&Json::Null),
+            _ => &Json::Null,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            Json::String(ref s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self {
+            Json::Number(n) => Some(n),
+            _ => None,
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        self.as_f64().filter(|n| n.fract() == 0.0).map(|n| n as i64)
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            Json::Bool(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> &[Json] {
+        match *self {
+            Json::Array(ref items) => items,
+            _ => &[],
+        }
+    }
+}
+
+impl From<bool> for Json {
+    fn from(b : bool) -> Json {
+        Json::Bool(b)
+    }
+}
+
+impl From<&str> for Json {
+    fn from(s : &str) -> Json {
+        Json::String(s.to_string())
+    }
+}
+
+impl From<String> for Json {
+    fn from(s : String) -> Json {
+        Json::String(s)
+    }
+}
+
+impl From<Vec<Json>> for Json {
+    fn from(items : Vec<Json>) -> Json {
+        Json::Array(items)
+    }
+}
+
+macro_rules! from_number {
+    ($($t:ty)*) => ($(
+        impl From<$t> for Json {
+            fn from(n : $t) -> Json {
+                Json::Number(n as f64)
+            }
+        }
+    )*)
+}
+
+from_number!(u8 u16 u64 usize i64);
+
+impl fmt::Display for Json {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Number(n) if n.fract() == 0.0 && n.abs() < 1e15 => write!(f, "{}", n as i64),
+            Json::Number(n) if n.is_finite() => write!(f, "{}", n),
+            Json::Number(_) => write!(f, "null"),
+            Json::String(ref s) => write_string(f, s),
+            Json::Array(ref items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            },
+            Json::Object(ref fields) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                write!(f, "}}")
+            },
+        }
+    }
+}
+
+fn write_string(f : &mut fmt::Formatter, s : &str) -> fmt::Result {
+    f.write_char('"')?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => f.write_char(c)?,
+        }
+    }
+    f.write_char('"')
+}
+
+struct Parser<'a> {
+    bytes : &'a [u8],
+    pos : usize,
+}
+
+impl<'a> Parser<'a> {
+    fn space(&mut self) {
+        while self.pos < self.bytes.len() && self.bytes[self.pos].is_ascii_whitespace() {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        self.space();
+        self.bytes.get(self.pos).copied()
+    }
+
+    fn expect(&mut self, byte : u8) -> Result<(), String> {
+        match self.peek() {
+            Some(b) if b == byte => {
+                self.pos += 1;
+                Ok(())
+            },
+            _ => Err(format!("expected `{}` at {}", byte as char, self.pos)),
+        }
+    }
+
+    fn literal(&mut self, word : &str, value : Json) -> Result<Json, String> {
+        if self.bytes[self.pos..].starts_with(word.as_bytes()) {
+            self.pos += word.len();
+            Ok(value)
+        } else {
+            Err(format!("unexpected `{}` at {}", self.bytes[self.pos] as char, self.pos))
+        }
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        match self.peek() {
+            None => Err(String::from("unexpected end of JSON")),
+            Some(b'n') => self.literal("null", Json::Null),
+            Some(b't') => self.literal("true", Json::Bool(true)),
+            Some(b'f') => self.literal("false", Json::Bool(false)),
+            Some(b'"') => self.string().map(Json::String),
+            Some(b'[') => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                if self.peek() == Some(b']') {
+                    self.pos += 1;
+                    return Ok(Json::Array(items));
+                }
+                loop {
+                    items.push(self.value()?);
+                    match self.peek() {
+                        Some(b',') => self.pos += 1,
+                        _ => break,
+                    }
+                }
+                self.expect(b']')?;
+                Ok(Json::Array(items))
+            },
+            Some(b'{') => {
+                self.pos += 1;
+                let mut fields = Vec::new();
+                if self.peek() == Some(b'}') {
+                    self.pos += 1;
+                    return Ok(Json::Object(fields));
+                }
+                loop {
+                    if self.peek() != Some(b'"') {
+                        return Err(format!("expected a key at {}", self.pos));
+                    }
+                    let key = self.string()?;
+                    self.expect(b':')?;
+                    fields.push((key, self.value()?));
+                    match self.peek() {
+                        Some(b',') => self.pos += 1,
+                        _ => break,
+                    }
+                }
+                self.expect(b'}')?;
+                Ok(Json::Object(fields))
+            },
+            Some(_) => self.number(),
+        }
+    } // fn value
+
+    fn number(&mut self) -> Result<Json, String> {
+        let start = self.pos;
+        while self.pos < self.bytes.len() &&
+              matches!(self.bytes[self.pos], b'0'..=b'9' | b'-' | b'+' | b'.' | b'e' | b'E') {
+            self.pos += 1;
+        }
+        let text = String::from_utf8_lossy(&self.bytes[start..self.pos]);
+        text.parse().map(Json::Number)
+            .map_err(|_| format!("bad number `{}` at {}", text, start))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        // The opening quote is next. Escapes are decoded, including
+        // surrogate pairs.
+
+        self.pos += 1;
+        let mut out = Vec::new();
+        loop {
+            let byte = match self.bytes.get(self.pos) {
+                Some(&b) => b,
+                None => return Err(String::from("unterminated string")),
+            };
+            self.pos += 1;
+            match byte {
+                b'"' => break,
+                b'\\' => {
+                    let escape = self.bytes.get(self.pos).copied();
+                    self.pos += 1;
+                    let c = match escape {
+                        Some(b'"') => '"',
+                        Some(b'\\') => '\\',
+                        Some(b'/') => '/',
+                        Some(b'b') => '\u{8}',
+                        Some(b'f') => '\u{c}',
+                        Some(b'n') => '\n',
+                        Some(b'r') => '\r',
+                        Some(b't') => '\t',
+                        Some(b'u') => self.unicode()?,
+                        _ => return Err(format!("bad escape at {}", self.pos - 1)),
+                    };
+                    let mut buf = [0; 4];
+                    out.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                },
+                b => out.push(b),
+            }
+        }
+        String::from_utf8(out).map_err(|_| String::from("string is not UTF-8"))
+    } // fn string
+
+    fn hex4(&mut self) -> Result<u32, String> {
+        let digits = self.bytes.get(self.pos..self.pos + 4)
+            .and_then(|d| std::str::from_utf8(d).ok())
+            .and_then(|d| u32::from_str_radix(d, 16).ok())
+            .ok_or_else(|| format!("bad \\u escape at {}", self.pos))?;
+        self.pos += 4;
+        Ok(digits)
+    }
+
+    fn unicode(&mut self) -> Result<char, String> {
+        let high = self.hex4()?;
+        let code = if (0xD800..0xDC00).contains(&high) &&
+                      self.bytes[self.pos..].starts_with(b"\\u") {
+            self.pos += 2;
+            let low = self.hex4()?;
+            0x10000 + ((high - 0xD800) << 10) + (low.wrapping_sub(0xDC00) & 0x3FF)
+        } else {
+            high
+        };
+        Ok(std::char::from_u32(code).unwrap_or('\u{FFFD}'))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_and_prints_json() {
+        let text = r#"{"a":[1,2.5,true,null],"b":"x\"\né😀"}"#;
+        let value = Json::parse(text).unwrap();
+        assert_eq!(value.get("a").as_array()[1].as_f64(), Some(2.5));
+        assert_eq!(value.get("b").as_str(), Some("x\"\n\u{e9}\u{1F600}"));
+        assert_eq!(value.to_string(), r#"{"a":[1,2.5,true,null],"b":"x\"\né😀"}"#);
+        assert!(Json::parse("{\"a\":}").is_err());
+    }
+}
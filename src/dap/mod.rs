@@ -0,0 +1,867 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::sync::mpsc;
+use std::thread;
+use asm::{self, Program, SourceLine};
+use cpu::{Cpu, Mode, OpcodePolicy, QuirkPreset, Quirks};
+use debugger::{self, Condition, Debugger, Register, Stop};
+use octo;
+
+pub mod json;
+
+use self::json::Json;
+
+/*
+ * A server for the debug adapter protocol, so editors like VS Code can
+ * debug CHIP-8 programs. It talks over stdin and stdout or a local TCP
+ * port, one thread reading requests while the other runs the program.
+ *
+ * `launch` takes the program and optionally `mode` (chip8, schip or
+ * xochip) and `stopOnEntry`. Octo (.8o) and assembler (.asm, .s) sources
+ * are built first, and their source map allows breakpoints on lines and
+ * shows where in the source the program is. Breakpoints on addresses
+ * come in as instruction breakpoints, both can have a condition in the
+ * prompt's syntax (`v3 == 5`).
+ *
+ * The variables view has the registers, the timers and the stack, the
+ * memory view reads and writes memory, and the debug console passes
+ * its input on to the debugger prompt (e.g. `watch w 300-30F`).
+ */
+
+// Instructions run between looks at new requests while continuing.
+const SLICE : u64 = 100_000;
+
+const THREAD : u8 = 1;
+
+// variablesReference of the scopes.
+const REGISTERS : u8 = 1;
+const TIMERS : u8 = 2;
+const STACK : u8 = 3;
+
+pub struct Session {
+    pub cpu : Cpu,
+    pub debugger : Debugger,
+    pub program : Option<Program>,      // When built from source
+    pub closed : bool,                  // Disconnected
+    running : bool,
+    launched : bool,
+    stop_on_entry : bool,
+    source_breakpoints : BTreeMap<String, Vec<usize>>,  // Ids by source path
+    instruction_breakpoints : Vec<usize>,
+    events : Vec<Json>,                 // Sent after the response
+    seq : u64,
+}
+
+impl Default for Session {
+    fn default() -> Session {
+        Session::new()
+    }
+}
+
+impl Session {
+    pub fn new() -> Session {
+        Session {
+            cpu : Cpu::new(),
+            debugger : Debugger::new(),
+            program : None,
+            closed : false,
+            running : false,
+            launched : false,
+            stop_on_entry : false,
+            source_breakpoints : BTreeMap::new(),
+            instruction_breakpoints : Vec::new(),
+            events : Vec::new(),
+            seq : 0,
+        }
+    }
+
+    pub fn running(&self) -> bool {
+        self.running
+    }
+
+    // Handle a request, giving the response and any events to send.
+    pub fn handle(&mut self, request : &Json) -> Vec<Json> {
+        let command = request.get("command").as_str().unwrap_or("");
+        let args = request.get("arguments");
+
+        let result = match command {
+            "initialize" => Ok(capabilities()),
+            "launch" => self.launch(args),
+            "configurationDone" => {
+                if self.stop_on_entry {
+                    self.stopped(Stop::Done, "entry");
+                } else {
+                    self.running = true;
+                }
+                Ok(Json::Null)
+            },
+            "setBreakpoints" => self.set_breakpoints(args),
+            "setInstructionBreakpoints" => self.set_instruction_breakpoints(args),
+            "setExceptionBreakpoints" => Ok(Json::Null),
+            "threads" => Ok(Json::object(vec![
+                ("threads", Json::from(vec![Json::object(vec![
+                    ("id", Json::from(THREAD)),
+                    ("name", Json::from("CHIP-8")),
+                ])])),
+            ])),
+            "stackTrace" => Ok(self.stack_trace()),
+            "scopes" => Ok(scopes()),
+            "variables" => Ok(self.variables(args)),
+            "setVariable" => self.set_variable(args),
+            "continue" => {
+                self.running = true;
+                Ok(Json::object(vec![("allThreadsContinued", Json::from(true))]))
+            },
+            "next" | "stepIn" | "stepOut" => {
+                let stop = match command {
+                    "next" => self.debugger.step_over(&mut self.cpu),
+                    "stepIn" => self.debugger.step(&mut self.cpu, 1),
+                    _ => self.debugger.step_out(&mut self.cpu),
+                };
+                self.stopped(stop, "step");
+                Ok(Json::Null)
+            },
+            "pause" => {
+                self.running = false;
+                self.stopped(Stop::Done, "pause");
+                Ok(Json::Null)
+            },
+            "readMemory" => self.read_memory(args),
+            "writeMemory" => self.write_memory(args),
+            "disassemble" => self.disassemble(args),
+            "evaluate" => self.evaluate(args),
+            "disconnect" | "terminate" => {
+                self.running = false;
+                self.closed = true;
+                Ok(Json::Null)
+            },
+            _ => Err(format!("`{}` is not supported", command)),
+        };
+
+        // The adapter is ready for breakpoints once there is a program.
+        if command == "launch" && result.is_ok() {
+            self.event("initialized", Json::Null);
+        }
+
+        let mut response = vec![
+            ("type", Json::from("response")),
+            ("request_seq", request.get("seq").clone()),
+            ("success", Json::from(result.is_ok())),
+            ("command", Json::from(command)),
+        ];
+        match result {
+            Ok(Json::Null) => (),
+            Ok(body) => response.push(("body", body)),
+            Err(message) => response.push(("message", Json::from(message))),
+        }
+
+        let mut messages = vec![Json::object(response)];
+        messages.append(&mut self.events);
+        messages.into_iter().map(|m| self.stamp(m)).collect()
+    } // fn handle
+
+    // Run a little further while continuing, giving any events.
+    pub fn resume(&mut self) -> Vec<Json> {
+        let limit = self.debugger.limit;
+        self.debugger.limit = SLICE;
+        let stop = self.debugger.cont(&mut self.cpu);
+        self.debugger.limit = limit;
+
+        if stop != Stop::Limit {
+            self.stopped(stop, "step");
+        }
+        self.flush()
+    }
+
+    // Tell the client about a message that could not be read.
+    pub fn reject(&mut self, error : &str) -> Vec<Json> {
+        self.event("output", Json::object(vec![
+            ("category", Json::from("stderr")),
+            ("output", Json::from(format!("bad request: {}\n", error))),
+        ]));
+        self.flush()
+    }
+
+    fn flush(&mut self) -> Vec<Json> {
+        let events : Vec<Json> = self.events.drain(..).collect();
+        events.into_iter().map(|m| self.stamp(m)).collect()
+    }
+
+    fn stamp(&mut self, message : Json) -> Json {
+        self.seq += 1;
+        match message {
+            Json::Object(mut fields) => {
+                fields.insert(0, (String::from("seq"), Json::from(self.seq)));
+                Json::Object(fields)
+            },
+            other => other,
+        }
+    }
+
+    fn event(&mut self, name : &str, body : Json) {
+        let mut fields = vec![("type", Json::from("event")), ("event", Json::from(name))];
+        if body != Json::Null {
+            fields.push(("body", body));
+        }
+        self.events.push(Json::object(fields));
+    }
+
+    fn stopped(&mut self, stop : Stop, reason : &str) {
+        self.running = false;
+
+        let (reason, description) = match stop {
+            Stop::Exited => {
+                self.event("exited", Json::object(vec![("exitCode", Json::from(0u8))]));
+                self.event("terminated", Json::Null);
+                return;
+            },
+            Stop::Done => (reason, None),
+            Stop::Breakpoint(_) => ("breakpoint", None),
+            Stop::Watchpoint { .. } | Stop::SelfModify { .. } => ("data breakpoint", Some(stop)),
+            Stop::Fault(_) => ("exception", Some(stop)),
+            Stop::Limit => (reason, Some(stop)),
+        };
+
+        let mut body = vec![
+            ("reason", Json::from(reason)),
+            ("threadId", Json::from(THREAD)),
+            ("allThreadsStopped", Json::from(true)),
+        ];
+        if let Some(stop) = description {
+            let report = self.debugger.report(&self.cpu, stop);
+            let first = report.lines().next().unwrap_or("").to_string();
+            body.push(("description", Json::from(first)));
+            body.push(("text", Json::from(report)));
+        }
+        self.event("stopped", Json::object(body));
+    } // fn stopped
+
+    fn launch(&mut self, args : &Json) -> Result<Json, String> {
+        // Set up a fresh machine for the program, building it first if
+        // it is a source file. Unknown opcodes stop the program.
+
+        let path = args.get("program").as_str()
+            .ok_or_else(|| String::from("launch needs a `program`"))?;
+        let mode = match args.get("mode").as_str() {
+            None => Mode::Chip8,
+            Some(name) => Mode::from_name(name)
+                .ok_or_else(|| format!("mode must be chip8, schip or xochip, not {}", name))?,
+        };
+
+        let mut cpu = Cpu::new();
+        cpu.mode = mode;
+        cpu.quirks = Quirks::preset(match mode {
+            Mode::Chip8 => QuirkPreset::Modern,
+            Mode::SuperChip => QuirkPreset::Schip,
+            Mode::XoChip => QuirkPreset::XoChip,
+        });
+        cpu.opcode_policy = OpcodePolicy::Halt;
+
+        let program = if octo::is_source(path) {
+            Some(octo::compile_file(path))
+        } else if path.ends_with(".asm") || path.ends_with(".s") {
+            Some(asm::assemble_file(path))
+        } else {
+            None
+        };
+        let program = match program {
+            Some(Ok(program)) => {
+                cpu.load_rom_bytes(&program.rom).map_err(|e| e.to_string())?;
+                Some(program)
+            },
+            Some(Err(errors)) => {
+                let errors : Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+                return Err(errors.join("\n"));
+            },
+            None => {
+                cpu.load_rom(path).map_err(|e| format!("{}: {}", path, e))?;
+                None
+            },
+        };
+
+        self.cpu = cpu;
+        self.program = program;
+        self.debugger = Debugger::new();
+        self.source_breakpoints.clear();
+        self.instruction_breakpoints.clear();
+        self.stop_on_entry = args.get("stopOnEntry").as_bool().unwrap_or(false);
+        self.launched = true;
+        Ok(Json::Null)
+    } // fn launch
+
+    fn set_breakpoints(&mut self, args : &Json) -> Result<Json, String> {
+        // The breakpoints of one source file replace its earlier ones.
+        // A line without code moves to the next line that has some.
+
+        let path = args.get("source").get("path").as_str().unwrap_or("").to_string();
+        for id in self.source_breakpoints.remove(&path).unwrap_or_default() {
+            self.debugger.remove_breakpoint(id);
+        }
+
+        let mut ids = Vec::new();
+        let mut breakpoints = Vec::new();
+        for requested in args.get("breakpoints").as_array() {
+            let line = requested.get("line").as_i64().unwrap_or(0).max(0) as usize;
+            let found = self.find_line(&path, line);
+            let condition = condition(requested);
+
+            breakpoints.push(match (found, condition) {
+                (Some(source), Ok(condition)) => {
+                    let id = self.debugger.add_breakpoint(source.addr, condition);
+                    ids.push(id);
+                    Json::object(vec![
+                        ("id", Json::from(id)),
+                        ("verified", Json::from(true)),
+                        ("line", Json::from(source.line)),
+                        ("instructionReference", reference(source.addr)),
+                    ])
+                },
+                (None, _) => unverified(if self.program.is_some() {
+                    "no code at or after this line"
+                } else {
+                    "the program was not built from source"
+                }),
+                (_, Err(message)) => unverified(&message),
+            });
+        }
+
+        self.source_breakpoints.insert(path, ids);
+        Ok(Json::object(vec![("breakpoints", Json::from(breakpoints))]))
+    } // fn set_breakpoints
+
+    fn set_instruction_breakpoints(&mut self, args : &Json) -> Result<Json, String> {
+        for id in self.instruction_breakpoints.drain(..) {
+            self.debugger.remove_breakpoint(id);
+        }
+
+        let mut breakpoints = Vec::new();
+        for requested in args.get("breakpoints").as_array() {
+            let addr = requested.get("instructionReference").as_str()
+                .ok_or_else(|| String::from("no instructionReference"))
+                .and_then(debugger::number)
+                .map(|addr| addr.wrapping_add(requested.get("offset").as_i64().unwrap_or(0) as u16));
+
+            breakpoints.push(match (addr, condition(requested)) {
+                (Ok(addr), Ok(condition)) => {
+                    let id = self.debugger.add_breakpoint(addr, condition);
+                    self.instruction_breakpoints.push(id);
+                    Json::object(vec![
+                        ("id", Json::from(id)),
+                        ("verified", Json::from(true)),
+                        ("instructionReference", reference(addr)),
+                    ])
+                },
+                (Err(message), _) | (_, Err(message)) => unverified(&message),
+            });
+        }
+        Ok(Json::object(vec![("breakpoints", Json::from(breakpoints))]))
+    }
+
+    fn stack_trace(&self) -> Json {
+        // The current instruction, then every CALL on the stack.
+
+        let cpu = &self.cpu;
+        let mut addrs = vec![cpu.pc];
+        for depth in (0..cpu.sp as usize).rev() {
+            addrs.push(cpu.stack[depth].wrapping_sub(2));
+        }
+
+        let frames : Vec<Json> = addrs.iter().enumerate().map(|(id, &addr)| {
+            let mut frame = vec![
+                ("id", Json::from(id)),
+                ("name", Json::from(self.frame_name(addr))),
+                ("instructionPointerReference", reference(addr)),
+                ("column", Json::from(1u8)),
+            ];
+            match self.source_line(addr) {
+                Some(source) => {
+                    frame.push(("line", Json::from(source.line)));
+                    frame.push(("source", source_json(&source.file)));
+                },
+                None => frame.push(("line", Json::from(0u8))),
+            }
+            Json::object(frame)
+        }).collect();
+
+        Json::object(vec![
+            ("totalFrames", Json::from(frames.len())),
+            ("stackFrames", Json::from(frames)),
+        ])
+    } // fn stack_trace
+
+    fn variables(&self, args : &Json) -> Json {
+        let cpu = &self.cpu;
+        let hex = |register : Register, width : usize| {
+            format!("0x{:01$X}", register.get(cpu), width)
+        };
+
+        let variables = match args.get("variablesReference").as_i64() {
+            Some(r) if r == REGISTERS as i64 => {
+                let mut variables : Vec<Json> = (0..16)
+                    .map(|x| variable(Register::V(x), hex(Register::V(x), 2), None))
+                    .collect();
+                variables.push(variable(Register::I, hex(Register::I, 3), Some(cpu.i_reg)));
+                variables.push(variable(Register::Pc, hex(Register::Pc, 3), Some(cpu.pc)));
+                variables.push(variable(Register::Sp, cpu.sp.to_string(), None));
+                variables
+            },
+            Some(r) if r == TIMERS as i64 => vec![
+                variable(Register::Dt, cpu.dt.to_string(), None),
+                variable(Register::St, cpu.st.to_string(), None),
+            ],
+            Some(r) if r == STACK as i64 => (0..cpu.sp as usize)
+                .map(|n| variable(Register::Stack(n), hex(Register::Stack(n), 3),
+                                  Some(cpu.stack[n])))
+                .collect(),
+            _ => Vec::new(),
+        };
+        Json::object(vec![("variables", Json::from(variables))])
+    }
+
+    fn set_variable(&mut self, args : &Json) -> Result<Json, String> {
+        let name = args.get("name").as_str().unwrap_or("");
+        let register = Register::from_name(name)
+            .ok_or_else(|| format!("`{}` cannot be set", name))?;
+        let text = args.get("value").as_str().unwrap_or("").trim();
+        let value = match text.strip_prefix("0x").or_else(|| text.strip_prefix('#')) {
+            Some(_) => debugger::number(text)?,
+            None => text.parse().map_err(|_| format!("bad number `{}`", text))?,
+        };
+        register.set(&mut self.cpu, value)?;
+        Ok(Json::object(vec![("value", Json::from(match register {
+            Register::Dt | Register::St | Register::Sp => value.to_string(),
+            _ => format!("0x{:X}", value),
+        }))]))
+    }
+
+    fn read_memory(&self, args : &Json) -> Result<Json, String> {
+        let start = memory_address(args)?;
+        let count = args.get("count").as_i64().unwrap_or(0).max(0) as usize;
+
+        let memory = &self.cpu.memory;
+        let from = start.clamp(0, memory.len() as i64) as usize;
+        let to = start.saturating_add(count as i64).clamp(0, memory.len() as i64) as usize;
+        Ok(Json::object(vec![
+            ("address", Json::from(format!("0x{:X}", start.max(0)))),
+            ("data", Json::from(base64(&memory[from..to.max(from)]))),
+            ("unreadableBytes", Json::from(count - (to.max(from) - from))),
+        ]))
+    }
+
+    fn write_memory(&mut self, args : &Json) -> Result<Json, String> {
+        let start = memory_address(args)?;
+        let bytes = unbase64(args.get("data").as_str().unwrap_or(""))
+            .ok_or_else(|| String::from("data is not base64"))?;
+        if start < 0 || start as usize + bytes.len() > self.cpu.memory.len() {
+            return Err(String::from("the memory is not that large"));
+        }
+        let start = start as usize;
+        self.cpu.memory[start..start + bytes.len()].copy_from_slice(&bytes);
+        Ok(Json::object(vec![("bytesWritten", Json::from(bytes.len()))]))
+    }
+
+    fn disassemble(&self, args : &Json) -> Result<Json, String> {
+        // Instructions are taken as 2 bytes going back, only going on
+        // from the address can the 4 byte ones be told apart.
+
+        let cpu = &self.cpu;
+        // No more instructions than the memory holds.
+        let count = args.get("instructionCount").as_i64().unwrap_or(0)
+            .clamp(0, cpu.memory.len() as i64 / 2);
+        let offset = args.get("instructionOffset").as_i64().unwrap_or(0);
+        let mut addr = memory_address(args)?.saturating_add(offset.saturating_mul(2));
+
+        let mut instructions = Vec::new();
+        for _ in 0..count {
+            if addr < 0 || addr as usize + 1 >= cpu.memory.len() {
+                instructions.push(Json::object(vec![
+                    ("address", Json::from(format!("0x{:X}", addr.max(0)))),
+                    ("instruction", Json::from("")),
+                    ("presentationHint", Json::from("invalid")),
+                ]));
+                addr = addr.saturating_add(2);
+                continue;
+            }
+
+            let at = addr as u16;
+            let size = cpu.instruction_at(at).size() as usize;
+            let end = (at as usize + size).min(cpu.memory.len());
+            let bytes : Vec<String> = cpu.memory[at as usize..end].iter()
+                .map(|b| format!("{:02X}", b))
+                .collect();
+
+            let mut instruction = vec![
+                ("address", reference(at)),
+                ("instructionBytes", Json::from(bytes.join(" "))),
+                ("instruction", Json::from(debugger::mnemonic(cpu, at))),
+            ];
+            if let Some(source) = self.source_line(at) {
+                instruction.push(("location", source_json(&source.file)));
+                instruction.push(("line", Json::from(source.line)));
+            }
+            if let Some(label) = self.label_at(at) {
+                instruction.push(("symbol", Json::from(label)));
+            }
+            instructions.push(Json::object(instruction));
+            addr = addr.saturating_add(size as i64);
+        }
+        Ok(Json::object(vec![("instructions", Json::from(instructions))]))
+    } // fn disassemble
+
+    fn evaluate(&mut self, args : &Json) -> Result<Json, String> {
+        // Registers evaluate to their value, and the console goes on to
+        // the prompt. A command that runs ends in a stop like any step.
+
+        let expression = args.get("expression").as_str().unwrap_or("").trim();
+        let result = if let Some(register) = Register::from_name(expression) {
+            format!("0x{:X}", register.get(&self.cpu))
+        } else if args.get("context").as_str() == Some("repl") {
+            let (pc, instructions) = (self.cpu.pc, self.cpu.instructions);
+            let out = self.debugger.command(&mut self.cpu, expression)?;
+            if self.cpu.exited {
+                self.stopped(Stop::Exited, "step");
+            } else if self.cpu.pc != pc || self.cpu.instructions != instructions {
+                self.stopped(Stop::Done, "step");
+            }
+            out.trim_end().to_string()
+        } else {
+            return Err(format!("`{}` is not a register", expression));
+        };
+
+        Ok(Json::object(vec![
+            ("result", Json::from(result)),
+            ("variablesReference", Json::from(0u8)),
+        ]))
+    }
+
+    fn find_line(&self, path : &str, line : usize) -> Option<SourceLine> {
+        let program = self.program.as_ref()?;
+        program.lines.iter()
+            .filter(|source| source.line >= line && same_file(&source.file, path))
+            .min_by_key(|source| (source.line, source.addr))
+            .cloned()
+    }
+
+    fn source_line(&self, addr : u16) -> Option<&SourceLine> {
+        let program = self.program.as_ref()?;
+        program.lines.iter()
+            .find(|source| addr >= source.addr && (addr as usize) < source.addr as usize + source.len)
+    }
+
+    fn label_at(&self, addr : u16) -> Option<String> {
+        let program = self.program.as_ref()?;
+        program.labels.iter().find(|&(_, &a)| a == addr).map(|(name, _)| name.clone())
+    }
+
+    fn frame_name(&self, addr : u16) -> String {
+        // The closest label before addr, e.g. `draw+6`.
+        let label = self.program.as_ref().and_then(|program| {
+            program.labels.iter()
+                .filter(|&(_, &a)| a <= addr)
+                .max_by_key(|&(_, &a)| a)
+        });
+        match label {
+            Some((name, &a)) if a == addr => name.clone(),
+            Some((name, &a)) => format!("{}+{}", name, addr - a),
+            None => format!("{:03X}", addr),
+        }
+    }
+}
+
+fn capabilities() -> Json {
+    let yes = |name| (name, Json::from(true));
+    Json::object(vec![
+        yes("supportsConfigurationDoneRequest"),
+        yes("supportsConditionalBreakpoints"),
+        yes("supportsInstructionBreakpoints"),
+        yes("supportsDisassembleRequest"),
+        yes("supportsReadMemoryRequest"),
+        yes("supportsWriteMemoryRequest"),
+        yes("supportsSetVariable"),
+        yes("supportsTerminateRequest"),
+    ])
+}
+
+fn scopes() -> Json {
+    let scope = |name : &str, reference : u8| Json::object(vec![
+        ("name", Json::from(name)),
+        ("variablesReference", Json::from(reference)),
+        ("expensive", Json::from(false)),
+    ]);
+    Json::object(vec![("scopes", Json::from(vec![
+        scope("Registers", REGISTERS),
+        scope("Timers", TIMERS),
+        scope("Stack", STACK),
+    ]))])
+}
+
+fn variable(register : Register, value : String, memory : Option<u16>) -> Json {
+    let mut fields = vec![
+        ("name", Json::from(register.to_string())),
+        ("value", Json::from(value)),
+        ("variablesReference", Json::from(0u8)),
+    ];
+    if let Some(addr) = memory {
+        fields.push(("memoryReference", reference(addr)));
+    }
+    Json::object(fields)
+}
+
+fn condition(breakpoint : &Json) -> Result<Option<Condition>, String> {
+    match breakpoint.get("condition").as_str().map(str::trim) {
+        None | Some("") => Ok(None),
+        Some(text) => Condition::parse(text).map(Some),
+    }
+}
+
+fn unverified(message : &str) -> Json {
+    Json::object(vec![("verified", Json::from(false)), ("message", Json::from(message))])
+}
+
+fn reference(addr : u16) -> Json {
+    Json::from(format!("0x{:03X}", addr))
+}
+
+fn memory_address(args : &Json) -> Result<i64, String> {
+    let reference = args.get("memoryReference").as_str()
+        .ok_or_else(|| String::from("no memoryReference"))?;
+    let addr = debugger::number(reference)? as i64;
+    Ok(addr.saturating_add(args.get("offset").as_i64().unwrap_or(0)))
+}
+
+fn source_json(path : &str) -> Json {
+    let name = path.rsplit(['/', '\\']).next().unwrap_or(path);
+    let path = fs::canonicalize(path).map(|p| p.display().to_string())
+        .unwrap_or_else(|_| path.to_string());
+    Json::object(vec![("name", Json::from(name)), ("path", Json::from(path))])
+}
+
+fn same_file(a : &str, b : &str) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+const BASE64 : &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64(bytes : &[u8]) -> String {
+    let mut out = String::new();
+    for chunk in bytes.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64[(n >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+fn unbase64(text : &str) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let (mut bits, mut n) = (0, 0u32);
+    for c in text.bytes().filter(|&c| c != b'=' && !c.is_ascii_whitespace()) {
+        let value = BASE64.iter().position(|&b| b == c)? as u32;
+        n = n << 6 | value;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((n >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+fn read_message<R : BufRead>(input : &mut R) -> io::Result<Option<String>> {
+    // Headers up to an empty line, then Content-Length bytes of JSON.
+
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() && length.is_some() {
+            break;
+        }
+        if let Some(value) = line.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+
+    let mut body = vec![0; length.unwrap_or(0)];
+    input.read_exact(&mut body)?;
+    Ok(Some(String::from_utf8_lossy(&body).into_owned()))
+}
+
+fn write_message<W : Write>(output : &mut W, message : &Json) -> io::Result<()> {
+    let text = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", text.len(), text)?;
+    output.flush()
+}
+
+// Serve one client until it disconnects.
+pub fn serve<R, W>(input : R, mut output : W) -> io::Result<()>
+    where R : Read + Send + 'static, W : Write {
+    let (sender, requests) = mpsc::channel();
+    thread::spawn(move || {
+        let mut input = BufReader::new(input);
+        while let Ok(Some(text)) = read_message(&mut input) {
+            if sender.send(text).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut session = Session::new();
+    while !session.closed {
+        // While the program runs it goes on between requests.
+        let text = if session.running() {
+            match requests.try_recv() {
+                Ok(text) => Some(text),
+                Err(mpsc::TryRecvError::Empty) => None,
+                Err(mpsc::TryRecvError::Disconnected) => break,
+            }
+        } else {
+            match requests.recv() {
+                Ok(text) => Some(text),
+                Err(_) => break,
+            }
+        };
+
+        let messages = match text.map(|text| Json::parse(&text)) {
+            None => session.resume(),
+            Some(Ok(request)) => session.handle(&request),
+            Some(Err(e)) => session.reject(&e),
+        };
+        for message in &messages {
+            write_message(&mut output, message)?;
+        }
+    }
+    Ok(())
+} // fn serve
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(session : &mut Session, command : &str, arguments : Json) -> Vec<Json> {
+        session.handle(&Json::object(vec![
+            ("seq", Json::from(1u8)),
+            ("type", Json::from("request")),
+            ("command", Json::from(command)),
+            ("arguments", arguments),
+        ]))
+    }
+
+    #[test]
+    fn encodes_memory_as_base64() {
+        assert_eq!(base64(b"CHIP-8"), "Q0hJUC04");
+        assert_eq!(base64(b"\x12\x00"), "EgA=");
+        assert_eq!(unbase64("EgA=").unwrap(), b"\x12\x00");
+    }
+
+    #[test]
+    fn stops_at_source_breakpoints() {
+        let path = ::std::env::temp_dir()
+            .join(format!("chip8-dap-{}-stops_at_source_breakpoints.8o", ::std::process::id()))
+            .display().to_string();
+        fs::write(&path, ": main\n  v0 := 1\n\n  v1 += v0\n  loop again\n").unwrap();
+
+        // The source is only read while launching.
+        let mut session = Session::new();
+        let launched = request(&mut session, "launch", Json::object(vec![
+            ("program", Json::from(path.as_str())),
+        ]));
+        fs::remove_file(&path).unwrap();
+        assert_eq!(launched[0].get("success").as_bool(), Some(true));
+        assert_eq!(launched[1].get("event").as_str(), Some("initialized"));
+
+        // Line 3 is empty, the breakpoint moves to the ADD on line 4.
+        let set = request(&mut session, "setBreakpoints", Json::object(vec![
+            ("source", Json::object(vec![("path", Json::from(path.as_str()))])),
+            ("breakpoints", Json::from(vec![Json::object(vec![("line", Json::from(3u8))])])),
+        ]));
+        let breakpoint = &set[0].get("body").get("breakpoints").as_array()[0];
+        assert_eq!(breakpoint.get("verified").as_bool(), Some(true));
+        assert_eq!(breakpoint.get("line").as_i64(), Some(4));
+
+        request(&mut session, "configurationDone", Json::Null);
+        assert!(session.running());
+        let events = session.resume();
+        assert_eq!(events[0].get("body").get("reason").as_str(), Some("breakpoint"));
+        assert_eq!(session.cpu.pc, 0x204);
+
+        let trace = request(&mut session, "stackTrace", Json::Null);
+        let frame = &trace[0].get("body").get("stackFrames").as_array()[0];
+        assert_eq!(frame.get("name").as_str(), Some("main+2"));
+        assert_eq!(frame.get("line").as_i64(), Some(4));
+    }
+
+    #[test]
+    fn shows_registers_and_memory() {
+        let mut session = Session::new();
+        session.cpu.load_rom_bytes(&[0x60, 0x2A, 0x12, 0x02]).unwrap();
+        request(&mut session, "stepIn", Json::Null);
+
+        let variables = request(&mut session, "variables", Json::object(vec![
+            ("variablesReference", Json::from(REGISTERS)),
+        ]));
+        let variables = variables[0].get("body").get("variables").as_array();
+        assert_eq!(variables[0].get("value").as_str(), Some("0x2A"));
+        assert_eq!(variables[17].get("value").as_str(), Some("0x202"));
+
+        let written = request(&mut session, "writeMemory", Json::object(vec![
+            ("memoryReference", Json::from("0x300")),
+            ("data", Json::from("q80=")),
+        ]));
+        assert_eq!(written[0].get("body").get("bytesWritten").as_i64(), Some(2));
+        let read = request(&mut session, "readMemory", Json::object(vec![
+            ("memoryReference", Json::from("0x2FF")),
+            ("count", Json::from(4u8)),
+        ]));
+        assert_eq!(read[0].get("body").get("data").as_str(), Some("AKvNAA=="));
+
+        // Client values far out of range are clamped, not overflowed.
+        let read = request(&mut session, "readMemory", Json::object(vec![
+            ("memoryReference", Json::from("0x2FF")),
+            ("offset", Json::from(i64::MAX)),
+            ("count", Json::from(i64::MAX)),
+        ]));
+        assert_eq!(read[0].get("body").get("data").as_str(), Some(""));
+        let listing = request(&mut session, "disassemble", Json::object(vec![
+            ("memoryReference", Json::from("0x200")),
+            ("instructionOffset", Json::from(i64::MIN)),
+            ("instructionCount", Json::from(i64::MAX)),
+        ]));
+        let instructions = listing[0].get("body").get("instructions").as_array();
+        assert_eq!(instructions.len(), session.cpu.memory.len() / 2);
+        let listing = request(&mut session, "disassemble", Json::object(vec![
+            ("memoryReference", Json::from("0x200")),
+            ("instructionOffset", Json::from(i64::MAX)),
+            ("instructionCount", Json::from(3u8)),
+        ]));
+        let instructions = listing[0].get("body").get("instructions").as_array();
+        assert_eq!(instructions.len(), 3);
+        assert_eq!(instructions[2].get("presentationHint").as_str(), Some("invalid"));
+
+        // F000 is two bytes of nothing before XO-CHIP.
+        session.cpu.memory[0x300..0x304].copy_from_slice(&[0xF0, 0x00, 0x12, 0x34]);
+        let listing = request(&mut session, "disassemble", Json::object(vec![
+            ("memoryReference", Json::from("0x300")),
+            ("instructionCount", Json::from(2u8)),
+        ]));
+        let instructions = listing[0].get("body").get("instructions").as_array();
+        assert_eq!(instructions[1].get("address").as_str(), Some("0x302"));
+
+        let unknown = request(&mut session, "restart", Json::Null);
+        assert_eq!(unknown[0].get("success").as_bool(), Some(false));
+    }
+}
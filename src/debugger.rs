@@ -0,0 +1,853 @@
+use std::fmt;
+use std::fmt::Write as FmtWrite;
+use cpu::{Access, AccessKind, Cpu, Fault, Instruction, Speed, STACK_SIZE};
+use headless;
+
+/*
+ * A debugger around the Cpu. It keeps breakpoints and runs the Cpu one
+ * instruction at a time until one of them is hit or what was asked for
+ * is done: a single step, stepping over a CALL, stepping out of a
+ * subroutine or running to an address. Frames are closed whenever the
+ * scheduler's share of instructions has run, so the timers keep the
+ * speed they have without the debugger.
+ *
+ * Watchpoints stop after an instruction that read, wrote or executed
+ * memory in their range. The debugger also remembers which addresses
+ * have run as code and by default stops when one of them is written,
+ * which is how a stray Fx55 or Fx33 over the program shows up.
+ *
+ * Everything can be used from code, or through `command` with the
+ * same commands a prompt offers (see HELP). Addresses and values are
+ * hex there, with or without a 0x or # in front.
+ */
+
+pub const HELP : &str = "\
+step [N]           s   run N instructions (default 1)
+next               n   run one instruction, a CALL up to its return
+finish             f   run until the current subroutine returns
+until ADDR         u   run until pc is ADDR
+continue           c   run until a breakpoint is hit
+break ADDR [if C]  b   stop at ADDR, if given only when C holds, e.g. `v3 == 5`
+                       (==, !=, <, <=, >, >=)
+delete [ID]        d   remove a breakpoint or watchpoint, or all of them
+watch [rwx] RANGE  w   stop on reads, writes (default) or execution of an address
+                       or a range like 300-30F
+smc on|off             stop when code that has run is overwritten (default on)
+breakpoints        bl  list the breakpoints and watchpoints
+regs               r   show the registers and the stack
+set REG VALUE          change v0-vf, i, pc, sp, dt, st or stack[N]
+mem ADDR [LEN]     x   show LEN bytes of memory (default 40)
+poke ADDR BYTE...      change memory
+list [ADDR] [N]    l   disassemble N instructions (default 8) from ADDR or pc
+key KEY down|up        press or release a key
+screen                 show the display
+reset                  start the program over
+help               h   show this help
+quit               q   leave the debugger
+";
+
+// A run gives up after this many instructions, so a program waiting
+// for a key or looping forever cannot hang the debugger.
+pub const DEFAULT_LIMIT : u64 = 10_000_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Register {
+    V(u8),
+    I,
+    Pc,
+    Sp,
+    Dt,
+    St,
+    Stack(usize),
+}
+
+impl Register {
+    pub fn from_name(name : &str) -> Option<Register> {
+        let name = name.to_lowercase();
+        match name.as_str() {
+            "i" => return Some(Register::I),
+            "pc" => return Some(Register::Pc),
+            "sp" => return Some(Register::Sp),
+            "dt" => return Some(Register::Dt),
+            "st" => return Some(Register::St),
+            _ => (),
+        }
+
+        if let Some(index) = name.strip_prefix("stack[").and_then(|n| n.strip_suffix(']')) {
+            return match index.parse() {
+                Ok(n) if n < STACK_SIZE => Some(Register::Stack(n)),
+                _ => None,
+            };
+        }
+        let bytes = name.as_bytes();
+        if bytes.len() == 2 && bytes[0] == b'v' {
+            return (bytes[1] as char).to_digit(16).map(|d| Register::V(d as u8));
+        }
+        None
+    } // fn from_name
+
+    pub fn get(self, cpu : &Cpu) -> u16 {
+        match self {
+            Register::V(x) => cpu.v_regs[x as usize] as u16,
+            Register::I => cpu.i_reg,
+            Register::Pc => cpu.pc,
+            Register::Sp => cpu.sp,
+            Register::Dt => cpu.dt as u16,
+            Register::St => cpu.st as u16,
+            Register::Stack(n) => cpu.stack[n],
+        }
+    }
+
+    pub fn set(self, cpu : &mut Cpu, value : u16) -> Result<(), String> {
+        let byte = || if value <= 0xFF {
+            Ok(value as u8)
+        } else {
+            Err(format!("{} holds a byte, {:X} is too large", self, value))
+        };
+        // The stack is as deep as the quirks allow, see Quirks::stack_depth.
+        let depth = cpu.quirks.stack_depth.min(STACK_SIZE);
+
+        match self {
+            Register::V(x) => cpu.v_regs[x as usize] = byte()?,
+            Register::Dt => cpu.dt = byte()?,
+            Register::St => cpu.st = byte()?,
+            Register::I => cpu.i_reg = value,
+            Register::Pc if value as usize >= cpu.memory.len() =>
+                return Err(format!("{:X} is past the end of memory", value)),
+            Register::Pc => cpu.pc = value,
+            Register::Sp if value as usize > depth =>
+                return Err(format!("the stack holds {} addresses", depth)),
+            Register::Sp => cpu.sp = value,
+            Register::Stack(n) => cpu.stack[n] = value,
+        }
+        Ok(())
+    } // fn set
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Register::V(x) => write!(f, "V{:X}", x),
+            Register::I => write!(f, "I"),
+            Register::Pc => write!(f, "PC"),
+            Register::Sp => write!(f, "SP"),
+            Register::Dt => write!(f, "DT"),
+            Register::St => write!(f, "ST"),
+            Register::Stack(n) => write!(f, "STACK[{}]", n),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Comparison {
+    const ALL : [(&'static str, Comparison); 6] = [
+        ("==", Comparison::Eq),
+        ("!=", Comparison::Ne),
+        ("<=", Comparison::Le),
+        (">=", Comparison::Ge),
+        ("<", Comparison::Lt),
+        (">", Comparison::Gt)];
+
+    pub fn symbol(self) -> &'static str {
+        Comparison::ALL.iter()
+            .find(|&&(_, c)| c == self)
+            .map(|&(s, _)| s)
+            .unwrap_or("==")
+    }
+
+    pub fn test(self, left : u16, right : u16) -> bool {
+        match self {
+            Comparison::Eq => left == right,
+            Comparison::Ne => left != right,
+            Comparison::Lt => left < right,
+            Comparison::Le => left <= right,
+            Comparison::Gt => left > right,
+            Comparison::Ge => left >= right,
+        }
+    }
+}
+
+// When a breakpoint stops: a register compared with a value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Condition {
+    pub register : Register,
+    pub comparison : Comparison,
+    pub value : u16,
+}
+
+impl Condition {
+    // Read a condition like `v3 == 5` or `i>=300`, the value in hex.
+    pub fn parse(text : &str) -> Result<Condition, String> {
+        let (at, symbol, comparison) = Comparison::ALL.iter()
+            .filter_map(|&(s, c)| text.find(s).map(|at| (at, s, c)))
+            .min_by_key(|&(at, s, _)| (at, usize::MAX - s.len()))
+            .ok_or_else(|| format!("no comparison in `{}`", text))?;
+
+        let name = text[..at].trim();
+        let register = Register::from_name(name)
+            .ok_or_else(|| format!("unknown register `{}`", name))?;
+        let value = number(text[at + symbol.len()..].trim())?;
+        Ok(Condition { register, comparison, value })
+    }
+
+    pub fn holds(&self, cpu : &Cpu) -> bool {
+        self.comparison.test(self.register.get(cpu), self.value)
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} {:X}", self.register, self.comparison.symbol(), self.value)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Breakpoint {
+    pub id : usize,
+    pub addr : u16,
+    pub condition : Option<Condition>,
+    pub hits : u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub id : usize,
+    pub start : u16,
+    pub end : u16,          // Inclusive
+    pub read : bool,
+    pub write : bool,
+    pub execute : bool,
+    pub hits : u64,
+}
+
+impl Watchpoint {
+    pub fn matches(&self, access : &Access) -> bool {
+        let kind = match access.kind {
+            AccessKind::Read => self.read,
+            AccessKind::Write => self.write,
+            AccessKind::Execute => self.execute,
+        };
+        kind && access.addr >= self.start as usize && access.addr <= self.end as usize
+    }
+
+    fn kinds(&self) -> String {
+        [(self.read, 'r'), (self.write, 'w'), (self.execute, 'x')].iter()
+            .filter(|&&(on, _)| on)
+            .map(|&(_, c)| c)
+            .collect()
+    }
+}
+
+// Why a run stopped. Watchpoints stop after the access, pc is where
+// the instruction that made it is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stop {
+    Done,                   // What was asked for is done
+    Breakpoint(usize),      // The id of the breakpoint hit
+    Watchpoint { id : usize, pc : u16, access : Access },
+    SelfModify { pc : u16, access : Access },   // Code that has run was written
+    Exited,                 // The program ran 00FD
+    Fault(Fault),
+    Limit,                  // The run took `limit` instructions
+}
+
+#[derive(Clone, Copy)]
+enum Goal {
+    Steps(u64),
+    Return { addr : u16, sp : u16 },    // Back from a CALL
+    StepOut,                            // Until sp drops
+    Address(u16),
+    Breakpoint,
+    Frame(u64),                         // Until this frame has ended
+}
+
+pub struct Debugger {
+    pub breakpoints : Vec<Breakpoint>,
+    pub watchpoints : Vec<Watchpoint>,
+    pub stop_on_self_modify : bool,
+    pub limit : u64,                    // Instructions a run may take
+    next_id : usize,                    // Shared by break- and watchpoints
+    budget : u32,                       // Instructions left in this frame
+    executed : Vec<bool>,               // Addresses that have run as code
+}
+
+impl Default for Debugger {
+    fn default() -> Debugger {
+        Debugger::new()
+    }
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger {
+            breakpoints : Vec::new(),
+            watchpoints : Vec::new(),
+            stop_on_self_modify : true,
+            limit : DEFAULT_LIMIT,
+            next_id : 1,
+            budget : 0,
+            executed : Vec::new(),
+        }
+    }
+
+    pub fn add_breakpoint(&mut self, addr : u16, condition : Option<Condition>) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.breakpoints.push(Breakpoint { id, addr, condition, hits : 0 });
+        id
+    }
+
+    pub fn add_watchpoint(&mut self, start : u16, end : u16,
+                          read : bool, write : bool, execute : bool) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.watchpoints.push(Watchpoint { id, start, end, read, write, execute, hits : 0 });
+        id
+    }
+
+    // Remove the breakpoint or watchpoint with this id.
+    pub fn remove_breakpoint(&mut self, id : usize) -> bool {
+        let count = self.breakpoints.len() + self.watchpoints.len();
+        self.breakpoints.retain(|b| b.id != id);
+        self.watchpoints.retain(|w| w.id != id);
+        self.breakpoints.len() + self.watchpoints.len() != count
+    }
+
+    // Forget which addresses have run as code, e.g. after a reset.
+    pub fn forget_executed(&mut self) {
+        self.executed.clear();
+    }
+
+    // Run n instructions, none for n = 0.
+    pub fn step(&mut self, cpu : &mut Cpu, n : u64) -> Stop {
+        if n == 0 {
+            return Stop::Done;
+        }
+        self.run(cpu, Goal::Steps(n))
+    }
+
+    // Run one instruction, or a whole subroutine if it is a CALL.
+    pub fn step_over(&mut self, cpu : &mut Cpu) -> Stop {
+        let instruction = cpu.instruction_at(cpu.pc);
+        match instruction {
+            Instruction::Call(_) => {
+                let goal = Goal::Return { addr : cpu.pc.wrapping_add(instruction.size()),
+                                          sp : cpu.sp };
+                self.run(cpu, goal)
+            },
+            _ => self.run(cpu, Goal::Steps(1)),
+        }
+    }
+
+    // Run until the current subroutine returns. Outside of one this is
+    // the same as cont.
+    pub fn step_out(&mut self, cpu : &mut Cpu) -> Stop {
+        self.run(cpu, Goal::StepOut)
+    }
+
+    pub fn run_to(&mut self, cpu : &mut Cpu, addr : u16) -> Stop {
+        self.run(cpu, Goal::Address(addr))
+    }
+
+    // Run until a breakpoint is hit.
+    pub fn cont(&mut self, cpu : &mut Cpu) -> Stop {
+        self.run(cpu, Goal::Breakpoint)
+    }
+
+    // Run until the current frame ends, for frontends that draw every
+    // frame.
+    pub fn run_frame(&mut self, cpu : &mut Cpu) -> Stop {
+        self.run(cpu, Goal::Frame(cpu.frames))
+    }
+
+    fn run(&mut self, cpu : &mut Cpu, goal : Goal) -> Stop {
+        // The instruction at pc always runs, so a run can leave the
+        // breakpoint it stopped at. Before every further one the goal
+        // and then the breakpoints are checked.
+
+        let sp = cpu.sp;
+        let mut count = 0;
+
+        loop {
+            if count > 0 {
+                let done = match goal {
+                    Goal::Steps(n) => count >= n,
+                    Goal::Return { addr, sp } => cpu.pc == addr && cpu.sp == sp,
+                    Goal::StepOut => cpu.sp < sp,
+                    Goal::Address(addr) => cpu.pc == addr,
+                    Goal::Breakpoint => false,
+                    Goal::Frame(frames) => cpu.frames != frames,
+                };
+                if done {
+                    return Stop::Done;
+                }
+                if let Some(id) = self.breakpoint_hit(cpu) {
+                    return Stop::Breakpoint(id);
+                }
+            }
+            if cpu.exited {
+                return Stop::Exited;
+            }
+            if count >= self.limit {
+                return Stop::Limit;
+            }
+            let pc = cpu.pc;
+            match self.execute(cpu) {
+                Ok(true) => (),
+                Ok(false) => return Stop::Done,
+                Err(fault) => return Stop::Fault(fault),
+            }
+            count += 1;
+            if let Some(stop) = self.watch(cpu, pc) {
+                return stop;
+            }
+        }
+    } // fn run
+
+    fn watch(&mut self, cpu : &Cpu, pc : u16) -> Option<Stop> {
+        // Go through the memory accesses of the instruction at pc, its
+        // own fetch first, so an instruction overwriting itself counts.
+
+        if self.executed.len() != cpu.memory.len() {
+            self.executed = vec![false; cpu.memory.len()];
+        }
+
+        let mut stop = None;
+        for &access in cpu.accesses() {
+            match access.kind {
+                AccessKind::Execute => self.executed[access.addr] = true,
+                AccessKind::Write if self.stop_on_self_modify && self.executed[access.addr] => {
+                    stop = stop.or(Some(Stop::SelfModify { pc, access }));
+                },
+                _ => (),
+            }
+            if stop.is_some() {
+                continue;
+            }
+            if let Some(w) = self.watchpoints.iter_mut().find(|w| w.matches(&access)) {
+                w.hits += 1;
+                stop = Some(Stop::Watchpoint { id : w.id, pc, access });
+            }
+        }
+        stop
+    } // fn watch
+
+    fn breakpoint_hit(&mut self, cpu : &Cpu) -> Option<usize> {
+        let breakpoint = self.breakpoints.iter_mut()
+            .find(|b| b.addr == cpu.pc && b.condition.is_none_or(|c| c.holds(cpu)))?;
+        breakpoint.hits += 1;
+        Some(breakpoint.id)
+    }
+
+    fn execute(&mut self, cpu : &mut Cpu) -> Result<bool, Fault> {
+        // One instruction, closing the frame when its share has run.
+        // At a speed of 0 nothing ever runs, a single empty frame passes
+        // and false is returned.
+
+        if let Speed::InstructionsPerFrame(0) | Speed::Hz(0) = cpu.scheduler.speed {
+            cpu.end_frame();
+            return Ok(false);
+        }
+        while self.budget == 0 {
+            self.budget = cpu.scheduler.next_frame();
+            if self.budget == 0 {
+                cpu.end_frame();
+            }
+        }
+
+        cpu.log_accesses(self.stop_on_self_modify || !self.watchpoints.is_empty());
+        cpu.step()?;
+        self.budget -= 1;
+        if self.budget == 0 {
+            cpu.end_frame();
+        }
+        Ok(true)
+    }
+
+    // Carry out a prompt command and return what it prints.
+    pub fn command(&mut self, cpu : &mut Cpu, line : &str) -> Result<String, String> {
+        let words : Vec<&str> = line.split_whitespace().collect();
+        let (name, args) = match words.split_first() {
+            Some((name, args)) => (*name, args),
+            None => return Ok(String::new()),
+        };
+
+        let stop = match (name, args.len()) {
+            ("step", _) | ("s", _) => {
+                let n = match args.first() {
+                    Some(n) => match n.parse() {
+                        Ok(0) | Err(_) => return Err(format!("bad count `{}`", n)),
+                        Ok(n) => n,
+                    },
+                    None => 1,
+                };
+                self.step(cpu, n)
+            },
+            ("next", 0) | ("n", 0) => self.step_over(cpu),
+            ("finish", 0) | ("f", 0) => {
+                if cpu.sp == 0 {
+                    return Err(String::from("not in a subroutine"));
+                }
+                self.step_out(cpu)
+            },
+            ("until", 1) | ("u", 1) => {
+                let addr = number(args[0])?;
+                self.run_to(cpu, addr)
+            },
+            ("continue", 0) | ("c", 0) => self.cont(cpu),
+            _ => return self.inspect(cpu, name, args),
+        };
+        Ok(self.report(cpu, stop))
+    } // fn command
+
+    fn inspect(&mut self, cpu : &mut Cpu, name : &str, args : &[&str])
+               -> Result<String, String> {
+        // The commands that do not run the program.
+
+        let mut out = String::new();
+        match (name, args.len()) {
+            ("break", n) | ("b", n) if n > 0 => {
+                let addr = number(args[0])?;
+                let condition = match args.get(1) {
+                    None => None,
+                    Some(&"if") if n > 2 => Some(Condition::parse(&args[2..].join(" "))?),
+                    Some(_) => return Err(String::from("expected `break ADDR [if CONDITION]`")),
+                };
+                let id = self.add_breakpoint(addr, condition);
+                let _ = writeln!(out, "breakpoint {} at {:03X}", id, addr);
+            },
+            ("watch", 1) | ("watch", 2) | ("w", 1) | ("w", 2) => {
+                let (kinds, range) = match args {
+                    [kinds, range] => (*kinds, *range),
+                    _ => ("w", args[0]),
+                };
+                let (read, write, execute) = (kinds.contains('r'), kinds.contains('w'),
+                                              kinds.contains('x'));
+                if kinds.is_empty() || kinds.contains(|c| !"rwx".contains(c)) {
+                    return Err(format!("bad watch kinds `{}`, expected r, w and x", kinds));
+                }
+                let (start, end) = match range.split_once('-') {
+                    Some((start, end)) => (number(start)?, number(end)?),
+                    None => (number(range)?, number(range)?),
+                };
+                if start > end {
+                    return Err(format!("bad range `{}`", range));
+                }
+                let id = self.add_watchpoint(start, end, read, write, execute);
+                let _ = writeln!(out, "watchpoint {} on {:03X}-{:03X} ({})",
+                                 id, start, end, kinds);
+            },
+            ("smc", 1) => {
+                self.stop_on_self_modify = match args[0] {
+                    "on" => true,
+                    "off" => false,
+                    other => return Err(format!("expected on or off, got `{}`", other)),
+                };
+            },
+            ("delete", 0) | ("d", 0) => {
+                self.breakpoints.clear();
+                self.watchpoints.clear();
+            },
+            ("delete", 1) | ("d", 1) => {
+                let id = args[0].parse().map_err(|_| format!("bad breakpoint `{}`", args[0]))?;
+                if !self.remove_breakpoint(id) {
+                    return Err(format!("no breakpoint {}", id));
+                }
+            },
+            ("breakpoints", 0) | ("bl", 0) => {
+                for b in &self.breakpoints {
+                    let _ = write!(out, "{:>3}  {:03X}  hit {}", b.id, b.addr, b.hits);
+                    if let Some(condition) = b.condition {
+                        let _ = write!(out, "  if {}", condition);
+                    }
+                    out.push('\n');
+                }
+                for w in &self.watchpoints {
+                    let _ = writeln!(out, "{:>3}  {:03X}-{:03X}  hit {}  {}",
+                                     w.id, w.start, w.end, w.hits, w.kinds());
+                }
+            },
+            ("regs", 0) | ("r", 0) => out = headless::registers(cpu),
+            ("set", 2) => {
+                let register = Register::from_name(args[0])
+                    .ok_or_else(|| format!("unknown register `{}`", args[0]))?;
+                register.set(cpu, number(args[1])?)?;
+            },
+            ("mem", 1) | ("mem", 2) | ("x", 1) | ("x", 2) => {
+                let start = (number(args[0])? as usize).min(cpu.memory.len());
+                let len = match args.get(1) {
+                    Some(len) => number(len)? as usize,
+                    None => 0x40,
+                };
+                let end = (start + len).min(cpu.memory.len());
+                out = headless::hex_dump(&cpu.memory[start..end], start);
+            },
+            ("poke", n) if n > 1 => {
+                let addr = number(args[0])? as usize;
+                let bytes = args[1..].iter()
+                    .map(|b| number(b).and_then(|b| if b <= 0xFF { Ok(b as u8) }
+                                                    else { Err(format!("{:X} is not a byte", b)) }))
+                    .collect::<Result<Vec<u8>, String>>()?;
+                if addr + bytes.len() > cpu.memory.len() {
+                    return Err(String::from("past the end of memory"));
+                }
+                cpu.memory[addr..addr + bytes.len()].copy_from_slice(&bytes);
+            },
+            ("list", n) | ("l", n) if n <= 2 => {
+                let mut addr = match args.first() {
+                    Some(addr) => number(addr)?,
+                    None => cpu.pc,
+                };
+                let count = match args.get(1) {
+                    Some(count) => count.parse().map_err(|_| format!("bad count `{}`", count))?,
+                    None => 8,
+                };
+                for _ in 0..count {
+                    let marker = if addr == cpu.pc { "=>" }
+                                 else if self.breakpoints.iter().any(|b| b.addr == addr) { " *" }
+                                 else { "  " };
+                    let _ = writeln!(out, "{} {}", marker, location(cpu, addr));
+                    addr = addr.wrapping_add(cpu.instruction_at(addr).size());
+                }
+            },
+            ("key", 2) => {
+                let key = match u8::from_str_radix(args[0], 16) {
+                    Ok(k) if k < 16 => k,
+                    _ => return Err(format!("bad key `{}`, expected 0-F", args[0])),
+                };
+                let pressed = match args[1] {
+                    "down" => true,
+                    "up" => false,
+                    other => return Err(format!("bad key state `{}`, expected down or up",
+                                                other)),
+                };
+                cpu.set_key(key as usize, pressed);
+            },
+            ("screen", 0) => out = headless::ascii_art(cpu),
+            ("reset", 0) => {
+                cpu.reset();
+                self.budget = 0;
+                self.forget_executed();
+                out = location(cpu, cpu.pc) + "\n";
+            },
+            ("help", 0) | ("h", 0) => out = String::from(HELP),
+            _ => return Err(format!("bad command `{}`, try help", args.iter()
+                                    .fold(name.to_string(), |l, a| l + " " + a))),
+        }
+        Ok(out)
+    } // fn inspect
+
+    // What a prompt shows after a run.
+    pub fn report(&self, cpu : &Cpu, stop : Stop) -> String {
+        let reason = match stop {
+            Stop::Done => String::new(),
+            Stop::Breakpoint(id) => format!("breakpoint {}\n", id),
+            Stop::Watchpoint { id, pc, access } =>
+                format!("watchpoint {}, {}\n  by {}\n", id, describe(&access), location(cpu, pc)),
+            Stop::SelfModify { pc, access } =>
+                format!("code overwritten, {}\n  by {}\n", describe(&access), location(cpu, pc)),
+            Stop::Exited => return String::from("the program has exited\n"),
+            Stop::Fault(fault) => format!("{}\n", fault),
+            Stop::Limit => format!("stopped after {} instructions\n", self.limit),
+        };
+        reason + &location(cpu, cpu.pc) + "\n"
+    }
+}
+
+// The instruction at addr, as `2A4  8014  ADD V0, V1`.
+pub fn location(cpu : &Cpu, addr : u16) -> String {
+    format!("{:03X}  {:04X}  {}", addr, opcode_at(cpu, addr), mnemonic(cpu, addr))
+}
+
+// Just the instruction at addr, with the address of a long LD I.
+pub fn mnemonic(cpu : &Cpu, addr : u16) -> String {
+    match cpu.instruction_at(addr) {
+        Instruction::LdILong => format!("LD I, LONG #{:04X}", opcode_at(cpu, addr.wrapping_add(2))),
+        instruction => instruction.to_string(),
+    }
+}
+
+fn describe(access : &Access) -> String {
+    match access.kind {
+        AccessKind::Read => format!("read {:03X} {:02X}", access.addr, access.new),
+        AccessKind::Write => format!("write {:03X} {:02X}>{:02X}",
+                                     access.addr, access.old, access.new),
+        AccessKind::Execute => format!("execute {:03X}", access.addr),
+    }
+}
+
+fn opcode_at(cpu : &Cpu, addr : u16) -> u16 {
+    let byte = |a : u16| cpu.memory.get(a as usize).copied().unwrap_or(0) as u16;
+    byte(addr) << 8 | byte(addr.wrapping_add(1))
+}
+
+// A hex number, with or without 0x or #.
+pub fn number(text : &str) -> Result<u16, String> {
+    let digits = text.strip_prefix("0x")
+        .or_else(|| text.strip_prefix('#'))
+        .unwrap_or(text);
+    u16::from_str_radix(digits, 16).map_err(|_| format!("bad hex number `{}`", text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpu::Mode;
+
+    fn cpu(rom : &[u8]) -> Cpu {
+        let mut cpu = Cpu::new();
+        cpu.load_rom_bytes(rom).unwrap();
+        cpu
+    }
+
+    // 200: CALL 206, 202: ADD V0, 1, 204: JP 202
+    // 206: ADD V1, 1, 208: RET
+    const ROM : [u8; 10] = [0x22, 0x06, 0x70, 0x01, 0x12, 0x02, 0x71, 0x01, 0x00, 0xEE];
+
+    #[test]
+    fn stops_at_breakpoints_when_their_condition_holds() {
+        let mut cpu = cpu(&ROM);
+        let mut debugger = Debugger::new();
+        let id = debugger.add_breakpoint(0x202, Some(Condition::parse("v0 == 3").unwrap()));
+
+        assert_eq!(debugger.cont(&mut cpu), Stop::Breakpoint(id));
+        assert_eq!((cpu.pc, cpu.v_regs[0]), (0x202, 3));
+        assert_eq!(debugger.breakpoints[0].hits, 1);
+
+        // Continuing leaves the breakpoint, which is not hit again.
+        debugger.limit = 100;
+        assert_eq!(debugger.cont(&mut cpu), Stop::Limit);
+    }
+
+    #[test]
+    fn steps_over_and_out_of_calls() {
+        let mut cpu = cpu(&ROM);
+        let mut debugger = Debugger::new();
+
+        assert_eq!(debugger.step_over(&mut cpu), Stop::Done);
+        assert_eq!((cpu.pc, cpu.sp, cpu.v_regs[1]), (0x202, 0, 1));
+
+        cpu.reset();
+        assert_eq!(debugger.step(&mut cpu, 2), Stop::Done);
+        assert_eq!((cpu.pc, cpu.sp), (0x208, 1));
+        assert_eq!(debugger.step_out(&mut cpu), Stop::Done);
+        assert_eq!((cpu.pc, cpu.sp), (0x202, 0));
+
+        assert_eq!(debugger.run_to(&mut cpu, 0x204), Stop::Done);
+        assert_eq!(cpu.v_regs[0], 1);
+    }
+
+    #[test]
+    fn runs_a_frame_up_to_a_breakpoint() {
+        // A frame runs 3 instructions, unless a breakpoint comes first.
+        let mut cpu = cpu(&ROM);
+        let mut debugger = Debugger::new();
+        cpu.scheduler.set_speed(Speed::InstructionsPerFrame(3));
+
+        assert_eq!(debugger.run_frame(&mut cpu), Stop::Done);
+        assert_eq!((cpu.frames, cpu.pc), (1, 0x202));
+        let id = debugger.add_breakpoint(0x204, None);
+        assert_eq!(debugger.run_frame(&mut cpu), Stop::Breakpoint(id));
+        assert_eq!((cpu.frames, cpu.v_regs[0]), (1, 1));
+    }
+
+    #[test]
+    fn runs_nothing_at_zero_speed_or_zero_steps() {
+        let mut cpu = cpu(&ROM);
+        let mut debugger = Debugger::new();
+        assert_eq!(debugger.step(&mut cpu, 0), Stop::Done);
+        assert_eq!(cpu.pc, 0x200);
+        assert!(debugger.command(&mut cpu, "step 0").is_err());
+
+        // Each run lets one empty frame pass.
+        cpu.scheduler.set_speed(Speed::Hz(0));
+        assert_eq!(debugger.cont(&mut cpu), Stop::Done);
+        assert_eq!(debugger.run_frame(&mut cpu), Stop::Done);
+        assert_eq!((cpu.frames, cpu.pc), (2, 0x200));
+    }
+
+    #[test]
+    fn sizes_f000_by_the_mode() {
+        // F000 is a long LD I with XO-CHIP and two bytes of nothing before.
+        let mut cpu = cpu(&[0xF0, 0x00, 0x12, 0x34, 0x00, 0xE0]);
+        let mut debugger = Debugger::new();
+        assert_eq!(debugger.command(&mut cpu, "list 200 2").unwrap(),
+                   "=> 200  F000  DW #F000\n   202  1234  JP #234\n");
+
+        cpu.mode = Mode::XoChip;
+        cpu.reset();
+        assert_eq!(debugger.command(&mut cpu, "list 200 2").unwrap(),
+                   "=> 200  F000  LD I, LONG #1234\n   204  00E0  CLS\n");
+    }
+
+    #[test]
+    fn commands_change_and_show_the_machine() {
+        let mut cpu = cpu(&ROM);
+        let mut debugger = Debugger::new();
+
+        debugger.command(&mut cpu, "set v3 2A").unwrap();
+        debugger.command(&mut cpu, "set pc 206").unwrap();
+        debugger.command(&mut cpu, "poke 300 12 34").unwrap();
+        assert_eq!((cpu.v_regs[3], cpu.pc), (0x2A, 0x206));
+        assert_eq!(&cpu.memory[0x300..0x302], &[0x12, 0x34]);
+
+        assert_eq!(debugger.command(&mut cpu, "step").unwrap(), "208  00EE  RET\n");
+        assert_eq!(debugger.command(&mut cpu, "break 202 if v1 >= 1").unwrap(),
+                   "breakpoint 1 at 202\n");
+        assert_eq!(debugger.command(&mut cpu, "set v3 100").unwrap_err(),
+                   "V3 holds a byte, 100 is too large");
+        assert!(debugger.command(&mut cpu, "finish").is_err());
+    }
+
+    #[test]
+    fn keeps_sp_inside_the_stack_depth() {
+        let mut cpu = cpu(&ROM);
+        cpu.quirks.stack_depth = 12;
+        assert!(Register::Sp.set(&mut cpu, 12).is_ok());
+        assert_eq!(Register::Sp.set(&mut cpu, 13).unwrap_err(), "the stack holds 12 addresses");
+
+        cpu.quirks.stack_depth = 100;
+        assert!(Register::Sp.set(&mut cpu, STACK_SIZE as u16).is_ok());
+        assert!(Register::Sp.set(&mut cpu, STACK_SIZE as u16 + 1).is_err());
+        assert_eq!(cpu.sp, STACK_SIZE as u16);
+    }
+
+    #[test]
+    fn watchpoints_report_the_access_and_its_instruction() {
+        // 200: LD I, 300, 202: LD V0, 5, 204: LD [I], V0, 206: JP 206
+        let mut cpu = cpu(&[0xA3, 0x00, 0x60, 0x05, 0xF0, 0x55, 0x12, 0x06]);
+        let mut debugger = Debugger::new();
+        let id = debugger.add_watchpoint(0x300, 0x30F, false, true, false);
+
+        let access = Access { kind : AccessKind::Write, addr : 0x300, old : 0, new : 5 };
+        let stop = debugger.cont(&mut cpu);
+        assert_eq!(stop, Stop::Watchpoint { id, pc : 0x204, access });
+        assert_eq!(debugger.report(&cpu, stop),
+                   "watchpoint 1, write 300 00>05\n  by 204  F055  LD [I], V0\n206  1206  JP #206\n");
+    }
+
+    #[test]
+    fn stops_when_code_that_ran_is_overwritten() {
+        // 200: LD I, 200, 202: LD V0, 0, 204: LD [I], V0, 206: JP 206
+        let rom = [0xA2, 0x00, 0x60, 0x00, 0xF0, 0x55, 0x12, 0x06];
+        let mut cpu = cpu(&rom);
+        let mut debugger = Debugger::new();
+
+        let access = Access { kind : AccessKind::Write, addr : 0x200, old : 0xA2, new : 0 };
+        assert_eq!(debugger.cont(&mut cpu), Stop::SelfModify { pc : 0x204, access });
+
+        debugger.command(&mut cpu, "reset").unwrap();
+        debugger.command(&mut cpu, "smc off").unwrap();
+        debugger.limit = 100;
+        assert_eq!(debugger.cont(&mut cpu), Stop::Limit);
+    }
+}
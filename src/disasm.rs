@@ -0,0 +1,273 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use byteorder::{ByteOrder, BigEndian};
+use cpu::{decode_in, Instruction, Mode, PROGRAM_START};
+
+/*
+ * Turns a ROM back into assembly without running it. Which bytes are
+ * code is found by recursive descent: starting at 0x200 instructions
+ * are followed until a jump, return or exit, and every jump, call and
+ * skip adds another place to continue from. Whatever is never reached
+ * is data.
+ *
+ * Jump and call targets get an `L` label, addresses loaded into I
+ * a `D` label, as long as they lie inside the ROM.
+ */
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Code(Instruction),
+    Data,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Line {
+    pub addr : u16,
+    pub bytes : Vec<u8>,        // 2 per instruction (4 for F000 nnnn), up to 4 of data
+    pub kind : Kind,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Disassembly {
+    pub lines : Vec<Line>,
+    pub labels : BTreeMap<u16, String>,
+}
+
+pub fn disassemble(rom : &[u8], mode : Mode) -> Disassembly {
+    let start = PROGRAM_START as usize;
+    let end = start + rom.len();
+    let word = |addr : usize| -> Option<u16> {
+        if addr >= start && addr + 2 <= end {
+            Some(BigEndian::read_u16(&rom[addr - start..]))
+        } else {
+            None
+        }
+    };
+
+    // Instructions found so far by address, with their size in bytes.
+    let mut code : BTreeMap<usize, (Instruction, usize)> = BTreeMap::new();
+    let mut labels = BTreeMap::new();
+    let mut data_refs = Vec::new();
+    let mut todo = vec![start];
+
+    while let Some(mut addr) = todo.pop() {
+        while !code.contains_key(&addr) {
+            let instruction = match word(addr).map(|w| decode_in(w, mode)) {
+                Some(Instruction::Unknown(_)) | None => break,
+                Some(i) => i,
+            };
+            let size = instruction.size() as usize;
+            if size == 4 && word(addr + 2).is_none() {
+                break;
+            }
+            code.insert(addr, (instruction, size));
+            let next = addr + size;
+
+            match instruction {
+                Instruction::Jp(nnn) | Instruction::JpV0(nnn) => {
+                    labels.insert(nnn as usize, 'L');
+                    todo.push(nnn as usize);
+                    break;
+                },
+                Instruction::Call(nnn) => {
+                    labels.insert(nnn as usize, 'L');
+                    todo.push(nnn as usize);
+                },
+                Instruction::Ret | Instruction::Exit => break,
+                Instruction::LdI(nnn) => data_refs.push(nnn as usize),
+                Instruction::LdILong => data_refs.push(word(addr + 2).unwrap_or(0) as usize),
+                Instruction::SeByte(..) | Instruction::SneByte(..) |
+                Instruction::SeReg(..) | Instruction::SneReg(..) |
+                Instruction::Skp(_) | Instruction::Sknp(_) => {
+                    let skipped = word(next).map_or(2, |w| decode_in(w, mode).size());
+                    todo.push(next + skipped as usize);
+                },
+                _ => (),
+            }
+            addr = next;
+        }
+    }
+
+    for addr in data_refs {
+        labels.entry(addr).or_insert('D');
+    }
+
+    let mut disassembly = Disassembly::default();
+    for (addr, prefix) in labels {
+        if addr >= start && addr < end {
+            disassembly.labels.insert(addr as u16, format!("{}{:03X}", prefix, addr));
+        }
+    }
+
+    // Walk the ROM in order, data runs end at the next instruction or
+    // label so that every label starts a line. An instruction that
+    // another one jumps into the middle of is printed as data too, or
+    // the label of the overlapping entry point would be lost.
+    let mut addr = start;
+    while addr < end {
+        let whole = |size : usize| (addr + 1..addr + size).all(|inner| {
+            !code.contains_key(&inner) && !disassembly.labels.contains_key(&(inner as u16))
+        });
+        if let Some(&(instruction, size)) = code.get(&addr).filter(|&&(_, size)| whole(size)) {
+            disassembly.lines.push(Line {
+                addr : addr as u16,
+                bytes : rom[addr - start..addr - start + size].to_vec(),
+                kind : Kind::Code(instruction),
+            });
+            addr += size;
+            continue;
+        }
+
+        let mut len = 1;
+        while len < 4 && addr + len < end && !code.contains_key(&(addr + len)) &&
+              !disassembly.labels.contains_key(&((addr + len) as u16)) {
+            len += 1;
+        }
+        disassembly.lines.push(Line {
+            addr : addr as u16,
+            bytes : rom[addr - start..addr - start + len].to_vec(),
+            kind : Kind::Data,
+        });
+        addr += len;
+    }
+
+    disassembly
+} // fn disassemble
+
+impl Disassembly {
+    // The assembly text of a line, with addresses replaced by labels.
+    pub fn text(&self, line : &Line) -> String {
+        let instruction = match line.kind {
+            Kind::Data => {
+                let bytes : Vec<String> = line.bytes.iter()
+                    .map(|b| format!("#{:02X}", b))
+                    .collect();
+                return format!("DB {}", bytes.join(", "));
+            },
+            Kind::Code(instruction) => instruction,
+        };
+
+        // Before XO-CHIP 5xyN and 9xyN run as SE and SNE, but only N = 0
+        // can be written that way.
+        if let Instruction::SeReg(..) | Instruction::SneReg(..) = instruction {
+            if line.bytes[1] & 0xF != 0 {
+                return format!("DW #{:02X}{:02X}", line.bytes[0], line.bytes[1]);
+            }
+        }
+
+        let target = match instruction {
+            Instruction::Sys(nnn) | Instruction::Jp(nnn) | Instruction::Call(nnn) |
+            Instruction::LdI(nnn) | Instruction::JpV0(nnn) => nnn,
+            Instruction::LdILong => {
+                let nnnn = BigEndian::read_u16(&line.bytes[2..]);
+                return format!("LD I, LONG {}", self.address(nnnn, 4));
+            },
+            _ => return instruction.to_string(),
+        };
+        instruction.to_string()
+            .replace(&format!("#{:03X}", target), &self.address(target, 3))
+    } // fn text
+
+    fn address(&self, addr : u16, digits : usize) -> String {
+        match self.labels.get(&addr) {
+            Some(label) => label.clone(),
+            None => format!("#{:0width$X}", addr, width = digits),
+        }
+    }
+}
+
+impl fmt::Display for Disassembly {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        for line in &self.lines {
+            if let Some(label) = self.labels.get(&line.addr) {
+                writeln!(f, "{}:", label)?;
+            }
+            let bytes : Vec<String> = line.bytes.iter()
+                .map(|b| format!("{:02X}", b))
+                .collect();
+            writeln!(f, "{:03X}  {:<11}  {}", line.addr, bytes.join(" "), self.text(line))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn follows_jumps_and_calls_and_leaves_the_rest_as_data() {
+        let rom = [
+            0x22, 0x06,     // 200: CALL 206
+            0x12, 0x08,     // 202: JP 208
+            0xF0, 0x90,     // 204: data
+            0xA2, 0x04,     // 206: LD I, 204
+            0x00, 0xEE,     // 208: RET
+        ];
+        let disassembly = disassemble(&rom, Mode::Chip8);
+        let text : Vec<String> = disassembly.lines.iter()
+            .map(|l| format!("{:03X} {}", l.addr, disassembly.text(l)))
+            .collect();
+
+        assert_eq!(text, [
+            "200 CALL L206",
+            "202 JP L208",
+            "204 DB #F0, #90",
+            "206 LD I, D204",
+            "208 RET",
+        ]);
+    }
+
+    #[test]
+    fn skips_continue_at_both_instructions() {
+        let rom = [
+            0x30, 0x01,     // 200: SE V0, #01
+            0x12, 0x06,     // 202: JP 206
+            0x00, 0xFD,     // 204: EXIT, only reached by the skip
+            0x12, 0x06,     // 206: JP 206
+        ];
+        let disassembly = disassemble(&rom, Mode::SuperChip);
+        assert_eq!(disassembly.lines[2].kind, Kind::Code(Instruction::Exit));
+
+        // Without SUPER-CHIP 00FD is not an instruction.
+        let disassembly = disassemble(&rom, Mode::Chip8);
+        assert_eq!(disassembly.lines[2].kind, Kind::Data);
+    }
+
+    #[test]
+    fn prints_entry_points_inside_other_instructions() {
+        let rom = [
+            0x22, 0x04,     // 200: CALL 204
+            0x12, 0x05,     // 202: JP 205
+            0x60, 0x00,     // 204: LD V0, #00
+            0xEE,           // 205: 00EE is RET
+        ];
+        let disassembly = disassemble(&rom, Mode::Chip8);
+        assert_eq!(disassembly.to_string(), "\
+200  22 04        CALL L204
+202  12 05        JP L205
+L204:
+204  60           DB #60
+L205:
+205  00 EE        RET
+");
+    }
+
+    #[test]
+    fn goes_on_past_5xy2_before_xo_chip() {
+        let rom = [
+            0x51, 0x22,     // 200: SE V1, V2 outside XO-CHIP
+            0x60, 0x01,     // 202: LD V0, #01
+            0x00, 0xEE,     // 204: RET
+        ];
+        let disassembly = disassemble(&rom, Mode::Chip8);
+        let text : Vec<String> = disassembly.lines.iter()
+            .map(|l| format!("{:03X} {}", l.addr, disassembly.text(l)))
+            .collect();
+        assert_eq!(text, ["200 DW #5122", "202 LD V0, #01", "204 RET"]);
+
+        // With XO-CHIP it is SAVE, the skip no longer happens.
+        let disassembly = disassemble(&rom, Mode::XoChip);
+        assert_eq!(disassembly.text(&disassembly.lines[0]), "SAVE V1 - V2");
+    }
+}
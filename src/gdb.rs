@@ -0,0 +1,551 @@
+use std::fmt::Write as FmtWrite;
+use std::io;
+use std::io::prelude::*;
+use std::net::TcpStream;
+use cpu::{Cpu, Fault};
+use debugger::{Debugger, Register, Stop};
+
+/*
+ * A stub for GDB's remote serial protocol, so GDB and tools built on it
+ * can attach to a running program:
+ *
+ *   target remote localhost:PORT
+ *
+ * The registers are V0-VF, I, PC, SP, DT and ST, numbered in that order
+ * (0-20) and described to GDB by the target.xml below. I and PC are 16
+ * bit, the others 8 bit, and like everything on a CHIP-8 big-endian.
+ *
+ * Memory can be read and written, and breakpoints (Z0, Z1), watchpoints
+ * (Z2 write, Z3 read, Z4 access), step and continue are passed on to a
+ * Debugger. Ctrl-C interrupts a continue. `monitor` hands a command to
+ * the debugger's prompt, e.g. `monitor smc on` or `monitor screen`.
+ */
+
+// Instructions run between checks for Ctrl-C while continuing.
+const SLICE : u64 = 100_000;
+
+const REGISTERS : usize = 21;
+
+// Longest packet GDB may send or ask for, as told in qSupported.
+const PACKET_SIZE : usize = 0x4000;
+
+pub struct Stub {
+    pub debugger : Debugger,
+    pub closed : bool,              // Detached or killed
+    swbreak : bool,                 // GDB understands swbreak stop reasons
+    last_stop : Stop,
+}
+
+impl Default for Stub {
+    fn default() -> Stub {
+        Stub::new()
+    }
+}
+
+impl Stub {
+    pub fn new() -> Stub {
+        // GDB has no notion of overwritten code, it only stops for it
+        // after `monitor smc on`.
+        let mut debugger = Debugger::new();
+        debugger.stop_on_self_modify = false;
+
+        Stub {
+            debugger,
+            closed : false,
+            swbreak : false,
+            last_stop : Stop::Done,
+        }
+    }
+
+    // Answer a packet with the packets to send back. `interrupted` is
+    // asked now and then while continuing whether GDB sent Ctrl-C.
+    pub fn answer(&mut self, cpu : &mut Cpu, packet : &str,
+                  interrupted : &mut dyn FnMut() -> bool) -> Vec<String> {
+        let reply = match packet {
+            "?" => self.stop_reply(self.last_stop),
+            "g" => (0..REGISTERS).map(|n| register_hex(cpu, n)).collect(),
+            "c" | "s" => self.run(cpu, packet == "s", interrupted),
+            "D" => {
+                self.closed = true;
+                String::from("OK")
+            },
+            "k" => {
+                self.closed = true;
+                return Vec::new();
+            },
+            "QStartNoAckMode" => String::from("OK"),
+            "qAttached" => String::from("1"),
+            "qC" => String::from("QC1"),
+            "qfThreadInfo" => String::from("m1"),
+            "qsThreadInfo" => String::from("l"),
+            _ => {
+                // The packet's kind, then its arguments. Anything else,
+                // an empty packet too, is not supported.
+                let mut chars = packet.chars();
+                match (chars.next(), chars.as_str()) {
+                    (Some('G'), regs) => self.write_registers(cpu, regs),
+                    (Some('p'), n) => match usize::from_str_radix(n, 16) {
+                        Ok(n) if n < REGISTERS => register_hex(cpu, n),
+                        _ => String::from("E00"),
+                    },
+                    (Some('P'), assignment) => self.write_register(cpu, assignment),
+                    (Some('m'), args) => read_memory(cpu, args),
+                    (Some('M'), args) => write_memory(cpu, args),
+                    (Some('Z'), args) => self.breakpoint(args, true),
+                    (Some('z'), args) => self.breakpoint(args, false),
+                    (Some(kind @ 'c'), addr) | (Some(kind @ 's'), addr) =>
+                        match u16::from_str_radix(addr, 16) {
+                            Ok(addr) => {
+                                cpu.pc = addr;
+                                self.run(cpu, kind == 's', interrupted)
+                            },
+                            Err(_) => String::from("E00"),
+                        },
+                    (Some('H'), _) => String::from("OK"),
+                    (Some('q'), _) => return self.query(cpu, packet),
+                    _ => String::new(),
+                }
+            },
+        };
+        vec![reply]
+    } // fn answer
+
+    fn query(&mut self, cpu : &mut Cpu, packet : &str) -> Vec<String> {
+        if packet.starts_with("qSupported") {
+            self.swbreak = packet.contains("swbreak+");
+            return vec![format!("PacketSize={:x};qXfer:features:read+;\
+                                 QStartNoAckMode+;swbreak+", PACKET_SIZE)];
+        }
+
+        if let Some(args) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let xml = target_xml();
+            let reply = match parse_pair(args, ',') {
+                Some((offset, len)) if offset <= xml.len() => {
+                    let end = (offset + len).min(xml.len());
+                    let more = if end < xml.len() { 'm' } else { 'l' };
+                    format!("{}{}", more, &xml[offset..end])
+                },
+                _ => String::from("E00"),
+            };
+            return vec![reply];
+        }
+
+        if let Some(hex) = packet.strip_prefix("qRcmd,") {
+            // monitor: the output comes back as O packets.
+            let command = match decode_hex(hex).map(String::from_utf8) {
+                Some(Ok(command)) => command,
+                _ => return vec![String::from("E00")],
+            };
+            let out = match self.debugger.command(cpu, &command) {
+                Ok(out) => out,
+                Err(e) => e + "\n",
+            };
+            let mut packets = Vec::new();
+            if !out.is_empty() {
+                packets.push(format!("O{}", encode_hex(out.as_bytes())));
+            }
+            packets.push(String::from("OK"));
+            return packets;
+        }
+
+        vec![String::new()]
+    } // fn query
+
+    fn run(&mut self, cpu : &mut Cpu, step : bool,
+           interrupted : &mut dyn FnMut() -> bool) -> String {
+        // Continue in slices, to look for Ctrl-C in between. The limit
+        // is set back afterwards for the prompt commands of monitor.
+
+        let stop = if step {
+            self.debugger.step(cpu, 1)
+        } else {
+            let limit = self.debugger.limit;
+            self.debugger.limit = SLICE;
+            let stop = loop {
+                match self.debugger.cont(cpu) {
+                    Stop::Limit if interrupted() => break None,
+                    Stop::Limit => (),
+                    stop => break Some(stop),
+                }
+            };
+            self.debugger.limit = limit;
+            match stop {
+                Some(stop) => stop,
+                None => return String::from("T02"),     // SIGINT
+            }
+        };
+
+        self.last_stop = stop;
+        self.stop_reply(stop)
+    } // fn run
+
+    fn stop_reply(&self, stop : Stop) -> String {
+        match stop {
+            Stop::Breakpoint(_) if self.swbreak => String::from("T05swbreak:;"),
+            Stop::Watchpoint { id, access, .. } => {
+                let kind = match self.debugger.watchpoints.iter().find(|w| w.id == id) {
+                    Some(w) if w.read && w.write => "awatch",
+                    Some(w) if w.write => "watch",
+                    Some(w) if w.read => "rwatch",
+                    _ => return String::from("T05"),
+                };
+                format!("T05{}:{:x};", kind, access.addr)
+            },
+            Stop::SelfModify { access, .. } => format!("T05watch:{:x};", access.addr),
+            Stop::Exited => String::from("W00"),
+            Stop::Fault(Fault::UnknownOpcode { .. }) => String::from("T04"),    // SIGILL
+            Stop::Fault(_) => String::from("T0b"),                              // SIGSEGV
+            _ => String::from("T05"),                                           // SIGTRAP
+        }
+    }
+
+    fn breakpoint(&mut self, args : &str, insert : bool) -> String {
+        // Z/z type,addr,kind. For watchpoints kind is the length.
+
+        let fields : Vec<&str> = args.split(',').collect();
+        let (kind, addr, len) = match fields.as_slice() {
+            [kind, addr, len] => match (u16::from_str_radix(addr, 16),
+                                        u16::from_str_radix(len, 16)) {
+                (Ok(addr), Ok(len)) => (*kind, addr, len.max(1)),
+                _ => return String::from("E00"),
+            },
+            _ => return String::from("E00"),
+        };
+
+        let debugger = &mut self.debugger;
+        match kind {
+            "0" | "1" if insert => {
+                debugger.add_breakpoint(addr, None);
+            },
+            "0" | "1" => {
+                let found = debugger.breakpoints.iter()
+                    .find(|b| b.addr == addr && b.condition.is_none())
+                    .map(|b| b.id);
+                if let Some(id) = found {
+                    debugger.remove_breakpoint(id);
+                }
+            },
+            "2" | "3" | "4" => {
+                let (read, write) = match kind {
+                    "2" => (false, true),
+                    "3" => (true, false),
+                    _ => (true, true),
+                };
+                let end = addr.saturating_add(len - 1);
+                if insert {
+                    debugger.add_watchpoint(addr, end, read, write, false);
+                } else {
+                    let found = debugger.watchpoints.iter()
+                        .find(|w| w.start == addr && w.end == end &&
+                                  w.read == read && w.write == write && !w.execute)
+                        .map(|w| w.id);
+                    if let Some(id) = found {
+                        debugger.remove_breakpoint(id);
+                    }
+                }
+            },
+            _ => return String::new(),
+        }
+        String::from("OK")
+    } // fn breakpoint
+
+    fn write_registers(&mut self, cpu : &mut Cpu, hex : &str) -> String {
+        let bytes = match decode_hex(hex) {
+            Some(bytes) => bytes,
+            None => return String::from("E00"),
+        };
+        let mut at = 0;
+        for n in 0..REGISTERS {
+            let width = register_width(n);
+            if at + width > bytes.len() {
+                break;
+            }
+            let value = bytes[at..at + width].iter().fold(0, |v, &b| v << 8 | b as u16);
+            if register(n).set(cpu, value).is_err() {
+                return String::from("E01");
+            }
+            at += width;
+        }
+        String::from("OK")
+    }
+
+    fn write_register(&mut self, cpu : &mut Cpu, assignment : &str) -> String {
+        let (n, value) = match assignment.split_once('=') {
+            Some((n, value)) => (usize::from_str_radix(n, 16), decode_hex(value)),
+            None => return String::from("E00"),
+        };
+        match (n, value) {
+            (Ok(n), Some(ref bytes)) if n < REGISTERS && bytes.len() == register_width(n) => {
+                let value = bytes.iter().fold(0, |v, &b| v << 8 | b as u16);
+                match register(n).set(cpu, value) {
+                    Ok(()) => String::from("OK"),
+                    Err(_) => String::from("E01"),
+                }
+            },
+            _ => String::from("E00"),
+        }
+    }
+}
+
+pub fn target_xml() -> String {
+    let mut xml = String::from("<?xml version=\"1.0\"?>\
+                                <!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+                                <target version=\"1.0\"><feature name=\"org.chip8.core\">");
+    for n in 0..REGISTERS {
+        let kind = match register(n) {
+            Register::I => "data_ptr",
+            Register::Pc => "code_ptr",
+            _ => "uint8",
+        };
+        let _ = write!(xml, "<reg name=\"{}\" bitsize=\"{}\" type=\"{}\" regnum=\"{}\"/>",
+                       register(n).to_string().to_lowercase(), register_width(n) * 8, kind, n);
+    }
+    xml.push_str("</feature></target>");
+    xml
+}
+
+fn register(n : usize) -> Register {
+    match n {
+        0..=15 => Register::V(n as u8),
+        16 => Register::I,
+        17 => Register::Pc,
+        18 => Register::Sp,
+        19 => Register::Dt,
+        _ => Register::St,
+    }
+}
+
+fn register_width(n : usize) -> usize {
+    match register(n) {
+        Register::I | Register::Pc => 2,
+        _ => 1,
+    }
+}
+
+fn register_hex(cpu : &Cpu, n : usize) -> String {
+    let value = register(n).get(cpu);
+    match register_width(n) {
+        2 => format!("{:04x}", value),
+        _ => format!("{:02x}", value),
+    }
+}
+
+fn read_memory(cpu : &Cpu, args : &str) -> String {
+    match parse_pair(args, ',') {
+        Some((addr, len)) if addr < cpu.memory.len() => {
+            // Two hex digits a byte have to fit into a packet.
+            let len = len.min(PACKET_SIZE / 2);
+            let end = addr.saturating_add(len).min(cpu.memory.len());
+            encode_hex(&cpu.memory[addr..end])
+        },
+        _ => String::from("E01"),
+    }
+}
+
+fn write_memory(cpu : &mut Cpu, args : &str) -> String {
+    let (range, data) = match args.split_once(':') {
+        Some(parts) => parts,
+        None => return String::from("E00"),
+    };
+    match (parse_pair(range, ','), decode_hex(data)) {
+        (Some((addr, len)), Some(bytes)) if bytes.len() == len => {
+            if addr.saturating_add(len) > cpu.memory.len() {
+                return String::from("E01");
+            }
+            cpu.memory[addr..addr + len].copy_from_slice(&bytes);
+            String::from("OK")
+        },
+        _ => String::from("E00"),
+    }
+}
+
+fn parse_pair(text : &str, separator : char) -> Option<(usize, usize)> {
+    let (a, b) = text.split_once(separator)?;
+    Some((usize::from_str_radix(a, 16).ok()?, usize::from_str_radix(b, 16).ok()?))
+}
+
+fn encode_hex(bytes : &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_hex(hex : &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len()).step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+// `$data#checksum`, as every packet is sent.
+pub fn frame(data : &str) -> String {
+    let sum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+    format!("${}#{:02x}", data, sum)
+}
+
+// The packet layer on a TCP connection.
+struct Connection {
+    stream : TcpStream,
+    no_ack : bool,
+}
+
+impl Connection {
+    fn byte(&mut self) -> io::Result<Option<u8>> {
+        let mut buf = [0];
+        match self.stream.read(&mut buf)? {
+            0 => Ok(None),
+            _ => Ok(Some(buf[0])),
+        }
+    }
+
+    fn receive(&mut self) -> io::Result<Option<String>> {
+        // Skip acks and stray Ctrl-Cs up to the next packet, check it
+        // and acknowledge it (unless in no ack mode).
+
+        loop {
+            match self.byte()? {
+                None => return Ok(None),
+                Some(b'$') => (),
+                Some(_) => continue,
+            }
+
+            let mut data = Vec::new();
+            loop {
+                match self.byte()? {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    Some(b) => data.push(b),
+                }
+            }
+            let mut sum = [0; 2];
+            self.stream.read_exact(&mut sum)?;
+
+            let data = String::from_utf8_lossy(&data).into_owned();
+            let expected = u8::from_str_radix(&String::from_utf8_lossy(&sum), 16).ok();
+            let actual = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+            if self.no_ack {
+                return Ok(Some(data));
+            }
+            if expected == Some(actual) {
+                self.stream.write_all(b"+")?;
+                return Ok(Some(data));
+            }
+            self.stream.write_all(b"-")?;
+        }
+    } // fn receive
+
+    fn send(&mut self, data : &str) -> io::Result<()> {
+        let packet = frame(data);
+        loop {
+            self.stream.write_all(packet.as_bytes())?;
+            if self.no_ack {
+                return Ok(());
+            }
+            match self.byte()? {
+                Some(b'-') => continue,
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    // Whether GDB sent Ctrl-C (or went away), without waiting.
+    fn interrupted(&mut self) -> bool {
+        let mut buf = [0];
+        if self.stream.set_nonblocking(true).is_err() {
+            return false;
+        }
+        let result = self.stream.read(&mut buf);
+        let _ = self.stream.set_nonblocking(false);
+        match result {
+            Ok(0) => true,
+            Ok(_) => buf[0] == 0x03,
+            Err(_) => false,
+        }
+    }
+}
+
+// Serve GDB on a connection until it detaches.
+pub fn serve(cpu : &mut Cpu, stream : TcpStream) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    let mut connection = Connection { stream, no_ack : false };
+    let mut stub = Stub::new();
+
+    while !stub.closed {
+        let packet = match connection.receive()? {
+            Some(packet) => packet,
+            None => break,
+        };
+        let replies = stub.answer(cpu, &packet, &mut || connection.interrupted());
+        for reply in replies {
+            connection.send(&reply)?;
+        }
+        if packet == "QStartNoAckMode" {
+            connection.no_ack = true;
+        }
+    }
+    Ok(())
+} // fn serve
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn answer(stub : &mut Stub, cpu : &mut Cpu, packet : &str) -> String {
+        stub.answer(cpu, packet, &mut || false).join("|")
+    }
+
+    #[test]
+    fn frames_packets_with_their_checksum() {
+        assert_eq!(frame("OK"), "$OK#9a");
+        assert_eq!(frame(""), "$#00");
+    }
+
+    #[test]
+    fn reads_and_writes_registers_and_memory() {
+        let mut cpu = Cpu::new();
+        let mut stub = Stub::new();
+        cpu.v_regs[1] = 0xAB;
+        cpu.i_reg = 0x345;
+
+        let regs = answer(&mut stub, &mut cpu, "g");
+        assert_eq!(&regs[..6], "00ab00");
+        assert_eq!(&regs[32..40], "03450200");
+
+        assert_eq!(answer(&mut stub, &mut cpu, "P11=0300"), "OK");
+        assert_eq!(cpu.pc, 0x300);
+        assert_eq!(answer(&mut stub, &mut cpu, "P13=0100"), "E00");
+
+        assert_eq!(answer(&mut stub, &mut cpu, "M300,2:00e0"), "OK");
+        assert_eq!(answer(&mut stub, &mut cpu, "m2ff,3"), "0000e0");
+        assert_eq!(answer(&mut stub, &mut cpu, "m1000,1"), "E01");
+        assert_eq!(answer(&mut stub, &mut cpu, "m200,ffffffffffffffff").len(), 0xE00 * 2);
+        assert_eq!(answer(&mut stub, &mut cpu, "Mffffffffffffffff,1:00"), "E01");
+
+        // Empty and unknown packets are not supported.
+        assert_eq!(answer(&mut stub, &mut cpu, ""), "");
+        assert_eq!(answer(&mut stub, &mut cpu, "\u{FFFD}x"), "");
+        assert_eq!(answer(&mut stub, &mut cpu, "vCont?"), "");
+    }
+
+    #[test]
+    fn continues_to_breakpoints_and_watchpoints() {
+        // 200: LD I, 300, 202: LD [I], V0, 204: JP 204
+        let mut cpu = Cpu::new();
+        cpu.load_rom_bytes(&[0xA3, 0x00, 0xF0, 0x55, 0x12, 0x04]).unwrap();
+        let mut stub = Stub::new();
+
+        answer(&mut stub, &mut cpu, "qSupported:multiprocess+;swbreak+");
+        assert_eq!(answer(&mut stub, &mut cpu, "Z0,202,2"), "OK");
+        assert_eq!(answer(&mut stub, &mut cpu, "c"), "T05swbreak:;");
+        assert_eq!(cpu.pc, 0x202);
+
+        assert_eq!(answer(&mut stub, &mut cpu, "z0,202,2"), "OK");
+        assert_eq!(answer(&mut stub, &mut cpu, "Z2,300,1"), "OK");
+        assert_eq!(answer(&mut stub, &mut cpu, "c"), "T05watch:300;");
+        assert_eq!(answer(&mut stub, &mut cpu, "s"), "T05");
+        assert_eq!(cpu.pc, 0x204);
+
+        // A run without a stop ends at Ctrl-C.
+        assert_eq!(stub.answer(&mut cpu, "c", &mut || true), ["T02"]);
+    }
+}
@@ -0,0 +1,279 @@
+use std::fmt::Write as FmtWrite;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+#[cfg(feature = "png")]
+use std::io::BufWriter;
+#[cfg(feature = "png")]
+use png;
+use cpu::{Cpu, Fault};
+#[cfg(feature = "png")]
+use palette::Palette;
+
+/*
+ * Runs a ROM without a window: for a number of frames or instructions,
+ * with key presses read from a script, and dumps what is left behind
+ * (display, registers, memory) so a ROM can be checked on a machine
+ * without a display.
+ */
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Limit {
+    Frames(u64),
+    Instructions(u64),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub frame : u64,        // Frame (counted from the start of the run)
+    pub key : u8,           // Key 0-F
+    pub pressed : bool,
+}
+
+// Scripted key input. In text form every line holds one event,
+// `<frame> <key> <down|up>`, with the key as a hex digit. Empty lines
+// and everything after a '#' are ignored:
+//
+//   # press 5 for half a second
+//   10  5 down
+//   40  5 up
+//
+// A key stays down until an `up` event releases it.
+#[derive(Clone, Debug, Default)]
+pub struct KeyScript {
+    events : Vec<KeyEvent>,
+}
+
+impl KeyScript {
+    pub fn new() -> KeyScript {
+        KeyScript { events : Vec::new() }
+    }
+
+    pub fn push(&mut self, event : KeyEvent) {
+        self.events.push(event);
+    }
+
+    pub fn events(&self) -> &[KeyEvent] {
+        &self.events
+    }
+
+    pub fn parse(text : &str) -> io::Result<KeyScript> {
+        let mut script = KeyScript::new();
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let fields : Vec<&str> = line.split_whitespace().collect();
+            let event = match fields.as_slice() {
+                [frame, key, state] => parse_event(frame, key, state),
+                _ => Err(String::from("expected `<frame> <key> <down|up>`")),
+            };
+
+            match event {
+                Ok(event) => script.push(event),
+                Err(msg) => return Err(io::Error::new(io::ErrorKind::InvalidData,
+                    format!("key script line {}: {}", number + 1, msg))),
+            }
+        }
+
+        Ok(script)
+    } // fn parse
+
+    pub fn from_file(path : &str) -> io::Result<KeyScript> {
+        let mut text = String::new();
+        File::open(path)?.read_to_string(&mut text)?;
+        KeyScript::parse(&text)
+    }
+
+    fn apply(&self, cpu : &mut Cpu, frame : u64) {
+        for event in self.events.iter().filter(|e| e.frame == frame) {
+            cpu.set_key(event.key as usize, event.pressed);
+        }
+    }
+}
+
+fn parse_event(frame : &str, key : &str, state : &str) -> Result<KeyEvent, String> {
+    let frame = frame.parse::<u64>()
+        .map_err(|_| format!("bad frame number `{}`", frame))?;
+
+    let key = match u8::from_str_radix(key, 16) {
+        Ok(k) if k < 16 => k,
+        _ => return Err(format!("bad key `{}`, expected 0-F", key)),
+    };
+
+    let pressed = match state {
+        "down" => true,
+        "up" => false,
+        _ => return Err(format!("bad key state `{}`, expected down or up", state)),
+    };
+
+    Ok(KeyEvent { frame, key, pressed })
+} // fn parse_event
+
+pub fn run(cpu : &mut Cpu, limit : Limit, keys : &KeyScript) -> Result<(), Fault> {
+    // Frames are run whole, with the timers ticking in between, until
+    // the limit is reached or the program exits. An instruction limit
+    // may end a run halfway through a frame.
+
+    let mut frame = 0;
+
+    loop {
+        match limit {
+            Limit::Frames(n) if frame >= n => return Ok(()),
+            Limit::Instructions(n) if cpu.instructions >= n => return Ok(()),
+            _ if cpu.exited => return Ok(()),
+            _ => (),
+        }
+
+        keys.apply(cpu, frame);
+
+        let count = cpu.scheduler.next_frame();
+        for _ in 0..count {
+            if let Limit::Instructions(n) = limit {
+                if cpu.instructions >= n {
+                    return Ok(());
+                }
+            }
+            cpu.step()?;
+        }
+        cpu.end_frame();
+        frame += 1;
+    }
+} // fn run
+
+pub fn ascii_art(cpu : &Cpu) -> String {
+    let (width, _) = cpu.display_size();
+    let mut art = String::new();
+
+    for row in cpu.framebuffer().chunks(width) {
+        for pixel in row {
+            art.push(if *pixel != 0 { '#' } else { '.' });
+        }
+        art.push('\n');
+    }
+    art
+} // fn ascii_art
+
+pub fn registers(cpu : &Cpu) -> String {
+    let mut out = String::new();
+
+    for (i, v) in cpu.v_regs.iter().enumerate() {
+        let _ = write!(out, "V{:X}={:02X}", i, v);
+        out.push(if i % 8 == 7 { '\n' } else { ' ' });
+    }
+    let _ = writeln!(out, "PC={:03X} I={:03X} SP={:X} DT={:02X} ST={:02X}",
+                     cpu.pc, cpu.i_reg, cpu.sp, cpu.dt, cpu.st);
+
+    let stack : Vec<String> = cpu.stack[..cpu.sp as usize].iter()
+        .map(|a| format!("{:03X}", a))
+        .collect();
+    let _ = writeln!(out, "STACK=[{}]", stack.join(" "));
+    let _ = writeln!(out, "FRAMES={} INSTRUCTIONS={}", cpu.frames, cpu.instructions);
+    out
+} // fn registers
+
+pub fn hex_dump(bytes : &[u8], base : usize) -> String {
+    let mut out = String::new();
+
+    for (i, row) in bytes.chunks(16).enumerate() {
+        let _ = write!(out, "{:04X}:", base + i * 16);
+        for b in row {
+            let _ = write!(out, " {:02X}", b);
+        }
+        out.push('\n');
+    }
+    out
+} // fn hex_dump
+
+// Needs the `png` feature, on by default.
+#[cfg(feature = "png")]
+pub fn write_png(cpu : &Cpu, path : &str, scale : usize, palette : &Palette)
+                 -> io::Result<()> {
+    // Every pixel becomes a scale x scale block in the image.
+
+    let (width, height) = cpu.display_size();
+    let scale = scale.max(1);
+
+    let mut data = Vec::with_capacity(width * height * scale * scale * 3);
+    for row in cpu.framebuffer().chunks(width) {
+        for _ in 0..scale {
+            for pixel in row {
+                let color = palette.color(*pixel);
+                for _ in 0..scale {
+                    data.push((color >> 16) as u8);
+                    data.push((color >> 8) as u8);
+                    data.push(color as u8);
+                }
+            }
+        }
+    }
+
+    let file = BufWriter::new(File::create(path)?);
+    let mut encoder = png::Encoder::new(file,
+                                        (width * scale) as u32,
+                                        (height * scale) as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&data)?;
+    Ok(())
+} // fn write_png
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_key_scripts() {
+        let script = KeyScript::parse("# press 5\n10  5 down\n\n40 a up # release\n").unwrap();
+        assert_eq!(script.events(), [
+            KeyEvent { frame : 10, key : 5, pressed : true },
+            KeyEvent { frame : 40, key : 0xA, pressed : false },
+        ]);
+
+        let error = KeyScript::parse("1 5 down\n2 G down\n").unwrap_err();
+        assert_eq!(error.to_string(), "key script line 2: bad key `G`, expected 0-F");
+        assert!(KeyScript::parse("1 5").is_err());
+        assert!(KeyScript::parse("x 5 down").is_err());
+        assert!(KeyScript::parse("1 5 held").is_err());
+    }
+
+    #[test]
+    fn runs_with_scripted_keys_and_dumps_the_result() {
+        // Wait for a key, then draw the font glyph of it at (0, 0).
+        let mut cpu = Cpu::new();
+        cpu.load_rom_bytes(&[0xF0, 0x0A, 0xF0, 0x29, 0xD1, 0x15, 0x12, 0x06]).unwrap();
+        let mut keys = KeyScript::new();
+        keys.push(KeyEvent { frame : 2, key : 1, pressed : true });
+        keys.push(KeyEvent { frame : 3, key : 1, pressed : false });
+
+        run(&mut cpu, Limit::Frames(5), &keys).unwrap();
+        assert_eq!(cpu.frames, 5);
+        assert_eq!(cpu.v_regs[0], 1);
+        let art = ascii_art(&cpu);
+        assert_eq!(art.lines().count(), 32);
+        assert!(art.starts_with("..#....."));
+        assert!(art.lines().nth(4).unwrap().starts_with(".###...."));
+
+        let dump = registers(&cpu);
+        assert!(dump.starts_with("V0=01 V1=00"));
+        assert!(dump.contains("PC=206 I=005 SP=0 DT=00 ST=00\nSTACK=[]\nFRAMES=5"));
+
+        let mut cpu = Cpu::new();
+        cpu.load_rom_bytes(&[0x12, 0x00]).unwrap();
+        run(&mut cpu, Limit::Instructions(25), &KeyScript::new()).unwrap();
+        assert_eq!(cpu.instructions, 25);
+    }
+
+    #[test]
+    fn dumps_memory_in_rows_of_16() {
+        let bytes : Vec<u8> = (0..20).collect();
+        assert_eq!(hex_dump(&bytes, 0x200),
+                   "0200: 00 01 02 03 04 05 06 07 08 09 0A 0B 0C 0D 0E 0F\n\
+                    0210: 10 11 12 13\n");
+    }
+}
@@ -0,0 +1,88 @@
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+
+/*
+ * Binds host keys to the 16 keys of the CHIP-8 keypad. Host keys are
+ * plain names ("Q", "1", "Space", ...), every frontend translates them
+ * to whatever its window or terminal reports.
+ *
+ * In a key map file every line holds `<host key> <chip-8 key>` with
+ * the CHIP-8 key as a hex digit. Empty lines and everything after a
+ * '#' are ignored.
+ */
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeyMap {
+    bindings : Vec<(String, u8)>,
+}
+
+impl KeyMap {
+    pub fn new() -> KeyMap {
+        KeyMap { bindings : Vec::new() }
+    }
+
+    pub fn bind(&mut self, host : &str, key : u8) {
+        let host = host.to_uppercase();
+        self.bindings.retain(|b| b.0 != host);
+        self.bindings.push((host, key & 0xF));
+    }
+
+    pub fn lookup(&self, host : &str) -> Option<u8> {
+        self.bindings.iter()
+            .find(|b| b.0.eq_ignore_ascii_case(host))
+            .map(|b| b.1)
+    }
+
+    pub fn bindings(&self) -> &[(String, u8)] {
+        &self.bindings
+    }
+
+    pub fn parse(text : &str) -> io::Result<KeyMap> {
+        let mut map = KeyMap::new();
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let fields : Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                [host, key] => match u8::from_str_radix(key, 16) {
+                    Ok(k) if k < 16 => map.bind(host, k),
+                    _ => return Err(bad_line(number, "expected a key from 0 to F")),
+                },
+                _ => return Err(bad_line(number, "expected `<host key> <chip-8 key>`")),
+            }
+        }
+
+        Ok(map)
+    } // fn parse
+
+    pub fn from_file(path : &str) -> io::Result<KeyMap> {
+        let mut text = String::new();
+        File::open(path)?.read_to_string(&mut text)?;
+        KeyMap::parse(&text)
+    }
+}
+
+fn bad_line(number : usize, msg : &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData,
+                   format!("key map line {}: {}", number + 1, msg))
+}
+
+impl Default for KeyMap {
+    fn default() -> KeyMap {
+        // The left hand side of a QWERTY keyboard, row by row.
+        let mut map = KeyMap::new();
+        let keys = ["1", "2", "3", "4",
+                    "Q", "W", "E", "R",
+                    "A", "S", "D", "F",
+                    "Y", "X", "C", "V"];
+        for (i, host) in keys.iter().enumerate() {
+            map.bind(host, i as u8);
+        }
+        map
+    }
+}
@@ -0,0 +1,31 @@
+extern crate byteorder;
+#[cfg(feature = "png")]
+extern crate png;
+extern crate rand;
+
+pub mod asm;
+pub mod audio;
+pub mod cpu;
+pub mod dap;
+pub mod debugger;
+pub mod disasm;
+pub mod gdb;
+pub mod headless;
+pub mod keymap;
+pub mod octo;
+pub mod pacer;
+pub mod palette;
+pub mod trace;
+
+pub use audio::{AudioSink, NullSink, PipeSink, WavSink};
+pub use cpu::{Cpu, Fault, Font, FontVariant, LoadError, MemoryPolicy, Mode, OpcodePolicy};
+pub use cpu::{Access, AccessKind};
+pub use cpu::{IndexIncrement, QuirkPreset, Quirks};
+pub use cpu::{Instruction, decode, decode_in};
+pub use cpu::{Scheduler, Speed, TIMER_HZ};
+pub use keymap::KeyMap;
+pub use pacer::FramePacer;
+pub use palette::Palette;
+pub use trace::{TraceFormat, Tracer};
+pub use cpu::{DISPLAY_WIDTH, DISPLAY_HEIGHT, HIRES_WIDTH, HIRES_HEIGHT, PROGRAM_START};
+pub use cpu::{MEMORY_SIZE, STACK_SIZE, XO_MEMORY_SIZE};
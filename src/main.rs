@@ -1,53 +1,227 @@
+extern crate chip8;
+extern crate getopts;
 extern crate minifb;
-pub mod cpu;
-use cpu::Cpu;
-use minifb::{Key, WindowOptions, Window, Scale};
 
-#[allow(unused)]
+mod cli;
+mod tui;
+mod window;
+
+use chip8::{Cpu, OpcodePolicy, Tracer, WavSink};
+use chip8::{asm, dap, disasm, gdb, headless, octo};
+use chip8::debugger::{self, Debugger};
+use cli::{Command, Config};
+use std::env;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::mem;
+use std::net::TcpListener;
+use std::process;
+
 fn main() {
-    let mut chip8 : Cpu = Cpu::new();
-    chip8.load_rom(String::from("./rom/INVADERS"));
-
-
-    let mut window = Window::new("RUST Chip-8",
-                                 64,
-                                 32,
-                                 WindowOptions {
-                                     resize: true,
-                                     scale: Scale::X4,
-                                     ..WindowOptions::default()})
-                                 .unwrap_or_else(|e| {
-        panic!("{}", e);
-    });
-
-    while window.is_open() && !window.is_key_down(Key::Escape){
-
-        window.get_keys().map(|keys| {
-            for t in keys {
-                match t {
-                    Key::Key1 => chip8.keypad[0] = 1,
-                    Key::Key2 => chip8.keypad[1] = 1,
-                    Key::Key3 => chip8.keypad[2] = 1,
-                    Key::Key4 => chip8.keypad[3] = 1,
-                    Key::Q => chip8.keypad[4] = 1,
-                    Key::W => chip8.keypad[5] = 1,
-                    Key::E => chip8.keypad[6] = 1,
-                    Key::R => chip8.keypad[7] = 1,
-                    Key::A => chip8.keypad[8] = 1,
-                    Key::S => chip8.keypad[9] = 1,
-                    Key::D => chip8.keypad[10] = 1,
-                    Key::F => chip8.keypad[11] = 1,
-                    Key::Y => chip8.keypad[12] = 1,
-                    Key::X => chip8.keypad[13] = 1,
-                    Key::C => chip8.keypad[14] = 1,
-                    Key::V => chip8.keypad[15] = 1,
-                    _ => (),
-                }
+    let args : Vec<String> = env::args().collect();
+    let program = args.first().map(|a| a.as_str()).unwrap_or("RUST-Chip-8");
+
+    let mut config = match cli::parse(program, &args[1..]) {
+        Ok(Command::Run(config)) => config,
+        Ok(Command::Help(usage)) => {
+            print!("{}", usage);
+            return;
+        },
+        Ok(Command::Disasm { rom, mode }) => {
+            match fs::read(&rom) {
+                // A closed pipe (e.g. `| head`) is not an error.
+                Ok(bytes) => {
+                    let _ = write!(io::stdout(), "{}", disasm::disassemble(&bytes, mode));
+                },
+                Err(e) => {
+                    eprintln!("{}: {}", rom, e);
+                    process::exit(1);
+                },
+            }
+            return;
+        },
+        Ok(Command::Asm { source, output, listing }) => {
+            if let Err(e) = assemble(&source, &output, listing.as_deref()) {
+                eprint!("{}", e);
+                process::exit(1);
             }
+            return;
+        },
+        Ok(Command::Dap { port }) => {
+            let served = match port {
+                Some(port) => run_dap(port),
+                None => dap::serve(io::stdin(), io::stdout()),
+            };
+            if let Err(e) = served {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+            return;
+        },
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(2);
+        },
+    };
+
+    let mut chip8 : Cpu = Cpu::new();
+    chip8.mode = config.mode;
+    chip8.quirks = config.quirks;
+    chip8.set_font(config.font.clone());
+    chip8.scheduler.set_speed(config.speed);
+    chip8.opcode_policy = mem::replace(&mut config.opcodes, OpcodePolicy::Ignore);
+    chip8.memory_policy = config.addresses;
+    if let Some(seed) = config.seed {
+        chip8.seed_rng(seed);
+    }
+
+    if let Some(ref trace) = config.trace {
+        let tracer = if trace.path == "-" {
+            Ok(Tracer::stderr(trace.format))
+        } else {
+            Tracer::create(&trace.path, trace.format)
+        };
+        let mut tracer = tracer.unwrap_or_else(|e| {
+            eprintln!("--trace {}: {}", trace.path, e);
+            process::exit(1);
         });
+        tracer.range = trace.range;
+        tracer.families = trace.families;
+        chip8.set_tracer(Some(tracer));
+    }
+
+    if let Some(ref path) = config.wav {
+        let sink = WavSink::create(path).unwrap_or_else(|e| {
+            eprintln!("--wav {}: {}", path, e);
+            process::exit(1);
+        });
+        chip8.set_audio_sink(Box::new(sink));
+    }
+
+    let loaded = if octo::is_source(&config.rom) {
+        octo::compile_file(&config.rom)
+            .map_err(|errors| errors.iter().map(|e| format!("{}\n", e)).collect())
+            .and_then(|program| chip8.load_rom_bytes(&program.rom)
+                                     .map_err(|e| format!("{}\n", e)))
+    } else {
+        chip8.load_rom(&config.rom).map_err(|e| format!("{}\n", e))
+    };
+    if let Err(e) = loaded {
+        eprint!("{}", e);
+        process::exit(1);
+    }
+
+    let result = match (&config.headless, config.gdb) {
+        (Some(_), _) => run_headless(&mut chip8, &config),
+        (None, Some(port)) => run_gdb(&mut chip8, port).map_err(|e| e.to_string()),
+        (None, None) if config.debug => run_debugger(&mut chip8).map_err(|e| e.to_string()),
+        (None, None) if config.tui => tui::run(&mut chip8, &config).map_err(|e| e.to_string()),
+        (None, None) => window::run(&mut chip8, &config),
+    };
+
+    if let Some(tracer) = chip8.tracer() {
+        let _ = tracer.flush();
+    }
+    if let (Some(ref path), Err(e)) = (&config.wav, chip8.finish_audio()) {
+        eprintln!("--wav {}: {}", path, e);
+        process::exit(1);
+    }
 
-        chip8.cycle();
-        window.update_with_buffer(&chip8.v_memory);
-        chip8.keypad = [0; 16];
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        process::exit(1);
     }
 }
+
+fn run_headless(chip8 : &mut Cpu, config : &Config) -> Result<(), String> {
+    let options = match config.headless {
+        Some(ref options) => options,
+        None => return Ok(()),
+    };
+
+    let result = headless::run(chip8, options.limit, &options.keys);
+
+    print!("{}", headless::ascii_art(chip8));
+    print!("{}", headless::registers(chip8));
+    if let Some(ref path) = options.png {
+        headless::write_png(chip8, path, config.scale, &config.palette)
+            .map_err(|e| format!("{}: {}", path, e))?;
+    }
+    if let Some(ref path) = options.memory {
+        File::create(path)
+            .and_then(|mut f| f.write_all(headless::hex_dump(&chip8.memory, 0).as_bytes()))
+            .map_err(|e| format!("{}: {}", path, e))?;
+    }
+
+    result.map_err(|fault| fault.to_string())
+} // fn run_headless
+
+fn run_gdb(chip8 : &mut Cpu, port : u16) -> io::Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    eprintln!("waiting for gdb on {}", listener.local_addr()?);
+    let (stream, peer) = listener.accept()?;
+    eprintln!("gdb connected from {}", peer);
+    gdb::serve(chip8, stream)
+}
+
+fn run_dap(port : u16) -> io::Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    eprintln!("waiting for a debug adapter client on {}", listener.local_addr()?);
+    let (stream, _) = listener.accept()?;
+    dap::serve(stream.try_clone()?, stream)
+}
+
+fn run_debugger(chip8 : &mut Cpu) -> io::Result<()> {
+    // Read commands until quit or the end of input. An empty line
+    // repeats the last command, like stepping on in gdb.
+
+    let mut debugger = Debugger::new();
+    let stdin = io::stdin();
+    let mut stdout = io::stdout();
+    let mut last = String::new();
+
+    writeln!(stdout, "{}", debugger::location(chip8, chip8.pc))?;
+    loop {
+        write!(stdout, "(chip8) ")?;
+        stdout.flush()?;
+
+        let mut line = String::new();
+        if stdin.read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        let line = match line.trim() {
+            "" => last.clone(),
+            line => line.to_string(),
+        };
+        if line == "quit" || line == "q" {
+            return Ok(());
+        }
+
+        match debugger.command(chip8, &line) {
+            Ok(out) => write!(stdout, "{}", out)?,
+            Err(e) => writeln!(stdout, "{}", e)?,
+        }
+        last = line;
+    }
+} // fn run_debugger
+
+// Assemble source (or compile Octo) into a ROM, errors come back one
+// per line.
+fn assemble(source : &str, output : &str, listing : Option<&str>) -> Result<(), String> {
+    let program = if octo::is_source(source) {
+        octo::compile_file(source)
+    } else {
+        asm::assemble_file(source)
+    };
+    let program = program.map_err(|errors| {
+        errors.iter().map(|e| format!("{}\n", e)).collect::<String>()
+    })?;
+
+    fs::write(output, &program.rom).map_err(|e| format!("{}: {}\n", output, e))?;
+    if let Some(path) = listing {
+        fs::write(path, &program.listing).map_err(|e| format!("{}: {}\n", path, e))?;
+    }
+    Ok(())
+} // fn assemble
@@ -0,0 +1,1049 @@
+use std::collections::BTreeMap;
+use std::f64::consts;
+use std::fs;
+use std::mem;
+use std::path::Path;
+use asm::{listing, AsmError, Program, SourceLine};
+use cpu::PROGRAM_START;
+
+/*
+ * Compiles Octo programs (.8o), the assembly language of the Octo IDE
+ * that most CHIP-8 homebrew of today is written in:
+ *
+ *   :const SPEED 2
+ *   :alias x v1
+ *
+ *   : main
+ *     loop
+ *       i := dot
+ *       sprite x x 1
+ *       x += SPEED
+ *       if x > 60 then x := 0
+ *     again
+ *
+ *   : dot 0x80
+ *
+ * Supported are labels (`: name` and `:next name`), `:const`, `:alias`,
+ * `:calc`, `:byte`, `:pointer`, `:org`, `:unpack`, `:assert` and
+ * `:macro`, the structured `if ... then`, `if ... begin ... else ...
+ * end` and `loop ... while ... again`, and every CHIP-8, SUPER-CHIP
+ * and XO-CHIP statement. `:breakpoint` and `:monitor` are read and
+ * ignored. As in Octo 0x200 holds a jump to `main`, names may be used
+ * before they are defined wherever an address is expected, and a bare
+ * number is a byte of data.
+ *
+ * Expressions in `{ }` are evaluated right to left without precedence,
+ * like Octo does, so `{ 2 * 3 + 1 }` is 8.
+ *
+ * Compilation stops at the first error, since what follows a broken
+ * statement cannot be read reliably.
+ */
+
+const MAX_ADDRESS : usize = 0xFFFF;
+const MAX_EXPANSIONS : usize = 0x10000;   // Macros calling themselves
+
+const KEYWORDS : &[&str] = &[
+    ":=", "+=", "-=", "=-", "|=", "&=", "^=", ">>=", "<<=",
+    "==", "!=", "<", ">", "<=", ">=", "key", "-key", "-",
+    ";", "return", "clear", "bcd", "save", "load", "sprite", "jump", "jump0",
+    "native", "hires", "lores", "exit", "scroll-down", "scroll-up",
+    "scroll-left", "scroll-right", "saveflags", "loadflags", "plane", "audio",
+    "delay", "buzzer", "pitch", "random", "i", "if", "then", "begin", "else",
+];
+
+const FLOW : &[&str] = &["end", "loop", "while", "again", "long", "hex"];
+
+const UNARY : &[&str] = &[
+    "-", "~", "!", "sin", "cos", "tan", "exp", "log", "abs", "sqrt", "sign",
+    "ceil", "floor", "@",
+];
+
+struct Token {
+    text : String,
+    line : usize,
+}
+
+struct Macro {
+    params : Vec<String>,
+    body : Vec<String>,
+}
+
+// How an address is written into the instructions that use it.
+#[derive(Clone, Copy)]
+enum Patch {
+    Addr12,             // The low 12 bits of a word: jumps, calls, i :=
+    Addr16,             // A whole word: i := long, :pointer
+    Unpack(u8),         // :unpack n, into v0 := and v1 := after it
+    UnpackLong,         // :unpack long
+}
+
+enum Target {
+    Known(u16),
+    Label(String),
+}
+
+struct Fixup {
+    addr : usize,
+    patch : Patch,
+    name : String,
+    line : usize,
+}
+
+// Structured control flow waiting for its end.
+enum Flow {
+    If { jump : usize, line : usize },
+    Else { jump : usize, line : usize },
+    Loop { start : usize, breaks : Vec<usize>, line : usize },
+}
+
+struct Compiler {
+    tokens : Vec<Token>,                // Reversed, the next one last
+    line : usize,                       // Of the token read last
+    image : Vec<u8>,                    // Memory from 0x200 on
+    here : usize,
+    labels : BTreeMap<String, u16>,
+    constants : BTreeMap<String, f64>,
+    aliases : BTreeMap<String, u8>,
+    macros : BTreeMap<String, Macro>,
+    expansions : usize,
+    fixups : Vec<Fixup>,
+    flow : Vec<Flow>,
+    spans : Vec<(usize, usize, usize)>, // Line, address and length of the code
+}
+
+pub fn compile(source : &str, name : &str) -> Result<Program, Vec<AsmError>> {
+    let mut compiler = Compiler::new(source);
+    let error = |line, message| vec![AsmError { file : name.to_string(), line, message }];
+
+    compiler.word(0x1000).map_err(|m| error(0, m))?;
+    compiler.fixups.push(Fixup {
+        addr : PROGRAM_START as usize,
+        patch : Patch::Addr12,
+        name : String::from("main"),
+        line : 0,
+    });
+
+    while !compiler.tokens.is_empty() {
+        compiler.statement().map_err(|m| error(compiler.line, m))?;
+    }
+    if let Some(flow) = compiler.flow.last() {
+        return Err(match *flow {
+            Flow::If { line, .. } | Flow::Else { line, .. } =>
+                error(line, String::from("`if ... begin` without `end`")),
+            Flow::Loop { line, .. } =>
+                error(line, String::from("`loop` without `again`")),
+        });
+    }
+
+    for fixup in mem::take(&mut compiler.fixups) {
+        let value = match compiler.lookup(&fixup.name) {
+            Some(value) => value,
+            None if fixup.name == "main" =>
+                return Err(error(0, String::from("the program has no `main` label"))),
+            None => return Err(error(fixup.line, format!("`{}` is not defined", fixup.name))),
+        };
+        compiler.patch(fixup.addr, fixup.patch, value as u16)
+            .map_err(|m| error(fixup.line, m))?;
+    }
+
+    // Line 0 is the jump to main, which has no source.
+    let lines = compiler.spans.iter()
+        .filter(|&&(line, _, _)| line > 0)
+        .map(|&(line, addr, len)| SourceLine { file : name.to_string(), line,
+                                               addr : addr as u16, len })
+        .collect();
+
+    Ok(Program {
+        listing : compiler.listing(source),
+        rom : compiler.image,
+        labels : compiler.labels,
+        lines,
+    })
+} // fn compile
+
+pub fn compile_file<P : AsRef<Path>>(path : P) -> Result<Program, Vec<AsmError>> {
+    let name = path.as_ref().display().to_string();
+    match fs::read_to_string(&path) {
+        Ok(source) => compile(&source, &name),
+        Err(e) => Err(vec![AsmError { file : name, line : 0, message : e.to_string() }]),
+    }
+}
+
+// Whether a file should be compiled as Octo rather than loaded as a ROM.
+pub fn is_source(path : &str) -> bool {
+    path.ends_with(".8o")
+}
+
+impl Compiler {
+    fn new(source : &str) -> Compiler {
+        let mut tokens = Vec::new();
+        for (number, text) in source.lines().enumerate() {
+            let code = text.split('#').next().unwrap_or("");
+            for word in code.split_whitespace() {
+                tokens.push(Token { text : word.to_string(), line : number + 1 });
+            }
+        }
+        tokens.reverse();
+
+        Compiler {
+            tokens,
+            line : 0,
+            image : Vec::new(),
+            here : PROGRAM_START as usize,
+            labels : BTreeMap::new(),
+            constants : BTreeMap::new(),
+            aliases : BTreeMap::new(),
+            macros : BTreeMap::new(),
+            expansions : 0,
+            fixups : Vec::new(),
+            flow : Vec::new(),
+            spans : Vec::new(),
+        }
+    } // fn new
+
+    fn statement(&mut self) -> Result<(), String> {
+        let token = self.next()?;
+        let xy = |x : u8, y : u8| (x as u16) << 8 | (y as u16) << 4;
+
+        match token.as_str() {
+            ":" => {
+                let name = self.name()?;
+                self.define_label(name, self.here)
+            },
+            ":next" => {
+                let name = self.name()?;
+                self.define_label(name, self.here + 1)
+            },
+            ":const" => {
+                let name = self.name()?;
+                let value = self.value()?;
+                if self.constants.contains_key(&name) || self.labels.contains_key(&name) {
+                    return Err(format!("`{}` is defined twice", name));
+                }
+                self.constants.insert(name, value);
+                Ok(())
+            },
+            ":calc" => {
+                // Unlike :const, :calc may change a constant.
+                let name = self.name()?;
+                let value = self.calc()?;
+                self.constants.insert(name, value);
+                Ok(())
+            },
+            ":alias" => {
+                let name = self.name()?;
+                let reg = if self.peek() == Some("{") {
+                    self.integer(0, 0xF, "register")? as u8
+                } else {
+                    self.register()?
+                };
+                self.aliases.insert(name, reg);
+                Ok(())
+            },
+            ":byte" => {
+                let byte = self.byte()?;
+                self.emit(byte)
+            },
+            ":pointer" => {
+                let target = self.target()?;
+                let addr = self.here;
+                self.word(0)?;
+                self.refer(addr, Patch::Addr16, target)
+            },
+            ":org" => {
+                let addr = self.integer(PROGRAM_START as i64, MAX_ADDRESS as i64, "address")?;
+                self.here = addr as usize;
+                Ok(())
+            },
+            ":unpack" => {
+                let patch = if self.peek() == Some("long") {
+                    self.next()?;
+                    Patch::UnpackLong
+                } else {
+                    Patch::Unpack(self.integer(0, 0xF, "nibble")? as u8)
+                };
+                let target = self.target()?;
+                let addr = self.here;
+                self.word(0x6000)?;
+                self.word(0x6100)?;
+                self.refer(addr, patch, target)
+            },
+            ":assert" => {
+                if self.calc()? == 0.0 {
+                    return Err(String::from("assertion failed"));
+                }
+                Ok(())
+            },
+            ":macro" => self.define_macro(),
+            ":call" => self.address_op(0x2000),
+            ":breakpoint" | ":proto" => self.name().map(|_| ()),
+            ":monitor" => {
+                self.next()?;
+                self.next().map(|_| ())
+            },
+            ";" | "return" => self.word(0x00EE),
+            "clear" => self.word(0x00E0),
+            "scroll-right" => self.word(0x00FB),
+            "scroll-left" => self.word(0x00FC),
+            "exit" => self.word(0x00FD),
+            "lores" => self.word(0x00FE),
+            "hires" => self.word(0x00FF),
+            "audio" => self.word(0xF002),
+            "scroll-down" => {
+                let n = self.integer(0, 0xF, "nibble")? as u16;
+                self.word(0x00C0 | n)
+            },
+            "scroll-up" => {
+                let n = self.integer(0, 0xF, "nibble")? as u16;
+                self.word(0x00D0 | n)
+            },
+            "plane" => {
+                let n = self.integer(0, 0xF, "nibble")? as u16;
+                self.word(0xF001 | n << 8)
+            },
+            "bcd" | "saveflags" | "loadflags" => {
+                let x = self.register()?;
+                let op = match token.as_str() {
+                    "bcd" => 0xF033,
+                    "saveflags" => 0xF075,
+                    _ => 0xF085,
+                };
+                self.word(op | xy(x, 0))
+            },
+            "save" | "load" => {
+                let x = self.register()?;
+                let (single, range) = if token == "save" { (0xF055, 0x5002) }
+                                      else { (0xF065, 0x5003) };
+                if self.peek() == Some("-") {
+                    self.next()?;
+                    let y = self.register()?;
+                    self.word(range | xy(x, y))
+                } else {
+                    self.word(single | xy(x, 0))
+                }
+            },
+            "sprite" => {
+                let x = self.register()?;
+                let y = self.register()?;
+                let n = self.integer(0, 0xF, "nibble")? as u16;
+                self.word(0xD000 | xy(x, y) | n)
+            },
+            "jump" => self.address_op(0x1000),
+            "jump0" => self.address_op(0xB000),
+            "native" => self.address_op(0x0000),
+            "delay" | "buzzer" | "pitch" => {
+                self.expect(":=")?;
+                let x = self.register()?;
+                let op = match token.as_str() {
+                    "delay" => 0xF015,
+                    "buzzer" => 0xF018,
+                    _ => 0xF03A,
+                };
+                self.word(op | xy(x, 0))
+            },
+            "i" => self.index(),
+            "if" => self.branch(),
+            "else" => match self.flow.pop() {
+                Some(Flow::If { jump, line }) => {
+                    let end = self.here;
+                    self.word(0x1000)?;
+                    self.patch(jump, Patch::Addr12, self.here as u16)?;
+                    self.flow.push(Flow::Else { jump : end, line });
+                    Ok(())
+                },
+                _ => Err(String::from("`else` without `if ... begin`")),
+            },
+            "end" => match self.flow.pop() {
+                Some(Flow::If { jump, .. }) | Some(Flow::Else { jump, .. }) =>
+                    self.patch(jump, Patch::Addr12, self.here as u16),
+                _ => Err(String::from("`end` without `if ... begin`")),
+            },
+            "loop" => {
+                self.flow.push(Flow::Loop { start : self.here, breaks : Vec::new(),
+                                            line : self.line });
+                Ok(())
+            },
+            "while" => {
+                // Skip the jump out of the loop while the condition holds.
+                self.condition(true)?;
+                let jump = self.here;
+                self.word(0x1000)?;
+                match self.flow.iter_mut().rev().find(|f| matches!(f, Flow::Loop { .. })) {
+                    Some(Flow::Loop { breaks, .. }) => {
+                        breaks.push(jump);
+                        Ok(())
+                    },
+                    _ => Err(String::from("`while` outside of a loop")),
+                }
+            },
+            "again" => match self.flow.pop() {
+                Some(Flow::Loop { start, breaks, .. }) => {
+                    self.word(0x1000 | start as u16)?;
+                    for jump in breaks {
+                        self.patch(jump, Patch::Addr12, self.here as u16)?;
+                    }
+                    Ok(())
+                },
+                _ => Err(String::from("`again` without `loop`")),
+            },
+            _ => {
+                if let Some(x) = self.reg_of(&token) {
+                    self.register_op(x)
+                } else if self.macros.contains_key(&token) {
+                    self.expand(&token)
+                } else if let Some(value) = number(&token)
+                        .or_else(|| self.constants.get(&token).copied()) {
+                    let byte = self.checked(value, -128, 0xFF, "byte")? as u8;
+                    self.emit(byte)
+                } else if token.starts_with(':') {
+                    Err(format!("unknown directive `{}`", token))
+                } else if is_name(&token) {
+                    // A call, of a subroutine that may come later.
+                    self.tokens.push(Token { text : token, line : self.line });
+                    self.address_op(0x2000)
+                } else {
+                    Err(format!("unexpected `{}`", token))
+                }
+            },
+        }
+    } // fn statement
+
+    fn register_op(&mut self, x : u8) -> Result<(), String> {
+        let op = self.next()?;
+        let xy = |y : u8| 0x8000 | (x as u16) << 8 | (y as u16) << 4;
+
+        if op == ":=" {
+            match self.peek() {
+                Some("key") => {
+                    self.next()?;
+                    return self.word(0xF00A | (x as u16) << 8);
+                },
+                Some("delay") => {
+                    self.next()?;
+                    return self.word(0xF007 | (x as u16) << 8);
+                },
+                Some("random") => {
+                    self.next()?;
+                    let mask = self.byte()? as u16;
+                    return self.word(0xC000 | (x as u16) << 8 | mask);
+                },
+                _ => (),
+            }
+        }
+
+        let y = self.peek().and_then(|t| self.reg_of(t));
+        if let Some(y) = y {
+            self.next()?;
+            let n = match op.as_str() {
+                ":=" => 0x0,
+                "|=" => 0x1,
+                "&=" => 0x2,
+                "^=" => 0x3,
+                "+=" => 0x4,
+                "-=" => 0x5,
+                ">>=" => 0x6,
+                "=-" => 0x7,
+                "<<=" => 0xE,
+                _ => return Err(format!("`{}` does not work between registers", op)),
+            };
+            return self.word(xy(y) | n);
+        }
+
+        let byte = self.byte()?;
+        let (op, byte) = match op.as_str() {
+            ":=" => (0x6000, byte),
+            "+=" => (0x7000, byte),
+            "-=" => (0x7000, byte.wrapping_neg()),
+            _ => return Err(format!("`{}` needs a register on both sides", op)),
+        };
+        self.word(op | (x as u16) << 8 | byte as u16)
+    } // fn register_op
+
+    fn index(&mut self) -> Result<(), String> {
+        let op = self.next()?;
+        match op.as_str() {
+            "+=" => {
+                let x = self.register()?;
+                self.word(0xF01E | (x as u16) << 8)
+            },
+            ":=" => match self.peek() {
+                Some("long") => {
+                    self.next()?;
+                    let target = self.target()?;
+                    self.word(0xF000)?;
+                    let addr = self.here;
+                    self.word(0)?;
+                    self.refer(addr, Patch::Addr16, target)
+                },
+                Some("hex") | Some("bighex") => {
+                    let op = if self.next()? == "hex" { 0xF029 } else { 0xF030 };
+                    let x = self.register()?;
+                    self.word(op | (x as u16) << 8)
+                },
+                _ => self.address_op(0xA000),
+            },
+            _ => Err(format!("expected `:=` or `+=` after i, got `{}`", op)),
+        }
+    } // fn index
+
+    fn branch(&mut self) -> Result<(), String> {
+        // `if c then` skips the next statement unless c holds, `if c
+        // begin` skips a jump to the else branch (or the end) if it does.
+
+        let line = self.line;
+        let n = self.tokens.len();
+        let keyed = n >= 2 && (self.tokens[n - 2].text == "key" || self.tokens[n - 2].text == "-key");
+        let at = if keyed { 3 } else { 4 };
+        let begin = match self.tokens.get(n.wrapping_sub(at)).map(|t| t.text.as_str()) {
+            Some("begin") => true,
+            Some("then") => false,
+            _ => return Err(String::from("`if` expects a condition and `then` or `begin`")),
+        };
+
+        self.condition(begin)?;
+        self.next()?;
+        if begin {
+            let jump = self.here;
+            self.word(0x1000)?;
+            self.flow.push(Flow::If { jump, line });
+        }
+        Ok(())
+    } // fn branch
+
+    fn condition(&mut self, when : bool) -> Result<(), String> {
+        // Emit what skips the next instruction when the condition is
+        // `when`. Comparisons by size subtract into VF and test the
+        // borrow flag.
+
+        let x = self.register()?;
+        let mut op = self.next()?;
+        if !when {
+            op = String::from(match op.as_str() {
+                "==" => "!=",
+                "!=" => "==",
+                "key" => "-key",
+                "-key" => "key",
+                "<" => ">=",
+                ">=" => "<",
+                ">" => "<=",
+                "<=" => ">",
+                other => return Err(format!("unknown comparison `{}`", other)),
+            });
+        }
+        let vx = (x as u16) << 8;
+
+        match op.as_str() {
+            "key" => return self.word(0xE09E | vx),
+            "-key" => return self.word(0xE0A1 | vx),
+            "==" | "!=" => {
+                let equal = op == "==";
+                let y = self.peek().and_then(|t| self.reg_of(t));
+                return match y {
+                    Some(y) => {
+                        self.next()?;
+                        self.word(if equal { 0x5000 } else { 0x9000 } | vx | (y as u16) << 4)
+                    },
+                    None => {
+                        let byte = self.byte()? as u16;
+                        self.word(if equal { 0x3000 } else { 0x4000 } | vx | byte)
+                    },
+                };
+            },
+            "<" | ">" | "<=" | ">=" => (),
+            other => return Err(format!("unknown comparison `{}`", other)),
+        }
+
+        // VF = 1 when Vx >= rhs for < and >=, when rhs >= Vx otherwise.
+        let at_least = op == "<" || op == ">=";
+        let y = self.peek().and_then(|t| self.reg_of(t));
+        match y {
+            Some(y) => {
+                self.next()?;
+                self.word(0x8F00 | (x as u16) << 4)?;
+                self.word(0x8F00 | (y as u16) << 4 | if at_least { 0x5 } else { 0x7 })?;
+            },
+            None => {
+                let byte = self.byte()? as u16;
+                self.word(0x6F00 | byte)?;
+                self.word(0x8F00 | (x as u16) << 4 | if at_least { 0x7 } else { 0x5 })?;
+            },
+        }
+        let flag_set = op == ">=" || op == "<=";
+        self.word(if flag_set { 0x3F01 } else { 0x3F00 })
+    } // fn condition
+
+    fn define_macro(&mut self) -> Result<(), String> {
+        let name = self.name()?;
+        if self.macros.contains_key(&name) {
+            return Err(format!("macro `{}` is defined twice", name));
+        }
+
+        let mut params = Vec::new();
+        loop {
+            let token = self.next()?;
+            if token == "{" {
+                break;
+            }
+            params.push(token);
+        }
+
+        let mut body = Vec::new();
+        let mut depth = 0;
+        loop {
+            let token = self.next()?;
+            if token == "}" {
+                if depth == 0 {
+                    break;
+                }
+                depth -= 1;
+            } else if token == "{" {
+                depth += 1;
+            }
+            body.push(token);
+        }
+
+        self.macros.insert(name, Macro { params, body });
+        Ok(())
+    } // fn define_macro
+
+    fn expand(&mut self, name : &str) -> Result<(), String> {
+        // Put the body back into the token stream, with the arguments
+        // in place of the parameters.
+
+        self.expansions += 1;
+        if self.expansions > MAX_EXPANSIONS {
+            return Err(format!("macro `{}` expands without end", name));
+        }
+
+        let count = self.macros[name].params.len();
+        let mut args = Vec::new();
+        for _ in 0..count {
+            args.push(self.next()?);
+        }
+
+        let line = self.line;
+        let m = &self.macros[name];
+        for token in m.body.iter().rev() {
+            let text = match m.params.iter().position(|p| p == token) {
+                Some(i) => args[i].clone(),
+                None => token.clone(),
+            };
+            self.tokens.push(Token { text, line });
+        }
+        Ok(())
+    } // fn expand
+
+    fn address_op(&mut self, op : u16) -> Result<(), String> {
+        let target = self.target()?;
+        let addr = self.here;
+        self.word(op)?;
+        self.refer(addr, Patch::Addr12, target)
+    }
+
+    fn target(&mut self) -> Result<Target, String> {
+        // An address, which may be a name defined later.
+        if self.peek() == Some("{") {
+            let value = self.integer(0, MAX_ADDRESS as i64, "address")?;
+            return Ok(Target::Known(value as u16));
+        }
+        let token = self.next()?;
+        match self.lookup(&token) {
+            Some(value) => {
+                Ok(Target::Known(self.checked(value, 0, MAX_ADDRESS as i64, "address")? as u16))
+            },
+            None if is_name(&token) => Ok(Target::Label(token)),
+            None => Err(format!("expected an address, got `{}`", token)),
+        }
+    }
+
+    fn refer(&mut self, addr : usize, patch : Patch, target : Target) -> Result<(), String> {
+        match target {
+            Target::Known(value) => self.patch(addr, patch, value),
+            Target::Label(name) => {
+                self.fixups.push(Fixup { addr, patch, name, line : self.line });
+                Ok(())
+            },
+        }
+    }
+
+    fn patch(&mut self, addr : usize, patch : Patch, value : u16) -> Result<(), String> {
+        let i = addr - PROGRAM_START as usize;
+        let image = &mut self.image;
+        match patch {
+            Patch::Addr12 | Patch::Unpack(_) if value > 0xFFF => {
+                return Err(format!("address {:X} does not fit in 12 bits", value));
+            },
+            Patch::Addr12 => {
+                image[i] = image[i] & 0xF0 | (value >> 8) as u8;
+                image[i + 1] = value as u8;
+            },
+            Patch::Addr16 => {
+                image[i] = (value >> 8) as u8;
+                image[i + 1] = value as u8;
+            },
+            Patch::Unpack(n) => {
+                image[i + 1] = n << 4 | (value >> 8) as u8;
+                image[i + 3] = value as u8;
+            },
+            Patch::UnpackLong => {
+                image[i + 1] = (value >> 8) as u8;
+                image[i + 3] = value as u8;
+            },
+        }
+        Ok(())
+    } // fn patch
+
+    fn emit(&mut self, byte : u8) -> Result<(), String> {
+        if self.here > MAX_ADDRESS {
+            return Err(String::from("the program does not fit in 64 KiB"));
+        }
+        let i = self.here - PROGRAM_START as usize;
+        if self.image.len() <= i {
+            self.image.resize(i + 1, 0);
+        }
+        self.image[i] = byte;
+
+        match self.spans.last_mut() {
+            Some(span) if span.0 == self.line && span.1 + span.2 == self.here => span.2 += 1,
+            _ => self.spans.push((self.line, self.here, 1)),
+        }
+        self.here += 1;
+        Ok(())
+    }
+
+    fn word(&mut self, word : u16) -> Result<(), String> {
+        self.emit((word >> 8) as u8)?;
+        self.emit(word as u8)
+    }
+
+    fn define_label(&mut self, name : String, addr : usize) -> Result<(), String> {
+        if self.labels.contains_key(&name) || self.constants.contains_key(&name) {
+            return Err(format!("`{}` is defined twice", name));
+        }
+        self.labels.insert(name, addr as u16);
+        Ok(())
+    }
+
+    fn next(&mut self) -> Result<String, String> {
+        let token = self.tokens.pop()
+            .ok_or_else(|| String::from("unexpected end of the program"))?;
+        self.line = token.line;
+        Ok(token.text)
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.tokens.last().map(|t| t.text.as_str())
+    }
+
+    fn expect(&mut self, text : &str) -> Result<(), String> {
+        let token = self.next()?;
+        if token != text {
+            return Err(format!("expected `{}`, got `{}`", text, token));
+        }
+        Ok(())
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        let token = self.next()?;
+        if !is_name(&token) || self.reg_of(&token).is_some() {
+            return Err(format!("`{}` cannot be used as a name", token));
+        }
+        Ok(token)
+    }
+
+    fn reg_of(&self, token : &str) -> Option<u8> {
+        if let Some(&reg) = self.aliases.get(token) {
+            return Some(reg);
+        }
+        let bytes = token.as_bytes();
+        if bytes.len() == 2 && (bytes[0] == b'v' || bytes[0] == b'V') {
+            (bytes[1] as char).to_digit(16).map(|d| d as u8)
+        } else {
+            None
+        }
+    }
+
+    fn register(&mut self) -> Result<u8, String> {
+        let token = self.next()?;
+        self.reg_of(&token).ok_or_else(|| format!("expected a register, got `{}`", token))
+    }
+
+    fn lookup(&self, token : &str) -> Option<f64> {
+        number(token)
+            .or_else(|| self.constants.get(token).copied())
+            .or_else(|| self.labels.get(token).map(|&addr| addr as f64))
+    }
+
+    fn value(&mut self) -> Result<f64, String> {
+        if self.peek() == Some("{") {
+            return self.calc();
+        }
+        let token = self.next()?;
+        self.lookup(&token).ok_or_else(|| format!("`{}` is not defined", token))
+    }
+
+    fn integer(&mut self, min : i64, max : i64, what : &str) -> Result<i64, String> {
+        let value = self.value()?;
+        self.checked(value, min, max, what)
+    }
+
+    fn checked(&self, value : f64, min : i64, max : i64, what : &str) -> Result<i64, String> {
+        let n = value.floor() as i64;
+        if n < min || n > max {
+            return Err(format!("{} {} is out of range {}-{}", what, n, min, max));
+        }
+        Ok(n)
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        self.integer(-128, 0xFF, "byte").map(|n| n as u8)
+    }
+
+    fn calc(&mut self) -> Result<f64, String> {
+        self.expect("{")?;
+
+        // Parentheses need no spaces around them.
+        let mut tokens = Vec::new();
+        loop {
+            let token = self.next()?;
+            if token == "}" {
+                break;
+            }
+            let mut word = String::new();
+            for c in token.chars() {
+                if c == '(' || c == ')' {
+                    if !word.is_empty() {
+                        tokens.push(mem::take(&mut word));
+                    }
+                    tokens.push(c.to_string());
+                } else {
+                    word.push(c);
+                }
+            }
+            if !word.is_empty() {
+                tokens.push(word);
+            }
+        }
+
+        let mut pos = 0;
+        let value = self.expression(&tokens, &mut pos)?;
+        if pos < tokens.len() {
+            return Err(format!("unexpected `{}` in expression", tokens[pos]));
+        }
+        Ok(value)
+    } // fn calc
+
+    fn expression(&self, tokens : &[String], pos : &mut usize) -> Result<f64, String> {
+        let left = self.term(tokens, pos)?;
+        match tokens.get(*pos) {
+            Some(op) if op != ")" => {
+                *pos += 1;
+                let right = self.expression(tokens, pos)?;
+                binary(op, left, right).ok_or_else(|| format!("unknown operator `{}`", op))
+            },
+            _ => Ok(left),
+        }
+    }
+
+    fn term(&self, tokens : &[String], pos : &mut usize) -> Result<f64, String> {
+        let token = tokens.get(*pos).ok_or_else(|| String::from("incomplete expression"))?;
+        *pos += 1;
+
+        if token == "(" {
+            let value = self.expression(tokens, pos)?;
+            if tokens.get(*pos).map(|t| t.as_str()) != Some(")") {
+                return Err(String::from("missing `)` in expression"));
+            }
+            *pos += 1;
+            return Ok(value);
+        }
+        if token == "@" {
+            // The byte compiled at an address so far.
+            let addr = self.term(tokens, pos)? as usize;
+            let byte = addr.checked_sub(PROGRAM_START as usize)
+                .and_then(|i| self.image.get(i));
+            return Ok(byte.copied().unwrap_or(0) as f64);
+        }
+        if UNARY.contains(&token.as_str()) {
+            let value = self.term(tokens, pos)?;
+            return Ok(unary(token, value));
+        }
+
+        match token.as_str() {
+            "HERE" => Ok(self.here as f64),
+            "PI" => Ok(consts::PI),
+            "E" => Ok(consts::E),
+            _ => self.lookup(token).ok_or_else(|| format!("`{}` is not defined", token)),
+        }
+    } // fn term
+
+    fn listing(&self, source : &str) -> String {
+        // Every source line with the code compiled from it, the jump
+        // to main shows up as line 0.
+
+        let mut spans : BTreeMap<usize, Vec<(usize, usize)>> = BTreeMap::new();
+        for &(line, addr, len) in &self.spans {
+            spans.entry(line).or_default().push((addr, len));
+        }
+
+        let mut out = String::new();
+        let mut addr = PROGRAM_START as usize;
+        let lines = Some("jump main").into_iter().chain(source.lines());
+        for (line, text) in lines.enumerate() {
+            match spans.get(&line) {
+                None => listing(&mut out, line, addr, text, &[]),
+                Some(spans) => {
+                    for (i, &(start, len)) in spans.iter().enumerate() {
+                        let offset = start - PROGRAM_START as usize;
+                        listing(&mut out, line, start, if i == 0 { text } else { "" },
+                                &self.image[offset..offset + len]);
+                        addr = start + len;
+                    }
+                },
+            }
+        }
+        out
+    } // fn listing
+}
+
+fn number(token : &str) -> Option<f64> {
+    let (negative, digits) = match token.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, token),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()? as f64
+    } else if let Some(bin) = digits.strip_prefix("0b") {
+        i64::from_str_radix(bin, 2).ok()? as f64
+    } else if digits.starts_with(|c : char| c.is_ascii_digit()) {
+        digits.parse().ok()?
+    } else {
+        return None;
+    };
+    Some(if negative { -value } else { value })
+}
+
+fn is_name(token : &str) -> bool {
+    !token.is_empty() && number(token).is_none() &&
+        !KEYWORDS.contains(&token) && !FLOW.contains(&token) &&
+        !token.starts_with(':') && !token.contains(['{', '}'])
+}
+
+fn unary(op : &str, v : f64) -> f64 {
+    match op {
+        "-" => -v,
+        "~" => !(v as i64) as f64,
+        "!" => if v == 0.0 { 1.0 } else { 0.0 },
+        "sin" => v.sin(),
+        "cos" => v.cos(),
+        "tan" => v.tan(),
+        "exp" => v.exp(),
+        "log" => v.ln(),
+        "abs" => v.abs(),
+        "sqrt" => v.sqrt(),
+        "sign" => if v == 0.0 { 0.0 } else { v.signum() },
+        "ceil" => v.ceil(),
+        _ => v.floor(),
+    }
+}
+
+fn binary(op : &str, a : f64, b : f64) -> Option<f64> {
+    let (i, j) = (a as i64, b as i64);
+    let truth = |t : bool| if t { 1.0 } else { 0.0 };
+    Some(match op {
+        "+" => a + b,
+        "-" => a - b,
+        "*" => a * b,
+        "/" => a / b,
+        "%" => a % b,
+        "&" => (i & j) as f64,
+        "|" => (i | j) as f64,
+        "^" => (i ^ j) as f64,
+        "<<" => if (0..64).contains(&j) { (i << j) as f64 } else { 0.0 },
+        ">>" => if (0..64).contains(&j) { (i >> j) as f64 } else { 0.0 },
+        "pow" => a.powf(b),
+        "min" => a.min(b),
+        "max" => a.max(b),
+        "<" => truth(a < b),
+        ">" => truth(a > b),
+        "<=" => truth(a <= b),
+        ">=" => truth(a >= b),
+        "==" => truth(a == b),
+        "!=" => truth(a != b),
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rom(source : &str) -> Vec<u8> {
+        compile(source, "test.8o").map(|p| p.rom).unwrap_or_else(|e| panic!("{:?}", e))
+    }
+
+    #[test]
+    fn compiles_structured_control_flow() {
+        let rom = rom("
+            : main
+              loop
+                if v0 == 5 then v1 := 1
+                if v0 < v2 begin
+                  v0 += 1
+                else
+                  v0 := 0
+                end
+                while v1 key
+              again");
+
+        assert_eq!(rom, [
+            0x12, 0x02,                 // 200: jump main
+            0x40, 0x05, 0x61, 0x01,     // 202: skip the load unless v0 == 5
+            0x8F, 0x00, 0x8F, 0x25,     // 206: vf := v0 - v2, VF = v0 >= v2
+            0x3F, 0x00, 0x12, 0x12,     // 20A: jump to else unless v0 < v2
+            0x70, 0x01, 0x12, 0x14,     // 20E: v0 += 1, jump to end
+            0x60, 0x00,                 // 212: else v0 := 0
+            0xE1, 0x9E, 0x12, 0x1A,     // 214: leave the loop unless v1 is pressed
+            0x12, 0x02,                 // 218: again
+        ]);
+    }
+
+    #[test]
+    fn expands_macros_constants_and_forward_references() {
+        let source = "
+            :const WIDTH 8
+            :calc HALF { WIDTH / 2 + 2 }
+            :alias pos v3
+            :macro twice op { op op }
+            : main
+              pos := HALF
+              twice draw
+              :unpack 0xA dot
+              i := long dot
+            : draw
+              sprite pos pos 1 ;
+            : dot 0x80 :byte { WIDTH * 2 }";
+
+        let program = compile(source, "test.8o").unwrap();
+        assert_eq!(program.rom, [
+            0x12, 0x02,
+            0x63, 0x02,                 // 8 / (2 + 2), right to left
+            0x22, 0x10, 0x22, 0x10,
+            0x60, 0xA2, 0x61, 0x14,
+            0xF0, 0x00, 0x02, 0x14,
+            0xD3, 0x31, 0x00, 0xEE,
+            0x80, 0x10,
+        ]);
+        assert_eq!(program.labels["dot"], 0x214);
+    }
+
+    #[test]
+    fn reports_the_first_error_with_its_line() {
+        let error = |source| compile(source, "test.8o").unwrap_err()[0].to_string();
+
+        assert_eq!(error(": main\n  v0 := 300"), "test.8o:2: byte 300 is out of range -128-255");
+        assert_eq!(error(": main\n  jump nowhere"), "test.8o:2: `nowhere` is not defined");
+        assert_eq!(error(": main\n  loop\n  v0 += 1"), "test.8o:2: `loop` without `again`");
+        assert_eq!(error("v0 := 1"), "test.8o:0: the program has no `main` label");
+    }
+}
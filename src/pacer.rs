@@ -0,0 +1,94 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+/*
+ * Keeps a frontend in step with the wall clock. Elapsed real time is
+ * collected in an accumulator and paid out in whole frames: after a
+ * slow frame the next call asks for more than one frame so emulated
+ * time catches up, but never more than max_catch_up, otherwise a host
+ * that cannot keep up would fall further behind on every call.
+ *
+ * The Cpu itself never sleeps, so headless runs go as fast as they can.
+ */
+
+pub struct FramePacer {
+    frame : Duration,           // Length of one frame
+    pub max_catch_up : u32,     // Most frames handed out by one wait
+    accumulator : Duration,     // Real time not yet emulated
+    last : Instant,
+}
+
+impl FramePacer {
+    pub fn new(hz : u32) -> FramePacer {
+        FramePacer {
+            frame : Duration::from_secs(1) / hz,
+            max_catch_up : 5,
+            accumulator : Duration::from_secs(0),
+            last : Instant::now(),
+        }
+    } // fn new
+
+    // Sleep until at least one frame is due, then return how many
+    // frames should be emulated before the next present.
+    pub fn wait(&mut self) -> u32 {
+        self.collect();
+        if self.accumulator < self.frame {
+            thread::sleep(self.frame - self.accumulator);
+            self.collect();
+        }
+
+        let mut frames = 0;
+        while self.accumulator >= self.frame {
+            self.accumulator -= self.frame;
+            frames += 1;
+        }
+
+        if frames > self.max_catch_up {
+            // Too far behind, give up on the backlog instead of
+            // spiralling.
+            frames = self.max_catch_up;
+            self.accumulator = Duration::from_secs(0);
+        }
+        frames
+    } // fn wait
+
+    // Forget the time spent while not emulating, e.g. while paused.
+    pub fn resync(&mut self) {
+        self.accumulator = Duration::from_secs(0);
+        self.last = Instant::now();
+    }
+
+    fn collect(&mut self) {
+        let now = Instant::now();
+        self.accumulator += now - self.last;
+        self.last = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pays_out_the_frames_that_are_due() {
+        // Pretend the host was away for 3 and a half frames.
+        let mut pacer = FramePacer::new(60);
+        pacer.last = Instant::now() - pacer.frame * 7 / 2;
+        assert_eq!(pacer.wait(), 3);
+        assert!(pacer.accumulator >= pacer.frame / 2);
+
+        // With nothing due it sleeps until the next frame.
+        pacer.resync();
+        let start = Instant::now();
+        assert_eq!(pacer.wait(), 1);
+        assert!(start.elapsed() >= pacer.frame);
+    }
+
+    #[test]
+    fn drops_a_backlog_longer_than_max_catch_up() {
+        let mut pacer = FramePacer::new(60);
+        pacer.last = Instant::now() - pacer.frame * 20;
+        assert_eq!(pacer.wait(), 5);
+        assert_eq!(pacer.accumulator, Duration::from_secs(0));
+    }
+}
@@ -0,0 +1,62 @@
+/*
+ * The Cpu only knows pixel values (0 = dark, 1 = lit, up to 15 with the
+ * XO-CHIP bitplanes). A Palette maps every value to a 0x00RRGGBB color
+ * for a frontend to show, values without a color get the last one.
+ */
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Palette {
+    pub colors : Vec<u32>,      // Indexed by pixel value
+}
+
+impl Palette {
+    pub fn new(colors : Vec<u32>) -> Palette {
+        Palette { colors }
+    }
+
+    // Parse a comma separated list of hex colors, e.g. "000000,FFFFFF".
+    // The first one is the background, at least two are needed.
+    pub fn parse(text : &str) -> Result<Palette, String> {
+        let mut colors = Vec::new();
+
+        for part in text.split(',') {
+            let hex = part.trim().trim_start_matches('#');
+            match u32::from_str_radix(hex, 16) {
+                // from_str_radix takes a sign, a color does not.
+                Ok(c) if hex.len() == 6 && hex.bytes().all(|b| b.is_ascii_hexdigit()) => {
+                    colors.push(c)
+                }
+                _ => return Err(format!("bad color `{}`, expected RRGGBB", part)),
+            }
+        }
+
+        if colors.len() < 2 {
+            return Err(String::from("a palette needs at least two colors"));
+        }
+        Ok(Palette { colors })
+    } // fn parse
+
+    // Four colors for the two XO-CHIP bitplanes: background, plane 1,
+    // plane 2 and both planes.
+    pub fn xo_chip() -> Palette {
+        Palette { colors : vec![0x000000, 0xFFFFFF, 0xAAAAAA, 0x555555] }
+    }
+
+    pub fn color(&self, pixel : u8) -> u32 {
+        match self.colors.get(pixel as usize) {
+            Some(c) => *c,
+            None => self.colors[self.colors.len() - 1],
+        }
+    }
+
+    pub fn apply(&self, pixels : &[u8], out : &mut Vec<u32>) {
+        out.clear();
+        out.extend(pixels.iter().map(|p| self.color(*p)));
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Palette {
+        Palette { colors : vec![0x000000, 0xFFFFFF] }
+    }
+}
@@ -0,0 +1,236 @@
+use std::fmt::Write as FmtWrite;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufWriter;
+use cpu::Instruction;
+
+/*
+ * Tracing records every executed instruction: when it ran, where, what
+ * it was and the registers before and after. It is off unless a Tracer
+ * is given to the Cpu, and a filter keeps the output down to the part
+ * of a ROM that is of interest.
+ *
+ * Records are written as text, one line each with only the registers
+ * that changed, PC only when it does not simply move on:
+ *
+ *   12 204 8014  ADD V0, V1        V0 F0>10 VF 00>01
+ *
+ * or as JSON Lines, one object each with all registers:
+ *
+ *   {"cycle":12,"pc":516,"opcode":"8014","mnemonic":"ADD V0, V1",
+ *    "before":{"v":[240,...],"i":0,"pc":516,"sp":0,"dt":0,"st":0},
+ *    "after":{"v":[16,...],"i":0,"pc":518,"sp":0,"dt":0,"st":0}}
+ */
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TraceFormat {
+    Text,
+    JsonLines,
+}
+
+impl TraceFormat {
+    pub fn from_name(name : &str) -> Option<TraceFormat> {
+        match name {
+            "text" => Some(TraceFormat::Text),
+            "jsonl" => Some(TraceFormat::JsonLines),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Registers {
+    pub v : [u8; 16],
+    pub i : u16,
+    pub pc : u16,
+    pub sp : u16,
+    pub dt : u8,
+    pub st : u8,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Record {
+    pub cycle : u64,            // Instructions executed before this one
+    pub pc : u16,
+    pub opcode : u16,
+    pub instruction : Instruction,      // As the Mode of the Cpu runs it
+    pub before : Registers,
+    pub after : Registers,
+}
+
+pub struct Tracer {
+    pub format : TraceFormat,
+    pub range : Option<(u16, u16)>,     // Only trace pc in start..=end
+    pub families : u16,                 // Bit n traces opcodes nXXX
+    out : Box<dyn Write>,
+}
+
+impl Tracer {
+    pub fn new(out : Box<dyn Write>, format : TraceFormat) -> Tracer {
+        Tracer {
+            format,
+            range : None,
+            families : 0xFFFF,
+            out,
+        }
+    } // fn new
+
+    pub fn stderr(format : TraceFormat) -> Tracer {
+        Tracer::new(Box::new(io::stderr()), format)
+    }
+
+    pub fn create(path : &str, format : TraceFormat) -> io::Result<Tracer> {
+        let file = BufWriter::new(File::create(path)?);
+        Ok(Tracer::new(Box::new(file), format))
+    }
+
+    // Whether the instruction at pc should be recorded.
+    pub fn wants(&self, pc : u16, opcode : u16) -> bool {
+        let in_range = match self.range {
+            Some((start, end)) => pc >= start && pc <= end,
+            None => true,
+        };
+        in_range && self.families & (1 << (opcode >> 12)) != 0
+    }
+
+    // Write a record. Like the audio sinks a tracer that cannot write
+    // does not stop the emulation.
+    pub fn record(&mut self, record : &Record) {
+        let line = match self.format {
+            TraceFormat::Text => text(record),
+            TraceFormat::JsonLines => json(record),
+        };
+        let _ = self.out.write_all(line.as_bytes());
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+fn text(record : &Record) -> String {
+    let mut line = format!("{:>8} {:03X} {:04X}  {:<18}",
+                           record.cycle, record.pc, record.opcode,
+                           mnemonic(record));
+
+    let (before, after) = (&record.before, &record.after);
+    for reg in 0..16 {
+        if before.v[reg] != after.v[reg] {
+            let _ = write!(line, " V{:X} {:02X}>{:02X}", reg, before.v[reg], after.v[reg]);
+        }
+    }
+    if before.i != after.i {
+        let _ = write!(line, " I {:03X}>{:03X}", before.i, after.i);
+    }
+    if after.pc != before.pc.wrapping_add(record.instruction.size()) {
+        let _ = write!(line, " PC {:03X}>{:03X}", before.pc, after.pc);
+    }
+    let counters = [("SP", before.sp, after.sp),
+                    ("DT", before.dt as u16, after.dt as u16),
+                    ("ST", before.st as u16, after.st as u16)];
+    for &(name, old, new) in &counters {
+        if old != new {
+            let _ = write!(line, " {} {:02X}>{:02X}", name, old, new);
+        }
+    }
+
+    let len = line.trim_end().len();
+    line.truncate(len);
+    line.push('\n');
+    line
+} // fn text
+
+fn json(record : &Record) -> String {
+    format!("{{\"cycle\":{},\"pc\":{},\"opcode\":\"{:04X}\",\"mnemonic\":\"{}\",\
+             \"before\":{},\"after\":{}}}\n",
+            record.cycle, record.pc, record.opcode, mnemonic(record),
+            json_registers(&record.before), json_registers(&record.after))
+}
+
+// F000 loads I with the word that follows it, so I holds it afterwards.
+fn mnemonic(record : &Record) -> String {
+    match record.instruction {
+        Instruction::LdILong => format!("LD I, LONG #{:04X}", record.after.i),
+        instruction => instruction.to_string(),
+    }
+}
+
+fn json_registers(regs : &Registers) -> String {
+    let v : Vec<String> = regs.v.iter().map(|v| v.to_string()).collect();
+    format!("{{\"v\":[{}],\"i\":{},\"pc\":{},\"sp\":{},\"dt\":{},\"st\":{}}}",
+            v.join(","), regs.i, regs.pc, regs.sp, regs.dt, regs.st)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpu::{decode, Cpu};
+
+    fn record(opcode : u16, before : Registers, after : Registers) -> Record {
+        Record { cycle : 12, pc : before.pc, opcode, instruction : decode(opcode), before, after }
+    }
+
+    const IDLE : Registers = Registers { v : [0; 16], i : 0, pc : 0x204, sp : 0, dt : 0, st : 0 };
+
+    #[test]
+    fn text_shows_what_changed() {
+        let mut before = IDLE;
+        before.v[0] = 0xF0;
+        before.v[1] = 0x20;
+        let mut after = Registers { pc : 0x206, ..before };
+        after.v[0] = 0x10;
+        after.v[15] = 1;
+        assert_eq!(text(&record(0x8014, before, after)),
+                   "      12 204 8014  ADD V0, V1         V0 F0>10 VF 00>01\n");
+
+        let after = Registers { pc : 0x300, sp : 1, ..IDLE };
+        assert_eq!(text(&record(0x2300, IDLE, after)),
+                   "      12 204 2300  CALL #300          PC 204>300 SP 00>01\n");
+    }
+
+    #[test]
+    fn json_has_all_registers() {
+        let after = Registers { pc : 0x206, dt : 9, ..IDLE };
+        let line = json(&record(0xF015, IDLE, after));
+        assert_eq!(line, format!("{{\"cycle\":12,\"pc\":516,\"opcode\":\"F015\",\
+                                  \"mnemonic\":\"LD DT, V0\",\
+                                  \"before\":{{\"v\":[{0}],\"i\":0,\"pc\":516,\"sp\":0,\"dt\":0,\"st\":0}},\
+                                  \"after\":{{\"v\":[{0}],\"i\":0,\"pc\":518,\"sp\":0,\"dt\":9,\"st\":0}}}}\n",
+                                 ["0"; 16].join(",")));
+    }
+
+    #[test]
+    fn long_load_shows_its_address() {
+        let before = IDLE;
+        let record = Record {
+            cycle : 0,
+            pc : 0x200,
+            opcode : 0xF000,
+            instruction : decode(0xF000),
+            before,
+            after : Registers { i : 0x1234, ..before },
+        };
+        assert!(text(&record).contains("LD I, LONG #1234"));
+        assert!(json(&record).contains("\"mnemonic\":\"LD I, LONG #1234\""));
+    }
+
+    #[test]
+    fn long_load_is_unknown_before_xo_chip() {
+        // F000 1234 in CHIP-8 mode: skipped as DW, pc moves on by 2.
+        let path = ::std::env::temp_dir()
+            .join(format!("chip8-{}-long_load_is_unknown_before_xo_chip.txt",
+                          ::std::process::id()))
+            .display().to_string();
+        let mut cpu = Cpu::new();
+        cpu.load_rom_bytes(&[0xF0, 0x00, 0x12, 0x34]).unwrap();
+        cpu.set_tracer(Some(Tracer::create(&path, TraceFormat::Text).unwrap()));
+        cpu.step().unwrap();
+        cpu.tracer().unwrap().flush().unwrap();
+        cpu.set_tracer(None);
+        let trace = ::std::fs::read_to_string(&path).unwrap();
+        ::std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(trace, "       0 200 F000  DW #F000\n");
+    }
+}
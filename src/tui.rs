@@ -0,0 +1,475 @@
+use chip8::{Cpu, FramePacer, TIMER_HZ};
+use chip8::debugger::{self, Debugger, Stop};
+use cli::Config;
+use std::fmt::Write as FmtWrite;
+use std::io;
+use std::io::prelude::*;
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+/*
+ * The terminal frontend, for when there is no window, e.g. over SSH.
+ * The display is drawn with half blocks, two pixels to a character, in
+ * the colors of the palette. Next to it are the disassembly around the
+ * cursor, the registers, timers and stack and the keypad, and below it
+ * a hex view of memory.
+ *
+ * A terminal only reports key presses, so a CHIP-8 key is held for a
+ * few frames after each. The debugger keys are not in the default key
+ * map:
+ *
+ *   P or Space   run / pause       Up, Down     move the cursor
+ *   N            step              B            breakpoint at the cursor
+ *   O            step over         PgUp, PgDn   scroll the memory view
+ *   U            step out          M            memory view back at I
+ *   Escape       quit
+ *
+ * The terminal is put into raw mode with `stty`.
+ */
+
+// Frames a key stays down after it was pressed.
+const KEY_HOLD : u8 = 8;
+
+const DISASSEMBLY_LINES : usize = 12;
+const MEMORY_ROWS : usize = 8;
+
+const HELP : &str = "P run/pause  N step  O over  U out  B breakpoint  \
+                     \u{2191}\u{2193} cursor  PgUp/PgDn/M memory  Esc quit";
+
+// The keypad as it is laid out on a COSMAC VIP.
+const KEYPAD : [[usize; 4]; 4] = [[0x1, 0x2, 0x3, 0xC],
+                                  [0x4, 0x5, 0x6, 0xD],
+                                  [0x7, 0x8, 0x9, 0xE],
+                                  [0xA, 0x0, 0xB, 0xF]];
+
+#[derive(Debug, PartialEq)]
+enum Input {
+    Char(char),
+    Up,
+    Down,
+    PageUp,
+    PageDown,
+    Quit,
+}
+
+// Raw mode and the alternate screen for as long as it lives.
+struct Terminal {
+    saved : String,                 // `stty -g` from before
+}
+
+impl Terminal {
+    fn raw() -> io::Result<Terminal> {
+        let saved = stty(&["-g"])?;
+        stty(&["raw", "-echo"])?;
+        print!("\x1b[?1049h\x1b[?25l\x1b[2J");
+        io::stdout().flush()?;
+        Ok(Terminal { saved : saved.trim().to_string() })
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        print!("\x1b[0m\x1b[?25h\x1b[?1049l");
+        let _ = io::stdout().flush();
+        let _ = stty(&[&self.saved]);
+    }
+}
+
+fn stty(args : &[&str]) -> io::Result<String> {
+    let output = Command::new("stty")
+        .args(args)
+        .stdin(Stdio::inherit())
+        .output()?;
+    if !output.status.success() {
+        return Err(io::Error::other("the terminal UI needs a terminal"));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+struct Ui {
+    debugger : Debugger,
+    paused : bool,
+    message : String,               // Why the program stopped
+    cursor : u16,                   // In the disassembly
+    memory : Option<usize>,         // Start of the memory view, None at I
+    held : [u8; 16],                // Frames left for each key
+    quit : bool,
+}
+
+pub fn run(chip8 : &mut Cpu, config : &Config) -> io::Result<()> {
+    let _terminal = Terminal::raw()?;
+
+    let (sender, inputs) = mpsc::channel();
+    thread::spawn(move || {
+        let mut buf = [0; 64];
+        let stdin = io::stdin();
+        let mut stdin = stdin.lock();
+        while let Ok(n) = stdin.read(&mut buf) {
+            if n == 0 || sender.send(buf[..n].to_vec()).is_err() {
+                break;
+            }
+        }
+    });
+
+    // Overwritten code only matters to a debugger prompt.
+    let mut debugger = Debugger::new();
+    debugger.stop_on_self_modify = false;
+
+    let mut ui = Ui {
+        debugger,
+        paused : config.paused,
+        message : String::new(),
+        cursor : chip8.pc,
+        memory : None,
+        held : [0; 16],
+        quit : false,
+    };
+    let mut pacer = FramePacer::new(TIMER_HZ);
+    let mut stdout = io::stdout();
+
+    while !ui.quit {
+        let chunks : Vec<Vec<u8>> = if ui.paused {
+            // Nothing changes until a key comes.
+            inputs.recv_timeout(Duration::from_millis(100)).into_iter().collect()
+        } else {
+            inputs.try_iter().collect()
+        };
+        let paused = ui.paused;
+        for chunk in chunks {
+            for input in parse(&chunk) {
+                ui.input(chip8, config, input);
+            }
+        }
+        if paused && !ui.paused {
+            pacer.resync();
+        }
+
+        if !ui.paused {
+            for _ in 0..pacer.wait() {
+                ui.run_frame(chip8);
+                if ui.paused {
+                    break;
+                }
+            }
+        }
+
+        stdout.write_all(ui.draw(chip8, config).as_bytes())?;
+        stdout.flush()?;
+    }
+    Ok(())
+} // fn run
+
+impl Ui {
+    fn input(&mut self, chip8 : &mut Cpu, config : &Config, input : Input) {
+        let c = match input {
+            Input::Char(c) => c,
+            Input::Quit => {
+                self.quit = true;
+                return;
+            },
+            Input::Up => {
+                self.cursor = self.cursor.wrapping_sub(2);
+                return;
+            },
+            Input::Down => {
+                self.cursor = self.cursor.wrapping_add(2);
+                return;
+            },
+            Input::PageUp => return self.scroll(chip8, -(MEMORY_ROWS as isize)),
+            Input::PageDown => return self.scroll(chip8, MEMORY_ROWS as isize),
+        };
+
+        match c.to_ascii_lowercase() {
+            'p' | ' ' => {
+                self.paused = !self.paused;
+                self.message.clear();
+                self.cursor = chip8.pc;
+            },
+            'n' if self.paused => {
+                let stop = self.debugger.step(chip8, 1);
+                self.stopped(chip8, stop);
+            },
+            'o' if self.paused => {
+                let stop = self.debugger.step_over(chip8);
+                self.stopped(chip8, stop);
+            },
+            'u' if self.paused => {
+                let stop = self.debugger.step_out(chip8);
+                self.stopped(chip8, stop);
+            },
+            'b' => {
+                let found = self.debugger.breakpoints.iter()
+                    .find(|b| b.addr == self.cursor)
+                    .map(|b| b.id);
+                match found {
+                    Some(id) => {
+                        self.debugger.remove_breakpoint(id);
+                    },
+                    None => {
+                        self.debugger.add_breakpoint(self.cursor, None);
+                    },
+                }
+            },
+            'm' => self.memory = None,
+            _ => {
+                if let Some(key) = config.keymap.lookup(&c.to_string()) {
+                    self.held[key as usize] = KEY_HOLD;
+                }
+            },
+        }
+    } // fn input
+
+    fn scroll(&mut self, chip8 : &Cpu, rows : isize) {
+        let start = self.memory.unwrap_or(chip8.i_reg as usize & !7) as isize;
+        let last = chip8.memory.len().saturating_sub(MEMORY_ROWS * 8) as isize;
+        self.memory = Some((start + rows * 8).clamp(0, last) as usize);
+    }
+
+    fn run_frame(&mut self, chip8 : &mut Cpu) {
+        for (key, held) in self.held.iter_mut().enumerate() {
+            if *held > 0 {
+                chip8.set_key(key, true);
+                *held -= 1;
+            }
+        }
+        let stop = self.debugger.run_frame(chip8);
+        chip8.release_keys();
+
+        if stop != Stop::Done {
+            self.paused = true;
+            self.stopped(chip8, stop);
+        }
+        self.cursor = chip8.pc;
+    }
+
+    fn stopped(&mut self, chip8 : &Cpu, stop : Stop) {
+        let report = self.debugger.report(chip8, stop);
+        self.message = match stop {
+            Stop::Done => String::new(),
+            _ => report.lines().next().unwrap_or("").to_string(),
+        };
+        self.cursor = chip8.pc;
+    }
+
+    fn draw(&self, chip8 : &Cpu, config : &Config) -> String {
+        // The display and memory on the left, the panels to the right of
+        // it, each line cleared behind what it shows.
+
+        let (width, _) = chip8.display_size();
+        let mut left = display(chip8, config);
+        left.push(String::new());
+        left.extend(self.memory_view(chip8));
+        left.push(String::new());
+        left.push(format!("{}  {}", if self.paused { "paused " } else { "running" },
+                          self.message));
+        left.push(format!("\x1b[2m{}\x1b[0m", HELP));
+
+        let mut right = self.disassembly(chip8);
+        right.push(String::new());
+        right.extend(registers(chip8));
+        right.push(String::new());
+        right.extend(keypad(chip8, &self.held));
+
+        let mut out = String::from("\x1b[H");
+        for row in 0..left.len().max(right.len()) {
+            let _ = write!(out, "\x1b[{};1H{}\x1b[0m\x1b[K", row + 1,
+                           left.get(row).map(|s| s.as_str()).unwrap_or(""));
+            if let Some(line) = right.get(row) {
+                let _ = write!(out, "\x1b[{};{}H{}\x1b[0m", row + 1, width + 3, line);
+            }
+        }
+        out.push_str("\x1b[J");
+        out
+    } // fn draw
+
+    fn disassembly(&self, chip8 : &Cpu) -> Vec<String> {
+        // A few instructions before the cursor, taken as 2 bytes each,
+        // then decoded forward. `>` marks pc and `*` a breakpoint.
+
+        let mut addr = self.cursor.wrapping_sub(8);
+        let mut lines = Vec::new();
+        for _ in 0..DISASSEMBLY_LINES {
+            let breakpoint = self.debugger.breakpoints.iter().any(|b| b.addr == addr);
+            let line = format!("{}{} {}",
+                               if addr == chip8.pc { '>' } else { ' ' },
+                               if breakpoint { '*' } else { ' ' },
+                               debugger::location(chip8, addr));
+            if addr == self.cursor {
+                lines.push(format!("\x1b[7m{:<32}\x1b[0m", line));
+            } else {
+                lines.push(line);
+            }
+
+            addr = addr.wrapping_add(chip8.instruction_at(addr).size());
+        }
+        lines
+    }
+
+    fn memory_view(&self, chip8 : &Cpu) -> Vec<String> {
+        let start = self.memory.unwrap_or(chip8.i_reg as usize & !7);
+        (0..MEMORY_ROWS).map(|row| {
+            let addr = start + row * 8;
+            let mut line = format!("{:04X} ", addr);
+            for offset in 0..8 {
+                match chip8.memory.get(addr + offset) {
+                    // I is underlined.
+                    Some(b) if addr + offset == chip8.i_reg as usize =>
+                        { let _ = write!(line, " \x1b[4m{:02X}\x1b[0m", b); },
+                    Some(b) => { let _ = write!(line, " {:02X}", b); },
+                    None => line.push_str("   "),
+                }
+            }
+            line
+        }).collect()
+    }
+}
+
+fn display(chip8 : &Cpu, config : &Config) -> Vec<String> {
+    // Every character is an upper half block, the top pixel in the
+    // foreground color and the bottom one in the background color.
+
+    let (width, height) = chip8.display_size();
+    let pixels = chip8.framebuffer();
+    let mut lines = Vec::new();
+    for y in (0..height).step_by(2) {
+        let mut line = String::new();
+        let mut last = None;
+        for x in 0..width {
+            let top = config.palette.color(pixels[y * width + x]);
+            let bottom = config.palette.color(pixels[(y + 1) * width + x]);
+            if last != Some((top, bottom)) {
+                let _ = write!(line, "\x1b[38;2;{};{};{}m\x1b[48;2;{};{};{}m",
+                               top >> 16 & 0xFF, top >> 8 & 0xFF, top & 0xFF,
+                               bottom >> 16 & 0xFF, bottom >> 8 & 0xFF, bottom & 0xFF);
+                last = Some((top, bottom));
+            }
+            line.push('\u{2580}');
+        }
+        line.push_str("\x1b[0m");
+        lines.push(line);
+    }
+    lines
+}
+
+fn registers(chip8 : &Cpu) -> Vec<String> {
+    let mut lines : Vec<String> = chip8.v_regs.chunks(4).enumerate().map(|(row, regs)| {
+        regs.iter().enumerate()
+            .map(|(i, v)| format!("V{:X} {:02X}", row * 4 + i, v))
+            .collect::<Vec<String>>()
+            .join("  ")
+    }).collect();
+    lines.push(format!("I  {:04X}  PC {:04X}  SP {}", chip8.i_reg, chip8.pc, chip8.sp));
+    lines.push(format!("DT {:02X}    ST {:02X}", chip8.dt, chip8.st));
+    let stack : Vec<String> = chip8.stack[..chip8.sp as usize].iter()
+        .map(|addr| format!("{:03X}", addr))
+        .collect();
+    lines.push(format!("STACK {}", stack.join(" ")));
+    lines
+}
+
+fn keypad(chip8 : &Cpu, held : &[u8; 16]) -> Vec<String> {
+    // Keys that are down are shown inverted.
+    KEYPAD.iter().map(|row| {
+        row.iter().map(|&key| {
+            if chip8.keypad[key] != 0 || held[key] > 0 {
+                format!("\x1b[7m {:X} \x1b[0m", key)
+            } else {
+                format!(" {:X} ", key)
+            }
+        }).collect::<String>()
+    }).collect()
+}
+
+fn parse(bytes : &[u8]) -> Vec<Input> {
+    // Arrow and page keys come as escape sequences, a lone escape is
+    // the Escape key. Ctrl-C quits too, raw mode swallows the signal.
+
+    let mut inputs = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            0x1b if i + 1 == bytes.len() => inputs.push(Input::Quit),
+            0x1b => {
+                // ESC [ ends at a letter or ~, ESC O at the letter after it.
+                let mut end = if bytes[i + 1] == b'O' { i + 2 } else { i + 1 };
+                while end < bytes.len() && !(bytes[end].is_ascii_alphabetic() || bytes[end] == b'~') {
+                    end += 1;
+                }
+                match &bytes[i + 1..(end + 1).min(bytes.len())] {
+                    b"[A" | b"OA" => inputs.push(Input::Up),
+                    b"[B" | b"OB" => inputs.push(Input::Down),
+                    b"[5~" => inputs.push(Input::PageUp),
+                    b"[6~" => inputs.push(Input::PageDown),
+                    _ => (),
+                }
+                i = end;
+            },
+            0x03 => inputs.push(Input::Quit),
+            b if b.is_ascii() => inputs.push(Input::Char(b as char)),
+            _ => (),
+        }
+        i += 1;
+    }
+    inputs
+} // fn parse
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_keys_and_escape_sequences() {
+        assert_eq!(parse(b"s\x1b[A\x1bOB\x1b[5~\x1b[6~"),
+                   [Input::Char('s'), Input::Up, Input::Down, Input::PageUp, Input::PageDown]);
+        // A lone escape is the Escape key, unknown sequences are dropped.
+        assert_eq!(parse(b"\x1b"), [Input::Quit]);
+        assert_eq!(parse(b"\x1b[15~q"), [Input::Char('q')]);
+        assert_eq!(parse(b"\x03"), [Input::Quit]);
+        assert_eq!(parse("é1".as_bytes()), [Input::Char('1')]);
+    }
+
+    #[test]
+    fn memory_view_stays_inside_memory() {
+        let mut chip8 = Cpu::new();
+        chip8.i_reg = 0x20F;
+        let mut ui = Ui {
+            debugger : Debugger::new(),
+            paused : true,
+            message : String::new(),
+            cursor : chip8.pc,
+            memory : None,
+            held : [0; 16],
+            quit : false,
+        };
+
+        // The view starts at the row of I.
+        ui.scroll(&chip8, 1);
+        assert_eq!(ui.memory, Some(0x210));
+        ui.scroll(&chip8, -0x100);
+        assert_eq!(ui.memory, Some(0));
+        ui.scroll(&chip8, 0x1000);
+        assert_eq!(ui.memory, Some(chip8.memory.len() - MEMORY_ROWS * 8));
+    }
+
+    #[test]
+    fn disassembly_sizes_f000_by_the_mode() {
+        let mut chip8 = Cpu::new();
+        chip8.load_rom_bytes(&[0xF0, 0x00, 0x12, 0x34]).unwrap();
+        let ui = Ui {
+            debugger : Debugger::new(),
+            paused : true,
+            message : String::new(),
+            cursor : chip8.pc,
+            memory : None,
+            held : [0; 16],
+            quit : false,
+        };
+
+        // The cursor line is the fifth, F000 is two bytes before XO-CHIP.
+        let lines = ui.disassembly(&chip8);
+        assert!(lines[4].contains("200  F000  DW #F000"), "{}", lines[4]);
+        assert_eq!(lines[5], "   202  1234  JP #234");
+    }
+}
@@ -0,0 +1,128 @@
+use chip8::{Cpu, FramePacer, Mode, PipeSink, HIRES_WIDTH, HIRES_HEIGHT, TIMER_HZ};
+use minifb::{Key, KeyRepeat, Scale, Window, WindowOptions};
+use cli::Config;
+use std::thread;
+use std::time::Duration;
+
+/*
+ * The minifb frontend: shows the display, feeds the keypad through the
+ * key map and plays the buzzer through `aplay` when it is available.
+ * P pauses and resumes, Escape quits.
+ */
+
+const TITLE : &str = "RUST Chip-8";
+
+pub fn run(chip8 : &mut Cpu, config : &Config) -> Result<(), String> {
+    // Without a player the default NullSink keeps the emulator silent,
+    // with --wav main has already set up the sink.
+    if config.wav.is_none() {
+        if let Ok(sink) = PipeSink::aplay() {
+            chip8.set_audio_sink(Box::new(sink));
+        }
+    }
+
+    // A SUPER-CHIP program may switch resolution at any time, so its
+    // window is always 128x64 and the low resolution display is doubled.
+    // Halving the scale keeps the window the size --scale asks for.
+    let (width, height, window_scale) = if chip8.mode >= Mode::SuperChip {
+        (HIRES_WIDTH, HIRES_HEIGHT, (config.scale / 2).max(1))
+    } else {
+        let (width, height) = chip8.display_size();
+        (width, height, config.scale)
+    };
+    let mut window = Window::new(TITLE,
+                                 width,
+                                 height,
+                                 WindowOptions {
+                                     resize: true,
+                                     scale: scale(window_scale),
+                                     ..WindowOptions::default()})
+                                 .map_err(|e| e.to_string())?;
+
+    let mut pacer = FramePacer::new(TIMER_HZ);
+    let mut paused = config.paused;
+    let mut pixels = Vec::with_capacity(width * height);
+    let mut buffer = Vec::with_capacity(width * height);
+    set_title(&mut window, paused);
+
+    while window.is_open() && !window.is_key_down(Key::Escape) {
+
+        if window.is_key_pressed(Key::P, KeyRepeat::No) {
+            paused = !paused;
+            pacer.resync();
+            set_title(&mut window, paused);
+        }
+
+        if let Some(keys) = window.get_keys() {
+            for key in keys {
+                if let Some(k) = config.keymap.lookup(&key_name(key)) {
+                    chip8.set_key(k as usize, true);
+                }
+            }
+        }
+
+        // Keys are still polled while paused, once a frame.
+        if paused {
+            window.update();
+            thread::sleep(Duration::from_secs(1) / TIMER_HZ);
+            continue;
+        }
+
+        for _ in 0..pacer.wait() {
+            chip8.run_frame().map_err(|f| f.to_string())?;
+        }
+        upscale(chip8, width, &mut pixels);
+        config.palette.apply(&pixels, &mut buffer);
+        window.update_with_buffer(&buffer);
+        chip8.release_keys();
+    }
+    Ok(())
+} // fn run
+
+// Copies the display into pixels, each pixel repeated until a row is
+// width pixels wide.
+fn upscale(chip8 : &Cpu, width : usize, pixels : &mut Vec<u8>) {
+    let (display_width, _) = chip8.display_size();
+    let factor = width / display_width;
+
+    pixels.clear();
+    for row in chip8.framebuffer().chunks(display_width) {
+        for _ in 0..factor {
+            for pixel in row {
+                for _ in 0..factor {
+                    pixels.push(*pixel);
+                }
+            }
+        }
+    }
+} // fn upscale
+
+fn set_title(window : &mut Window, paused : bool) {
+    if paused {
+        window.set_title(&format!("{} [paused]", TITLE));
+    } else {
+        window.set_title(TITLE);
+    }
+}
+
+fn scale(n : usize) -> Scale {
+    match n {
+        1 => Scale::X1,
+        2 => Scale::X2,
+        8 => Scale::X8,
+        16 => Scale::X16,
+        32 => Scale::X32,
+        _ => Scale::X4,
+    }
+}
+
+// The key map names keys the way they are printed on the keyboard:
+// minifb calls the digits Key0 - Key9, everything else matches.
+fn key_name(key : Key) -> String {
+    let name = format!("{:?}", key);
+    if name.len() == 4 && name.starts_with("Key") {
+        name[3..].to_string()
+    } else {
+        name
+    }
+}